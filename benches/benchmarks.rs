@@ -1,5 +1,7 @@
 use criterion::{criterion_group, criterion_main, Criterion, SamplingMode, Throughput};
-use rs_zip::{compression, decompression, file_io};
+use rs_zip::compression::{self, CompressOptions};
+use rs_zip::decompression::{self, ExtractOptions};
+use rs_zip::file_io::OverwritePolicy;
 use std::fs::File;
 use std::io::Write;
 use tempfile::TempDir;
@@ -12,9 +14,8 @@ const QUICK_MODE: bool = true;     // Set to false for full benchmarks
 
 fn generate_test_data(size: usize) -> Vec<u8> {
     let mut rng = rand::thread_rng();
-    let mut data = Vec::with_capacity(size);
     // Instead of unsafe set_len, fill the entire buffer safely.
-    data.resize(size, 0);
+    let mut data = vec![0; size];
     rng.fill(&mut data[..]);
     data
 }
@@ -106,10 +107,20 @@ fn setup_rsz_bench(c: &mut Criterion) {
         }
 
         group.throughput(Throughput::Bytes((file_size * count) as u64));
+
+        // Every iteration rewrites the same archive and extracted files.
+        let compress_options = CompressOptions {
+            overwrite: OverwritePolicy::Force,
+            ..CompressOptions::default()
+        };
+        let extract_options = ExtractOptions {
+            overwrite: OverwritePolicy::Force,
+            ..ExtractOptions::default()
+        };
         
         // Compression benchmark
         group.bench_function(
-            &format!("Compress {count} files"), 
+            format!("Compress {count} files"), 
             |b| {
                 b.iter(|| {
                     // Create a new archive in the temp directory.
@@ -118,7 +129,7 @@ fn setup_rsz_bench(c: &mut Criterion) {
                     compression::create_padded_archive(
                         &file_paths,
                         archive_path.to_str().unwrap(),
-                        6
+                        &compress_options
                     ).unwrap();
                 })
             }
@@ -129,15 +140,15 @@ fn setup_rsz_bench(c: &mut Criterion) {
         compression::create_padded_archive(
             &file_paths,
             archive_path.to_str().unwrap(),
-            6
+            &compress_options
         ).unwrap();
         
         // Decompression benchmark
         group.bench_function(
-            &format!("Decompress {count} files"), 
+            format!("Decompress {count} files"), 
             |b| {
                 b.iter(|| {
-                    decompression::extract_archive(&archive_path).unwrap();
+                    decompression::extract_archive(&archive_path, &extract_options).unwrap();
                 })
             }
         );
@@ -153,9 +164,9 @@ criterion_main!(benches);
 
 
 
-/// This is a more robust benchmark test still in the works.
-/// It runs the compression & decompression of both formats against 
-/// larger number sample sets.
+// This is a more robust benchmark test still in the works.
+// It runs the compression & decompression of both formats against 
+// larger number sample sets.
 /*use criterion::{criterion_group, criterion_main, Criterion, Throughput};
 use rs_zip::{compression, decompression, file_io};
 use std::path::PathBuf;
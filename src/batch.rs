@@ -0,0 +1,68 @@
+use std::io;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+
+/// Resolves a `--jobs` value to a worker count, where 0 means one worker per CPU.
+///
+/// # Arguments
+/// * `jobs` - Requested number of parallel jobs (0 = automatic).
+///
+/// # Returns
+/// * `usize` - Number of worker threads to use (at least 1).
+pub fn resolve_jobs(jobs: usize) -> usize {
+    if jobs == 0 {
+        thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1)
+    } else {
+        jobs
+    }
+}
+
+/// Runs `job` once for every item, on up to `jobs` worker threads, without stopping
+/// at the first failure.
+///
+/// # Arguments
+/// * `items` - Work items, e.g. input paths.
+/// * `jobs` - Maximum number of worker threads (values below 1 are treated as 1).
+/// * `job` - Function applied to each item.
+///
+/// # Returns
+/// * `Vec<io::Result<()>>` - One result per item, in the same order as `items`.
+pub fn run_parallel<T, F>(items: &[T], jobs: usize, job: F) -> Vec<io::Result<()>>
+where
+    T: Sync,
+    F: Fn(&T) -> io::Result<()> + Sync,
+{
+    let workers = jobs.clamp(1, items.len().max(1));
+    if workers == 1 {
+        return items.iter().map(&job).collect();
+    }
+
+    let next = AtomicUsize::new(0);
+    let results: Mutex<Vec<Option<io::Result<()>>>> =
+        Mutex::new(items.iter().map(|_| None).collect());
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    if index >= items.len() {
+                        break;
+                    }
+                    let result = job(&items[index]);
+                    results.lock().unwrap()[index] = Some(result);
+                }
+            });
+        }
+    });
+
+    results
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|result| result.expect("every item is processed by a worker"))
+        .collect()
+}
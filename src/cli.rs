@@ -1,17 +1,177 @@
 // src/cli.rs
-use clap::Parser;
-use std::path::PathBuf;
+use clap::parser::ValueSource;
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use zeroize::Zeroizing;
+use crate::codec::{self, Builtin, Codec};
+use crate::crypto::{self, Identity, Passphrase, Recipient};
+use crate::dedup::DedupMode;
+use crate::signature::{self, SigningKey, VerifyingKey};
+use crate::file_io::OverwritePolicy;
+use crate::seekable::ByteRange;
+use crate::solid::{FileOrder, SolidBlocks};
+use crate::store::{StorePolicy, StoreRules};
+use crate::lzma::{self, Filter, IntegrityCheck, LzmaProps, LzmaTuning, MatchFinder, RawFormat, RawLzma, TunedXz};
+use crate::zipfile::ZipMethod;
+
+/// Output formats selectable with `--format`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// Single-file mode: one compressed output per input file (codec chosen with --codec)
+    Xz,
+    /// Custom multi-file archive
+    Rsz,
+    /// tar archive, compressed with the codec the output name implies (`.tar.gz`, `.tzst`, ...)
+    /// or --codec, and left uncompressed if the name ends in `.tar`
+    Tar,
+    /// ZIP archive, compressed with --zip-method
+    Zip,
+    /// 7z archive (extraction only)
+    #[value(name = "7z")]
+    SevenZ,
+}
+
+impl ArchiveFormat {
+    /// The archive format a file name implies: tar by names such as `.tar`, `.tar.xz` or
+    /// `.tgz`, ZIP by `.zip`, 7z by `.7z` and RSZ otherwise.
+    pub fn from_path(path: &Path) -> Self {
+        if crate::tarball::is_tar_path(path) {
+            ArchiveFormat::Tar
+        } else if crate::zipfile::is_zip_path(path) {
+            ArchiveFormat::Zip
+        } else if crate::sevenzip::is_7z_path(path) {
+            ArchiveFormat::SevenZ
+        } else {
+            ArchiveFormat::Rsz
+        }
+    }
+}
+
+/// Backup styles accepted by `--backup`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BackupControl {
+    /// Keep every previous version as `<name>.~N~`
+    Numbered,
+}
+
+/// Subcommands; without one, rs-zip compresses or (with `-d`) decompresses `--inputs`.
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    /// Generate an X25519 identity for public-key encrypted RSZ archives
+    Keygen {
+        /// Write the key to this file instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Generate an Ed25519 signing key (for `--sign-key`) instead of an identity
+        #[arg(long)]
+        signing: bool,
+    },
+    /// Extract an RSZ, tar, ZIP or 7z archive (same as `-d -f rsz|tar|zip|7z -i ARCHIVE`; tar
+    /// is recognised by names such as `.tar`, `.tar.xz` or `.tgz`, ZIP by `.zip`, 7z by `.7z`)
+    Extract {
+        /// The archive to extract
+        archive: PathBuf,
+    },
+    /// List the files in an RSZ, tar, ZIP or 7z archive
+    List {
+        /// The archive to list
+        archive: PathBuf,
+    },
+    /// Check an RSZ archive's signature and contents (or read a tar, ZIP or 7z archive
+    /// through) without extracting it
+    Test {
+        /// The archive to test
+        archive: PathBuf,
+    },
+    /// Show the sizes, ratio, integrity check, blocks, dictionary size and filters of .xz
+    /// files (like `xz -lv`), read from their indexes without decompressing them
+    Info {
+        /// The .xz files to describe
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+
+        /// Print a JSON array with one object per file
+        #[arg(long)]
+        json: bool,
+    },
+    /// Copy every entry of an archive into a new archive of another format, e.g.
+    /// `convert in.tar.xz out.rsz` (formats are recognised by name as for `extract`; 7z
+    /// archives can only be converted from)
+    Convert {
+        /// The archive to read
+        input: PathBuf,
+
+        /// The archive to create
+        output: PathBuf,
+
+        /// Codec for RSZ and compressed tar output (default: the one a tar name such as
+        /// `.tgz` implies, otherwise xz)
+        #[arg(short, long, value_parser = parse_codec)]
+        codec: Option<Arc<dyn Codec>>,
+
+        /// Compression level (0-9)
+        #[arg(short, long, default_value_t = 6, value_parser = clap::value_parser!(u32).range(0..=9))]
+        level: u32,
+
+        /// Compression method for ZIP output (default: deflate)
+        #[arg(long, value_enum, ignore_case = true)]
+        zip_method: Option<ZipMethod>,
+
+        /// Which RSZ and ZIP output entries to store uncompressed (default: auto)
+        #[arg(long, value_enum, ignore_case = true)]
+        store: Option<StorePolicy>,
+
+        /// Also store entries with this extension uncompressed; may be repeated or comma-separated
+        #[arg(long, value_name = "EXT", value_delimiter = ',')]
+        store_ext: Vec<String>,
+
+        /// Solid blocks of the RSZ output: on, a size such as 64MiB, dir or file (default: on)
+        #[arg(long, value_name = "MODE")]
+        solid: Option<SolidBlocks>,
+
+        /// Store repeated contents in the RSZ output once (default: files)
+        #[arg(long, value_enum, ignore_case = true)]
+        dedup: Option<DedupMode>,
+
+        /// Encrypt the RSZ output with a password
+        #[arg(long)]
+        encrypt: bool,
+
+        /// Encrypt the RSZ output to this public key (rszpub1...); may be repeated
+        #[arg(long, value_name = "PUBKEY")]
+        recipient: Vec<Recipient>,
+
+        /// Encrypt the RSZ output to every public key listed in this file; may be repeated
+        #[arg(short = 'R', long, value_name = "FILE")]
+        recipients_file: Vec<PathBuf>,
+
+        /// Also encrypt the RSZ output's index
+        #[arg(long)]
+        encrypt_index: bool,
+
+        /// Sign the RSZ output with the key in this file
+        #[arg(long, value_name = "FILE")]
+        sign_key: Option<PathBuf>,
+    },
+}
 
 #[derive(Parser, Debug)]
 #[command(name = "rs-zip")]
 #[command(version = "1.0")]
 #[command(about = "LZMA compression/decompression tool with custom RSZ archive option", long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 pub struct Cli {
-    /// Input file/directory paths
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
+    /// Input file/directory paths (in XZ mode, each file is processed individually)
     #[arg(short, long, required = true, num_args = 1..)]
     pub inputs: Vec<PathBuf>,
 
-    /// Output file path (optional, required for xz compression/decompression)
+    /// Output file path (XZ mode: only with a single input; defaults to `<input>.xz` / stripped suffix)
     #[arg(short, long)]
     pub output: Option<PathBuf>,
 
@@ -27,37 +187,628 @@ pub struct Cli {
     #[arg(short, long, default_value_t = false)]
     pub recursive: bool,
 
-    /// Archive format: "xz" for single-file LZMA or "rsz" for the custom multi-file format
-    #[arg(short, long, default_value = "xz")]
-    pub format: String,
+    /// Archive format: "xz" for single-file mode, "rsz" for the custom multi-file format,
+    /// "tar" / "zip", or "7z" (extraction only)
+    #[arg(short, long, value_enum, ignore_case = true, default_value_t = ArchiveFormat::Xz)]
+    pub format: ArchiveFormat,
+
+    /// Compression codec for single-file output and RSZ archives: xz, zstd, gzip, bzip2, lz4,
+    /// lzma (legacy .lzma files) or any registered codec (detected automatically when
+    /// decompressing)
+    #[arg(short, long, value_parser = parse_codec, default_value = "xz")]
+    pub codec: Arc<dyn Codec>,
+
+    /// Single-file mode: write (or with -d, read) a headerless LZMA1 or LZMA2 stream, with
+    /// the properties from --lzma-props
+    #[arg(long, value_enum, ignore_case = true, conflicts_with = "codec")]
+    pub raw: Option<RawFormat>,
+
+    /// Properties of a --raw stream: `lc=3,lp=0,pb=2,dict=8MiB` (any subset), or the
+    /// encoded bytes in hex, 5 for LZMA1 (e.g. 5d00008000) or 1 for LZMA2 (default:
+    /// lc=3,lp=0,pb=2,dict=8MiB)
+    #[arg(long, value_name = "PROPS", requires = "raw")]
+    pub lzma_props: Option<LzmaProps>,
+
+    /// xz codec: use the slower extreme variant of the compression level
+    #[arg(short, long)]
+    pub extreme: bool,
+
+    /// xz codec: LZMA2 dictionary size, e.g. 64MiB (4 KiB - 1536 MiB; default: set by the level)
+    #[arg(long, value_name = "SIZE", value_parser = parse_dict_size)]
+    pub dict_size: Option<u32>,
+
+    /// xz codec: literal context bits (0-4, lc + lp at most 4; default 3)
+    #[arg(long, value_name = "N")]
+    pub lc: Option<u32>,
+
+    /// xz codec: literal position bits (0-4; default 0)
+    #[arg(long, value_name = "N")]
+    pub lp: Option<u32>,
+
+    /// xz codec: position bits (0-4; default 2)
+    #[arg(long, value_name = "N")]
+    pub pb: Option<u32>,
+
+    /// xz codec: match length at which the encoder stops searching (2-273; default: set by the level)
+    #[arg(long, value_name = "N")]
+    pub nice_len: Option<u32>,
+
+    /// xz codec: match finder (default: set by the level)
+    #[arg(long, value_enum, ignore_case = true)]
+    pub match_finder: Option<MatchFinder>,
+
+    /// xz codec: filters to run in front of LZMA2, comma-separated: x86, arm, armthumb,
+    /// arm64, powerpc, sparc, riscv (BCJ filters for executables) or delta:N (sampled data
+    /// with N-byte samples)
+    #[arg(long, value_name = "CHAIN", value_delimiter = ',')]
+    pub filters: Vec<Filter>,
+
+    /// xz codec: use the matching BCJ filter for ELF and PE executables (single-file mode
+    /// and RSZ archives)
+    #[arg(long, conflicts_with = "filters")]
+    pub auto_bcj: bool,
+
+    /// xz codec: integrity check stored with the data (default: crc64)
+    #[arg(long, value_enum, ignore_case = true)]
+    pub check: Option<IntegrityCheck>,
+
+    /// xz codec: start a new XZ block every SIZE bytes of input, e.g. 16MiB, so --range can
+    /// decode part of the file without the data before it (default: one block)
+    #[arg(long, value_name = "SIZE", value_parser = parse_block_size)]
+    pub block_size: Option<u64>,
+
+    /// Single-file mode with -d: decompress only LEN bytes starting at OFFSET of the
+    /// original data, e.g. 1GiB:4KiB; only the XZ blocks holding them are decoded
+    #[arg(long, value_name = "OFFSET:LEN")]
+    pub range: Option<ByteRange>,
+
+    /// Most memory the XZ/LZMA decoders may use when decompressing, listing, testing or
+    /// converting, e.g. 256MiB (default: no limit)
+    #[arg(long, value_name = "SIZE", value_parser = parse_memlimit, global = true)]
+    pub memlimit: Option<u64>,
+
+    /// Compression method for ZIP archive entries (default: deflate)
+    #[arg(long, value_enum, ignore_case = true)]
+    pub zip_method: Option<ZipMethod>,
+
+    /// Which RSZ and ZIP archive entries to store uncompressed instead of recompressing
+    /// (default: auto)
+    #[arg(long, value_enum, ignore_case = true)]
+    pub store: Option<StorePolicy>,
+
+    /// Also store archive entries with this extension (e.g. dat) uncompressed; may be
+    /// repeated or comma-separated
+    #[arg(long, value_name = "EXT", value_delimiter = ',')]
+    pub store_ext: Vec<String>,
+
+    /// How the RSZ archive splits files into solid blocks: on (one block), a size such as
+    /// 64MiB (a new block once one holds that much), dir (a block per directory) or file
+    /// (a block per file) (default: on)
+    #[arg(long, value_name = "MODE")]
+    pub solid: Option<SolidBlocks>,
+
+    /// Order files are added to the RSZ archive in: type groups similar files so they
+    /// compress better, input keeps the order they were found in (default: type)
+    #[arg(long, value_enum, ignore_case = true)]
+    pub order: Option<FileOrder>,
+
+    /// Store repeated contents in the RSZ archive once: off, files (identical files) or
+    /// chunks (also the parts near-identical files share) (default: files)
+    #[arg(long, value_enum, ignore_case = true)]
+    pub dedup: Option<DedupMode>,
+
+    /// Single-file mode: add the compressed input as a new XZ stream at the end of the
+    /// output (created if missing) instead of replacing it, e.g. to grow a log archive
+    #[arg(long, conflicts_with = "overwrite")]
+    pub append: bool,
+
+    /// Overwrite existing output files
+    #[arg(long, group = "overwrite", global = true)]
+    pub force: bool,
+
+    /// Never overwrite existing output files; skip them instead
+    #[arg(long, group = "overwrite", global = true)]
+    pub no_clobber: bool,
+
+    /// Only overwrite existing output files that are older than their source
+    #[arg(long, group = "overwrite", global = true)]
+    pub keep_newer: bool,
+
+    /// Back up existing output files before overwriting them
+    #[arg(long, value_name = "CONTROL", num_args = 0..=1, default_missing_value = "numbered", group = "overwrite", global = true)]
+    pub backup: Option<BackupControl>,
+
+    /// Keep input files after single-file (de)compression (the default)
+    #[arg(short, long, conflicts_with = "rm")]
+    pub keep: bool,
+
+    /// Delete the input file after successful single-file (de)compression
+    #[arg(long)]
+    pub rm: bool,
+
+    /// Delete source files once the archive has been written and verified
+    #[arg(long)]
+    pub remove_files: bool,
+
+    /// Number of files to process in parallel in XZ mode (0 = one per CPU)
+    #[arg(short, long, default_value_t = 1)]
+    pub jobs: usize,
+
+    /// Encrypt the RSZ archive's contents with a password (XChaCha20-Poly1305, Argon2id)
+    #[arg(long)]
+    pub encrypt: bool,
+
+    /// Encrypt the RSZ archive to this public key (rszpub1...); may be repeated
+    #[arg(long, value_name = "PUBKEY")]
+    pub recipient: Vec<Recipient>,
+
+    /// Encrypt the RSZ archive to every public key listed in this file; may be repeated
+    #[arg(short = 'R', long, value_name = "FILE")]
+    pub recipients_file: Vec<PathBuf>,
+
+    /// Also encrypt the RSZ index, hiding file names and sizes
+    #[arg(long)]
+    pub encrypt_index: bool,
+
+    /// Read the archive password from the first line of this file instead of prompting
+    #[arg(long, value_name = "FILE", global = true)]
+    pub password_file: Option<PathBuf>,
+
+    /// Decrypt with the private key(s) in this identity file (from `rs-zip keygen`); may be repeated
+    #[arg(long, value_name = "FILE", global = true)]
+    pub identity: Vec<PathBuf>,
+
+    /// Sign the RSZ archive with the key in this file (from `rs-zip keygen --signing`)
+    #[arg(long, value_name = "FILE")]
+    pub sign_key: Option<PathBuf>,
+
+    /// Require a valid signature by a key listed in this file (rszsign1...); may be repeated
+    #[arg(long, value_name = "FILE", global = true)]
+    pub verify_key: Vec<PathBuf>,
 }
 
 impl Cli {
     pub fn parse_args() -> Self {
-        let mut args = Cli::parse();
+        let matches = Cli::command().get_matches();
+        let mut args = Cli::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+        // `extract`, `list` and `test` read an archive like `-d -f rsz|tar|zip|7z -i ARCHIVE`.
+        if let Some(Command::Extract { archive } | Command::List { archive } | Command::Test { archive }) =
+            &args.command
+        {
+            args.inputs = vec![archive.clone()];
+            args.decompress = true;
+            args.format = ArchiveFormat::from_path(archive);
+        }
+
+        // `convert` writes its output like `-f <output format> -i INPUT -o OUTPUT`; the input
+        // format is recognised separately when the archive is read.
+        if let Some(Command::Convert {
+            input,
+            output,
+            codec,
+            level,
+            zip_method,
+            store,
+            store_ext,
+            solid,
+            dedup,
+            encrypt,
+            recipient,
+            recipients_file,
+            encrypt_index,
+            sign_key,
+        }) = args.command.clone()
+        {
+            args.format = ArchiveFormat::from_path(&output);
+            args.codec = codec
+                .or_else(|| crate::tarball::tar_codec(&output).filter(|_| args.format == ArchiveFormat::Tar))
+                .unwrap_or_else(codec::default_codec);
+            args.inputs = vec![input];
+            args.output = Some(output);
+            args.level = level;
+            args.zip_method = zip_method;
+            args.store = store;
+            args.store_ext = store_ext;
+            args.solid = solid;
+            args.dedup = dedup;
+            args.encrypt = encrypt;
+            args.recipient = recipient;
+            args.recipients_file = recipients_file;
+            args.encrypt_index = encrypt_index;
+            args.sign_key = sign_key;
+        }
 
-        // When compressing (not decompressing) and no output is specified, generate a default output path.
-        if args.output.is_none() && !args.decompress {
+        // `-f tar -o out.tar.gz` compresses with the codec the name implies, unless --codec
+        // picks one (a contradicting one is rejected by `validate`).
+        if args.format == ArchiveFormat::Tar
+            && !args.decompress
+            && matches.value_source("codec") != Some(ValueSource::CommandLine)
+            && let Some(codec) = args.output.as_deref().and_then(crate::tarball::tar_codec)
+        {
+            args.codec = codec;
+        }
+
+        if let Some(raw) = args.raw {
+            args.codec = Arc::new(RawLzma::new(raw, args.lzma_props.unwrap_or_default()));
+        }
+        let xz = args.xz_settings();
+        if !xz.is_default() && args.codec.id() == Builtin::Xz.id() {
+            args.codec = Arc::new(xz);
+        }
+
+        // When creating an archive and no output is specified, generate a default output path.
+        // XZ mode derives one output per input instead.
+        if args.output.is_none() && !args.decompress && args.format != ArchiveFormat::Xz {
+            let extension = match args.format {
+                ArchiveFormat::Tar => format!("tar.{}", args.codec.extension()),
+                ArchiveFormat::Zip => "zip".to_string(),
+                _ => "rsz".to_string(),
+            };
             let first_input = args.inputs.first().unwrap();
-            let input_str = first_input.to_str().unwrap_or_default();
-            let default_path = crate::file_io::default_output_path(input_str, true);
-            args.output = Some(PathBuf::from(default_path));
+            args.output = Some(crate::file_io::default_archive_path(first_input, &extension));
         }
         args
     }
 
     pub fn validate(&self) -> std::io::Result<()> {
-        for input in &self.inputs {
-            let input_str = input.to_str().unwrap_or_default();
-            crate::file_io::validate_input_path(input_str)?;
-        }
-        // For xz compression/decompression, we expect an output file. (For RSZ archives,
-        // the output is built into the archive so that isn’t strictly needed on decompression.)
-        if !self.decompress {
-            if let Some(output_path) = &self.output {
-                let _ = std::fs::File::create(output_path)?;
+        let is_rsz = self.format == ArchiveFormat::Rsz;
+        let is_archive = self.format != ArchiveFormat::Xz;
+        // `convert` reads one archive (of any format) and creates another of `self.format`.
+        let converting = matches!(self.command, Some(Command::Convert { .. }));
+        let reads_rsz = if converting {
+            ArchiveFormat::from_path(&self.inputs[0]) == ArchiveFormat::Rsz
+        } else {
+            self.decompress && is_rsz
+        };
+        // Only archive creation walks directories; everything else reads single files.
+        let allow_dirs = is_archive && !self.decompress && !converting;
+        // XZ mode checks each input as its own job, so one bad file doesn't stop the rest.
+        if is_archive || converting {
+            for input in &self.inputs {
+                crate::file_io::validate_input(input, allow_dirs)?;
+            }
+        }
+        if is_archive && self.decompress && self.inputs.len() > 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "archive extraction takes a single archive",
+            ));
+        }
+        if self.format == ArchiveFormat::Tar
+            && !self.decompress
+            && let Some(output) = &self.output
+            && let Some(implied) = crate::tarball::tar_codec(output)
+            && implied.id() != self.codec.id()
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "{} names a {} tar archive, but --codec is {}",
+                    output.display(),
+                    implied.name(),
+                    self.codec.name()
+                ),
+            ));
+        }
+        if self.rm && is_archive {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--rm applies to single-file mode; use --remove-files when creating an archive",
+            ));
+        }
+        if self.remove_files && (!is_archive || self.decompress) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--remove-files only applies when creating an RSZ, tar or ZIP archive",
+            ));
+        }
+        if self.format == ArchiveFormat::SevenZ && !self.decompress {
+            let message = if converting {
+                "7z archives can only be read; convert to an .rsz, tar or .zip archive"
+            } else {
+                "7z archives can only be read; create archives with -f rsz, tar or zip"
+            };
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, message));
+        }
+        if let Some(raw) = self.raw {
+            if is_archive {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--raw only applies to single-file mode (-f xz)",
+                ));
+            }
+            self.lzma_props.unwrap_or_default().check(raw)?;
+        }
+        let xz = self.xz_settings();
+        if !xz.is_default() || self.check.is_some() {
+            if self.decompress || self.codec.id() != Builtin::Xz.id() || self.format == ArchiveFormat::Zip {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--extreme, --dict-size, --lc, --lp, --pb, --nice-len, --match-finder, --filters, \
+                     --auto-bcj, --check and --block-size set up the xz codec, so they only apply when compressing with -c xz \
+                     (not with -f zip)",
+                ));
             }
+            if xz.auto_bcj && !matches!(self.format, ArchiveFormat::Xz | ArchiveFormat::Rsz) {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "--auto-bcj picks a filter per file, so it only applies to single-file mode and RSZ \
+                     archives; use --filters for a tar archive",
+                ));
+            }
+            xz.check(self.level)?;
+        }
+        if self.range.is_some() && (is_archive || !self.decompress || self.raw.is_some() || self.command.is_some()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--range only applies when decompressing a single .xz file (-d -f xz)",
+            ));
+        }
+        if self.range.is_some() && (self.inputs.len() > 1 || self.rm) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--range reads part of a single input, which is kept (no --rm)",
+            ));
+        }
+        if self.append && (is_archive || self.decompress || self.codec.id() != Builtin::Xz.id()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--append only applies when compressing a single file with -c xz",
+            ));
         }
+        if self.memlimit.is_some() && !self.decompress && !converting {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--memlimit limits decompression, so it only applies when extracting, listing, testing or converting",
+            ));
+        }
+        if self.zip_method.is_some() && (self.format != ArchiveFormat::Zip || self.decompress) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--zip-method only applies when creating a ZIP archive (-f zip)",
+            ));
+        }
+        if (self.store.is_some() || !self.store_ext.is_empty())
+            && (!matches!(self.format, ArchiveFormat::Rsz | ArchiveFormat::Zip) || self.decompress)
+        {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--store and --store-ext only apply when creating an RSZ or ZIP archive",
+            ));
+        }
+        if self.store == Some(StorePolicy::Never) && !self.store_ext.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--store-ext has no effect with --store never",
+            ));
+        }
+        if (self.solid.is_some() || self.order.is_some() || self.dedup.is_some()) && (!is_rsz || self.decompress) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--solid, --order and --dedup only apply when creating an RSZ archive (-f rsz)",
+            ));
+        }
+        let public_key = !self.recipient.is_empty() || !self.recipients_file.is_empty();
+        if (self.encrypt || public_key) && !is_rsz {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "encryption is only supported for RSZ archives (-f rsz)",
+            ));
+        }
+        if (self.encrypt || public_key || self.encrypt_index) && self.decompress {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--encrypt, --recipient and --encrypt-index only apply when creating an archive",
+            ));
+        }
+        if self.encrypt_index && !self.encrypt && !public_key {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--encrypt-index needs --encrypt or --recipient",
+            ));
+        }
+        if self.sign_key.is_some() && (!is_rsz || self.decompress) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--sign-key only applies when creating an RSZ archive",
+            ));
+        }
+        if !self.verify_key.is_empty() && !reads_rsz {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--verify-key only applies when reading an RSZ archive",
+            ));
+        }
+        if !self.identity.is_empty() && !self.decompress && !converting {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--identity is only used when extracting or converting",
+            ));
+        }
+        if self.password_file.is_some() && !self.decompress && !self.encrypt && !converting {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--password-file needs --encrypt when creating an archive",
+            ));
+        }
+        if !is_archive && self.output.is_some() && self.inputs.len() > 1 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--output cannot be used with multiple inputs in XZ mode; each file gets its own output",
+            ));
+        }
+        if let Some(output) = &self.output {
+            crate::file_io::check_output_outside_inputs(output, &self.inputs, self.recursive)?;
+        }
+        // Existing outputs are checked against the overwrite policy when they are written,
+        // so nothing is created or truncated here.
         Ok(())
     }
+
+    /// The xz codec settings given with `--extreme`, `--dict-size`, `--lc`, `--lp`, `--pb`,
+    /// `--nice-len`, `--match-finder`, `--filters`, `--auto-bcj` and `--check`.
+    pub fn xz_settings(&self) -> TunedXz {
+        TunedXz {
+            tuning: LzmaTuning {
+                extreme: self.extreme,
+                dict_size: self.dict_size,
+                lc: self.lc,
+                lp: self.lp,
+                pb: self.pb,
+                nice_len: self.nice_len,
+                match_finder: self.match_finder,
+            },
+            filters: self.filters.clone(),
+            auto_bcj: self.auto_bcj,
+            check: self.check.unwrap_or_default(),
+            block_size: self.block_size,
+        }
+    }
+
+    /// The rules for storing archive entries given with `--store` and `--store-ext`.
+    pub fn store_rules(&self) -> StoreRules {
+        StoreRules {
+            policy: self.store.unwrap_or_default(),
+            extensions: self
+                .store_ext
+                .iter()
+                .map(|ext| ext.trim_start_matches('.').to_string())
+                .collect(),
+        }
+    }
+
+    /// Reads the archive password from `--password-file`, or prompts for it on the terminal.
+    ///
+    /// # Arguments
+    /// * `confirm` - Ask twice and require both answers to match (used when encrypting).
+    pub fn read_password(&self, confirm: bool) -> std::io::Result<Passphrase> {
+        if let Some(path) = &self.password_file {
+            let contents = std::fs::read(path)?;
+            let line = contents.split(|&b| b == b'\n').next().unwrap_or_default();
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{} does not contain a password", path.display()),
+                ));
+            }
+            return Ok(Passphrase::new(line));
+        }
+
+        if !std::io::stdin().is_terminal() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "archive is encrypted; use --password-file or --identity when not running interactively",
+            ));
+        }
+        let password = Zeroizing::new(rpassword::prompt_password("Password: ")?);
+        if password.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "empty passwords are not allowed",
+            ));
+        }
+        if confirm {
+            let again = Zeroizing::new(rpassword::prompt_password("Confirm password: ")?);
+            if *again != *password {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "passwords do not match",
+                ));
+            }
+        }
+        Ok(Passphrase::from(password.as_str()))
+    }
+
+    /// Collects the public keys given with `--recipient` and `--recipients-file`.
+    pub fn recipients(&self) -> std::io::Result<Vec<Recipient>> {
+        let mut recipients = self.recipient.clone();
+        for path in &self.recipients_file {
+            let text = std::fs::read_to_string(path)?;
+            let parsed = crypto::parse_recipients(&text).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+            })?;
+            if parsed.is_empty() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{}: no recipients found", path.display()),
+                ));
+            }
+            recipients.extend(parsed);
+        }
+        Ok(recipients)
+    }
+
+    /// Reads the private keys from every `--identity` file.
+    pub fn identities(&self) -> std::io::Result<Vec<Identity>> {
+        let mut identities = Vec::new();
+        for path in &self.identity {
+            let text = Zeroizing::new(std::fs::read_to_string(path)?);
+            let parsed = crypto::parse_identities(&text).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+            })?;
+            identities.extend(parsed);
+        }
+        Ok(identities)
+    }
+
+    /// Reads the `--sign-key` file, if one was given.
+    pub fn signing_key(&self) -> std::io::Result<Option<SigningKey>> {
+        let Some(path) = &self.sign_key else {
+            return Ok(None);
+        };
+        let text = Zeroizing::new(std::fs::read_to_string(path)?);
+        signature::parse_signing_key(&text)
+            .map(Some)
+            .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e)))
+    }
+
+    /// Reads the trusted keys from every `--verify-key` file.
+    pub fn signers(&self) -> std::io::Result<Vec<VerifyingKey>> {
+        let mut signers = Vec::new();
+        for path in &self.verify_key {
+            let text = std::fs::read_to_string(path)?;
+            let parsed = signature::parse_verifying_keys(&text).map_err(|e| {
+                std::io::Error::new(e.kind(), format!("{}: {}", path.display(), e))
+            })?;
+            signers.extend(parsed);
+        }
+        Ok(signers)
+    }
+
+    /// Returns the overwrite policy selected by `--force`, `--no-clobber`, `--keep-newer` or `--backup`.
+    pub fn overwrite_policy(&self) -> OverwritePolicy {
+        if self.force {
+            OverwritePolicy::Force
+        } else if self.no_clobber {
+            OverwritePolicy::NoClobber
+        } else if self.keep_newer {
+            OverwritePolicy::KeepNewer
+        } else if self.backup == Some(BackupControl::Numbered) {
+            OverwritePolicy::BackupNumbered
+        } else {
+            OverwritePolicy::Refuse
+        }
+    }
+}
+
+/// Parses `--dict-size`.
+fn parse_dict_size(value: &str) -> Result<u32, String> {
+    lzma::parse_dict_size(value).map_err(|e| e.to_string())
+}
+
+/// Parses `--block-size`.
+fn parse_block_size(value: &str) -> Result<u64, String> {
+    lzma::parse_block_size(value).map_err(|e| e.to_string())
+}
+
+/// Parses `--memlimit`.
+fn parse_memlimit(value: &str) -> Result<u64, String> {
+    lzma::parse_memlimit(value).map_err(|e| e.to_string())
+}
+
+/// Parses `--codec`, accepting any codec in the registry.
+fn parse_codec(name: &str) -> Result<Arc<dyn Codec>, String> {
+    codec::by_name(name).map_err(|e| e.to_string())
 }
@@ -0,0 +1,436 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, BufReader, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::{Arc, LazyLock, RwLock};
+
+use liblzma::read::XzDecoder;
+use liblzma::stream::{LzmaOptions, Stream};
+use liblzma::write::XzEncoder;
+
+use crate::lzma::LimitedDecoder;
+
+/// XZ stream magic, also found 8 bytes in when a file uses rs-zip's legacy single-file framing.
+pub(crate) const XZ_MAGIC: [u8; 6] = [0xFD, b'7', b'z', b'X', b'Z', 0x00];
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xB5, 0x2F, 0xFD];
+const GZIP_MAGIC: [u8; 2] = [0x1F, 0x8B];
+const BZIP2_MAGIC: [u8; 3] = *b"BZh";
+const LZ4_MAGIC: [u8; 4] = [0x04, 0x22, 0x4D, 0x18];
+/// Number of leading bytes [`detect`] looks at (the length of a `.lzma` header).
+pub const MAGIC_LEN: usize = 13;
+/// Number of leading bytes [`Codec::variant_for`] is shown (enough for an ELF or PE header).
+pub const SNIFF_LEN: usize = 4096;
+/// Codec ids below this are reserved for rs-zip's built-in codecs.
+pub const FIRST_CUSTOM_ID: u8 = 128;
+/// Codec id of RSZ blocks whose contents are stored as they are (see [`Stored`]).
+pub const STORED_ID: u8 = 0;
+
+/// A compression format that rs-zip can use for single-file output and RSZ blocks.
+///
+/// Implement this for a custom compressor and [`register`] it; archives record the codec's
+/// [`id`](Codec::id), so the same codec must be registered wherever they are read.
+pub trait Codec: Send + Sync {
+    /// The identifier stored in RSZ archives. Custom codecs must use
+    /// [`FIRST_CUSTOM_ID`] or above.
+    fn id(&self) -> u8;
+
+    /// The codec's name, as accepted by `--codec`.
+    fn name(&self) -> &str;
+
+    /// The file extension used for single-file output, without the dot.
+    fn extension(&self) -> &str {
+        self.name()
+    }
+
+    /// Whether `header` (the first [`MAGIC_LEN`] or fewer bytes of a file) starts a stream
+    /// of this codec. Used to pick a decoder for single files; the default never matches.
+    fn matches(&self, _header: &[u8]) -> bool {
+        false
+    }
+
+    /// Starts a compressed stream on `writer`.
+    ///
+    /// # Arguments
+    /// * `writer` - Receives the compressed stream.
+    /// * `level` - Compression level 0-9 (codecs map it onto their own scale or ignore it).
+    fn encoder<'a>(&self, writer: Box<dyn Write + 'a>, level: u32) -> io::Result<Box<dyn Encoder + 'a>>;
+
+    /// Decodes a compressed stream read from `reader`.
+    fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>>;
+
+    /// Like [`decoder`](Codec::decoder), but the decoder may use at most `memlimit` bytes
+    /// of memory and fails with a clear error for data that needs more. Codecs that cannot
+    /// bound their memory use (the default) ignore the limit.
+    fn decoder_with_memlimit<'a>(&self, reader: Box<dyn Read + 'a>, _memlimit: u64) -> io::Result<Box<dyn Read + 'a>> {
+        self.decoder(reader)
+    }
+
+    /// A variant of this codec suited to data that starts with `head` (up to [`SNIFF_LEN`]
+    /// bytes), such as XZ with a BCJ filter for executables, with a short label that tells
+    /// variants apart. The variant's streams must decode with this codec. The default,
+    /// `None`, compresses everything with the codec itself.
+    fn variant_for(&self, _head: &[u8]) -> Option<(&'static str, Arc<dyn Codec>)> {
+        None
+    }
+}
+
+impl fmt::Debug for dyn Codec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Codec({})", self.name())
+    }
+}
+
+/// A streaming compressor. It must be finished to write the stream trailer.
+pub trait Encoder: Write {
+    /// Flushes buffered data and the stream trailer.
+    fn finish(self: Box<Self>) -> io::Result<()>;
+}
+
+/// The codecs that ship with rs-zip, registered by default.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Builtin {
+    /// LZMA2 in an XZ container (best ratio)
+    Xz,
+    /// Zstandard (fast, good ratio)
+    Zstd,
+    /// gzip/DEFLATE (widest compatibility)
+    Gzip,
+    /// bzip2
+    Bzip2,
+    /// LZ4 frame format (fastest)
+    Lz4,
+    /// Legacy `.lzma` (LZMA_Alone): LZMA1 behind a 13-byte header, as written by LZMA Utils
+    /// and the LZMA SDK
+    Lzma,
+}
+
+impl Builtin {
+    /// Every built-in codec.
+    /// `.lzma` comes last: it has no magic, so it is only detected when nothing else matches.
+    pub const ALL: [Builtin; 6] = [
+        Builtin::Xz,
+        Builtin::Zstd,
+        Builtin::Gzip,
+        Builtin::Bzip2,
+        Builtin::Lz4,
+        Builtin::Lzma,
+    ];
+
+    fn magic(self) -> &'static [u8] {
+        match self {
+            Builtin::Xz => &XZ_MAGIC,
+            Builtin::Zstd => &ZSTD_MAGIC,
+            Builtin::Gzip => &GZIP_MAGIC,
+            Builtin::Bzip2 => &BZIP2_MAGIC,
+            Builtin::Lz4 => &LZ4_MAGIC,
+            Builtin::Lzma => &[],
+        }
+    }
+}
+
+/// Whether `header` looks like a `.lzma` header, using the checks `xz` applies when it
+/// auto-detects the format: valid lc/lp/pb, a dictionary size of 2^n or 2^n + 2^(n-1)
+/// (at least 4 KiB), and an unknown or plausible (< 256 GiB) uncompressed size.
+fn is_lzma_alone_header(header: &[u8]) -> bool {
+    let Some(header) = header.get(..MAGIC_LEN) else {
+        return false;
+    };
+    let dict_size = u32::from_le_bytes([header[1], header[2], header[3], header[4]]);
+    let size = u64::from_le_bytes(header[5..13].try_into().unwrap());
+    let rounded = dict_size.checked_next_power_of_two().unwrap_or(u32::MAX);
+    let dict_ok = dict_size == u32::MAX
+        || (dict_size >= 4096 && (dict_size.is_power_of_two() || dict_size == rounded / 2 + rounded / 4));
+    header[0] < 9 * 5 * 5 && dict_ok && (size == u64::MAX || size < 1 << 38)
+}
+
+impl Codec for Builtin {
+    fn id(&self) -> u8 {
+        match self {
+            Builtin::Xz => 1,
+            Builtin::Zstd => 2,
+            Builtin::Gzip => 3,
+            Builtin::Bzip2 => 4,
+            Builtin::Lz4 => 5,
+            Builtin::Lzma => 6,
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self {
+            Builtin::Xz => "xz",
+            Builtin::Zstd => "zstd",
+            Builtin::Gzip => "gzip",
+            Builtin::Bzip2 => "bzip2",
+            Builtin::Lz4 => "lz4",
+            Builtin::Lzma => "lzma",
+        }
+    }
+
+    fn extension(&self) -> &str {
+        match self {
+            Builtin::Xz => "xz",
+            Builtin::Zstd => "zst",
+            Builtin::Gzip => "gz",
+            Builtin::Bzip2 => "bz2",
+            Builtin::Lz4 => "lz4",
+            Builtin::Lzma => "lzma",
+        }
+    }
+
+    fn matches(&self, header: &[u8]) -> bool {
+        match self {
+            Builtin::Lzma => is_lzma_alone_header(header),
+            _ => header.starts_with(self.magic()),
+        }
+    }
+
+    fn encoder<'a>(&self, writer: Box<dyn Write + 'a>, level: u32) -> io::Result<Box<dyn Encoder + 'a>> {
+        if level > 9 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Compression level must be between 0 and 9",
+            ));
+        }
+        Ok(match self {
+            Builtin::Xz => Box::new(XzEncoder::new(writer, level)),
+            // zstd's own scale runs to 22; 0-9 covers its fast and default range.
+            Builtin::Zstd => Box::new(zstd::stream::write::Encoder::new(writer, level as i32)?),
+            Builtin::Gzip => Box::new(flate2::write::GzEncoder::new(writer, flate2::Compression::new(level))),
+            Builtin::Bzip2 => Box::new(bzip2::write::BzEncoder::new(writer, bzip2::Compression::new(level.max(1)))),
+            Builtin::Lz4 => Box::new(lz4_flex::frame::FrameEncoder::new(writer)),
+            Builtin::Lzma => {
+                let options = LzmaOptions::new_preset(level).map_err(io::Error::other)?;
+                let stream = Stream::new_lzma_encoder(&options).map_err(io::Error::other)?;
+                Box::new(XzEncoder::new_stream(writer, stream))
+            }
+        })
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Builtin::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+            Builtin::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+            Builtin::Gzip => Box::new(flate2::read::MultiGzDecoder::new(reader)),
+            Builtin::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(reader)),
+            Builtin::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(reader)),
+            Builtin::Lzma => {
+                let stream = Stream::new_lzma_decoder(u64::MAX).map_err(io::Error::other)?;
+                Box::new(XzDecoder::new_stream(reader, stream))
+            }
+        })
+    }
+
+    fn decoder_with_memlimit<'a>(&self, reader: Box<dyn Read + 'a>, memlimit: u64) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(match self {
+            Builtin::Xz => Box::new(LimitedDecoder::xz(BufReader::new(reader), memlimit)?),
+            Builtin::Lzma => Box::new(LimitedDecoder::lzma_alone(BufReader::new(reader), memlimit)?),
+            _ => self.decoder(reader)?,
+        })
+    }
+}
+
+/// Leaves data as it is. RSZ archives use it for blocks of files that are compressed
+/// already (see [`StoreRules`](crate::store::StoreRules)); it is not registered, so it
+/// cannot be chosen with `--codec`, but [`by_id`] resolves its id.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Stored;
+
+impl Codec for Stored {
+    fn id(&self) -> u8 {
+        STORED_ID
+    }
+
+    fn name(&self) -> &str {
+        "store"
+    }
+
+    fn encoder<'a>(&self, writer: Box<dyn Write + 'a>, _level: u32) -> io::Result<Box<dyn Encoder + 'a>> {
+        Ok(Box::new(PassThrough(writer)))
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Ok(reader)
+    }
+}
+
+/// Writes data through unchanged, for outputs that are not compressed.
+pub(crate) struct PassThrough<W>(pub(crate) W);
+
+impl<W: Write> Write for PassThrough<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl<W: Write> Encoder for PassThrough<W> {
+    fn finish(mut self: Box<Self>) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// A writer shared between an encoder and its owner, who gets it back once the encoder
+/// has finished and dropped its handle.
+pub(crate) struct SharedWriter<W>(Rc<RefCell<W>>);
+
+impl<W> SharedWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        SharedWriter(Rc::new(RefCell::new(inner)))
+    }
+
+    /// Another handle to the same writer.
+    pub(crate) fn handle(&self) -> Self {
+        SharedWriter(Rc::clone(&self.0))
+    }
+
+    /// Takes the writer back; fails if another handle is still alive.
+    pub(crate) fn into_inner(self) -> io::Result<W> {
+        Rc::try_unwrap(self.0)
+            .map(RefCell::into_inner)
+            .map_err(|_| io::Error::other("output is still in use by an encoder"))
+    }
+}
+
+impl<W: Write> Write for SharedWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.borrow_mut().flush()
+    }
+}
+
+impl<W: Write> Encoder for XzEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        XzEncoder::finish(*self).map(drop)
+    }
+}
+
+impl<W: Write> Encoder for zstd::stream::write::Encoder<'_, W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        zstd::stream::write::Encoder::finish(*self).map(drop)
+    }
+}
+
+impl<W: Write> Encoder for flate2::write::GzEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        flate2::write::GzEncoder::finish(*self).map(drop)
+    }
+}
+
+impl<W: Write> Encoder for bzip2::write::BzEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        bzip2::write::BzEncoder::finish(*self).map(drop)
+    }
+}
+
+impl<W: Write> Encoder for lz4_flex::frame::FrameEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        lz4_flex::frame::FrameEncoder::finish(*self)
+            .map(drop)
+            .map_err(io::Error::from)
+    }
+}
+
+static REGISTRY: LazyLock<RwLock<Vec<Arc<dyn Codec>>>> = LazyLock::new(|| {
+    RwLock::new(
+        Builtin::ALL
+            .into_iter()
+            .map(|codec| Arc::new(codec) as Arc<dyn Codec>)
+            .collect(),
+    )
+});
+
+/// Makes a codec available to compression (`--codec`, `CompressOptions::codec`) and to
+/// decompression of single files and RSZ blocks.
+///
+/// # Returns
+/// * `io::Result<()>` - Ok, or `InvalidInput` if the id is reserved or the id or name is
+///   already taken.
+pub fn register(codec: Arc<dyn Codec>) -> io::Result<()> {
+    if codec.id() < FIRST_CUSTOM_ID {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("codec ids below {} are reserved for built-in codecs", FIRST_CUSTOM_ID),
+        ));
+    }
+    let mut codecs = REGISTRY.write().unwrap_or_else(|e| e.into_inner());
+    if let Some(existing) = codecs
+        .iter()
+        .find(|c| c.id() == codec.id() || c.name() == codec.name())
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "codec {} (id {}) clashes with registered codec {} (id {})",
+                codec.name(),
+                codec.id(),
+                existing.name(),
+                existing.id()
+            ),
+        ));
+    }
+    codecs.push(codec);
+    Ok(())
+}
+
+/// Returns every registered codec, built-ins first.
+pub fn registered() -> Vec<Arc<dyn Codec>> {
+    REGISTRY.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Looks up a registered codec by its stored id.
+pub fn by_id(id: u8) -> io::Result<Arc<dyn Codec>> {
+    if id == STORED_ID {
+        return Ok(Arc::new(Stored));
+    }
+    registered()
+        .into_iter()
+        .find(|codec| codec.id() == id)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("data uses codec id {}, which is not registered", id),
+            )
+        })
+}
+
+/// Looks up a registered codec by name (case-insensitive).
+pub fn by_name(name: &str) -> io::Result<Arc<dyn Codec>> {
+    let codecs = registered();
+    if let Some(codec) = codecs.iter().find(|codec| codec.name().eq_ignore_ascii_case(name)) {
+        return Ok(Arc::clone(codec));
+    }
+    let names: Vec<&str> = codecs.iter().map(|codec| codec.name()).collect();
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("unknown codec {} (available: {})", name, names.join(", ")),
+    ))
+}
+
+/// Finds the registered codec whose stream starts with `header`. `.lzma` files have no
+/// magic bytes, so their header check only runs once no other codec's magic matches;
+/// otherwise it could claim a registered codec's streams.
+pub fn detect(header: &[u8]) -> Option<Arc<dyn Codec>> {
+    let (lzma, magic): (Vec<_>, Vec<_>) =
+        registered().into_iter().partition(|codec| codec.id() == Builtin::Lzma.id());
+    magic.into_iter().chain(lzma).find(|codec| codec.matches(header))
+}
+
+/// Guesses the codec of a compressed file from its extension.
+pub fn from_path(path: &Path) -> Option<Arc<dyn Codec>> {
+    let extension = path.extension()?.to_str()?;
+    registered()
+        .into_iter()
+        .find(|codec| codec.extension().eq_ignore_ascii_case(extension))
+}
+
+/// The codec used when none is chosen (XZ).
+pub fn default_codec() -> Arc<dyn Codec> {
+    Arc::new(Builtin::Xz)
+}
@@ -1,13 +1,95 @@
-use std::fs::File;
-use std::io::{self, Read, Write};
-use xz2::write::XzEncoder;
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::cell::Cell;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use byteorder::{WriteBytesExt, LittleEndian};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::Cursor;
+use std::rc::Rc;
+use std::sync::Arc;
+use sha2::{Digest, Sha256};
+use crate::codec::{self, Builtin, Codec, Encoder, SharedWriter, Stored};
+use crate::file_io::{self, OutputFile, OverwritePolicy};
+use crate::crypto::{self, ContentKey, KeySlot, Passphrase, Recipient};
+use crate::dedup::{Chunker, DedupMode, DedupStats};
+use crate::entry::ArchiveEntry;
+use crate::progress::{self, CountingWriter, Progress, Tracker};
+use crate::rsz::{self, BlockWriter, Extent, HashingReader, HASH_LEN};
+use crate::signature::SigningKey;
+use crate::solid::{self, FileOrder, SolidBlocks};
+use crate::store::{self, StoreRules};
+use crate::zipfile::ZipMethod;
 
 
 const PAD_THRESHOLD: usize = 64;
 
+/// Size of the footer that ends every XZ stream.
+const XZ_FOOTER_LEN: u64 = 12;
+
+/// Options shared by single-file compression and RSZ archive creation.
+#[derive(Debug, Clone)]
+pub struct CompressOptions {
+    /// Compression strength (0-9, where 9 is maximum compression).
+    pub level: u32,
+    /// Compression format for single-file output and RSZ blocks; any codec in the
+    /// [`codec`] registry, or [`TunedXz`](crate::lzma::TunedXz) for XZ with custom
+    /// LZMA2 settings.
+    pub codec: Arc<dyn Codec>,
+    /// What to do when the output file already exists.
+    pub overwrite: OverwritePolicy,
+    /// Delete the source file(s) once the output has been written, synced and, for
+    /// RSZ archives, verified against the sources. Sources are kept if the output is skipped.
+    pub remove_sources: bool,
+    /// Receives progress updates while data is compressed.
+    pub progress: Option<Arc<dyn Progress>>,
+    /// Encrypt RSZ entry data with a key derived from this password.
+    pub password: Option<Passphrase>,
+    /// Encrypt RSZ entry data so that the holder of any of these public keys can extract it.
+    /// Can be combined with `password`; each gets its own key slot.
+    pub recipients: Vec<Recipient>,
+    /// Also encrypt the RSZ index, hiding file names and sizes (requires `password` or
+    /// `recipients`).
+    pub encrypt_index: bool,
+    /// Sign the RSZ archive's header and index (which holds every entry's SHA-256).
+    pub signing_key: Option<SigningKey>,
+    /// Compression method for ZIP archive entries.
+    pub zip_method: ZipMethod,
+    /// Single-file mode: add a new XZ stream to the end of an existing output (creating it
+    /// if needed) instead of applying `overwrite`. Decoders read all the streams in turn.
+    pub append: bool,
+    /// Which RSZ and ZIP entries are stored uncompressed because they are compressed already.
+    pub store: StoreRules,
+    /// How RSZ archives split their files into solid blocks.
+    pub solid: SolidBlocks,
+    /// The order files are added to RSZ archives in (entries of converted archives keep
+    /// their order).
+    pub order: FileOrder,
+    /// How RSZ archives avoid storing repeated contents.
+    pub dedup: DedupMode,
+}
+
+impl Default for CompressOptions {
+    fn default() -> Self {
+        CompressOptions {
+            level: 6,
+            codec: codec::default_codec(),
+            overwrite: OverwritePolicy::default(),
+            remove_sources: false,
+            progress: None,
+            password: None,
+            recipients: Vec::new(),
+            encrypt_index: false,
+            signing_key: None,
+            zip_method: ZipMethod::default(),
+            append: false,
+            store: StoreRules::default(),
+            solid: SolidBlocks::default(),
+            order: FileOrder::default(),
+            dedup: DedupMode::default(),
+        }
+    }
+}
+
 
 /// Compresses data from the input reader and writes it to the output writer using LZMA.
 /// 
@@ -24,8 +106,20 @@ const PAD_THRESHOLD: usize = 64;
 /// * `Result<(), std::io::Error>` - Ok on success, Io error on failure.
 pub fn compress_lzma<R: Read, W: Write>(
     input: &mut R,
-    mut output: W,
+    output: W,
+    compression_level: u32,
+) -> Result<(), std::io::Error> {
+    compress_lzma_tracked(input, output, &Builtin::Xz, compression_level, &Tracker::new(None, 1, None))
+}
+
+/// `compress_lzma` with progress reported through `tracker` as the padded data is encoded.
+/// `codec` writes the XZ stream: the built-in one, or [`TunedXz`](crate::lzma::TunedXz).
+fn compress_lzma_tracked<R: Read, W: Write>(
+    input: &mut R,
+    output: W,
+    codec: &dyn Codec,
     compression_level: u32,
+    tracker: &Tracker,
 ) -> Result<(), std::io::Error> {
     if compression_level > 9 {
         return Err(std::io::Error::new(
@@ -45,63 +139,596 @@ pub fn compress_lzma<R: Read, W: Write>(
     }
 
     // Write an 8-byte header with the original (unpadded) size.
+    let mut output = CountingWriter::new(output);
+    let written = output.counter();
     output.write_u64::<LittleEndian>(original_size)?;
 
     // Create the XZ encoder that will compress the padded data.
-    let mut encoder = XzEncoder::new(output, compression_level);
+    let mut encoder = codec.encoder(Box::new(&mut output), compression_level)?;
 
     // Use a cursor to read from the padded data buffer.
     let mut cursor = Cursor::new(data);
-    std::io::copy(&mut cursor, &mut encoder)?;
+    progress::copy_with_progress(&mut cursor, &mut encoder, |_, _, copied| {
+        tracker.report(copied.min(original_size), written.get())
+    })?;
 
     // Finalize the encoder and flush.
-    let mut final_output = encoder.finish()?;
-    final_output.flush()?;
+    encoder.finish()?;
+    output.flush()?;
+    tracker.report(original_size, output.count());
+    Ok(())
+}
+
+/// Compresses data from the input reader into a standard stream of the given codec,
+/// readable by that codec's usual tools (no rs-zip header or padding).
+///
+/// # Arguments
+/// * `input` - A reader implementing the Read trait for uncompressed data.
+/// * `output` - A writer implementing the Write trait for compressed data.
+/// * `codec` - The compression format.
+/// * `compression_level` - Compression strength (0-9, where 9 is maximum compression).
+///
+/// # Returns
+/// * `Result<(), std::io::Error>` - Ok on success, Io error on failure.
+pub fn compress_stream<R: Read, W: Write>(
+    input: &mut R,
+    output: W,
+    codec: &dyn Codec,
+    compression_level: u32,
+) -> io::Result<()> {
+    compress_stream_tracked(input, output, codec, compression_level, &Tracker::new(None, 1, None))
+}
+
+/// `compress_stream` with progress reported through `tracker`.
+fn compress_stream_tracked<R: Read, W: Write>(
+    input: &mut R,
+    output: W,
+    codec: &dyn Codec,
+    compression_level: u32,
+    tracker: &Tracker,
+) -> io::Result<()> {
+    let mut output = CountingWriter::new(output);
+    let written = output.counter();
+    let mut encoder = codec.encoder(Box::new(&mut output), compression_level)?;
+    let copied = progress::copy_with_progress(input, &mut encoder, |_, _, copied| {
+        tracker.report(copied, written.get())
+    })?;
+    encoder.finish()?;
+    output.flush()?;
+    tracker.report(copied, output.count());
+    Ok(())
+}
+
+/// Compresses a single file on disk, honouring the overwrite policy. XZ output keeps
+/// rs-zip's size header and padding (see `compress_lzma`); other codecs write a standard stream.
+/// With `options.append`, a standard XZ stream is added to the end of the output instead.
+///
+/// # Arguments
+/// * `input_path` - Path of the file to compress.
+/// * `output_path` - Path of the compressed file to write.
+/// * `options` - Codec, compression level, overwrite policy or appending, and source removal.
+///
+/// # Returns
+/// * `Result<(), io::Error>` - Ok on success (including when the policy skips the output).
+pub fn compress_file(
+    input_path: &Path,
+    output_path: &Path,
+    options: &CompressOptions,
+) -> io::Result<()> {
+    file_io::check_distinct_paths(input_path, output_path)?;
+    let mut input_file = File::open(input_path)?;
+    let metadata = input_file.metadata()?;
+    let source_modified = metadata.modified().ok();
+    let opened = if options.append {
+        if options.codec.id() != Builtin::Xz.id() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "only XZ streams can be appended to an existing file",
+            ));
+        }
+        let (file, len) = open_xz_for_append(output_path)?;
+        Some((file, Some(len), false))
+    } else {
+        file_io::create_output_file_with_policy(output_path, options.overwrite, source_modified)?
+            .map(|file| (file.writer, None, file.created))
+    };
+    let Some((mut output_file, appended_at, created)) = opened else {
+        return Ok(());
+    };
+    let mut tracker = Tracker::new(options.progress.as_deref(), 1, Some(metadata.len()));
+    tracker.start_entry(0, &input_path.to_string_lossy(), 0, 0);
+    // Let the codec adapt to the contents (e.g. a BCJ filter for executables).
+    let mut input = BufReader::with_capacity(codec::SNIFF_LEN, &mut input_file);
+    let variant = options.codec.variant_for(input.fill_buf()?).map(|(_, codec)| codec);
+    let codec = variant.as_deref().unwrap_or(options.codec.as_ref());
+    // Appended data goes in a standard stream, so a file `xz` made still decodes with `xz`.
+    let result = if codec.id() == Builtin::Xz.id() && appended_at.is_none() {
+        compress_lzma_tracked(&mut input, &mut output_file, codec, options.level, &tracker)
+    } else {
+        compress_stream_tracked(&mut input, &mut output_file, codec, options.level, &tracker)
+    };
+    if let Err(e) = result {
+        match appended_at {
+            // Cut the file back to what it held, without flushing what is still buffered.
+            Some(len) => {
+                let (file, _) = output_file.into_parts();
+                let _ = file.set_len(len);
+            }
+            // Don't leave a truncated output behind that looks like a finished one.
+            None => {
+                drop(output_file);
+                file_io::remove_partial_output(output_path, created);
+            }
+        }
+        return Err(e);
+    }
+
+    if options.remove_sources {
+        // Make sure the compressed copy is on disk before the original goes away.
+        output_file.get_ref().sync_all()?;
+        drop(input_file);
+        fs::remove_file(input_path)?;
+    }
     Ok(())
 }
 
-/// Creates a custom RSZ archive from a list of files with padding and original size metadata.
+/// Opens `path` for appending, creating it if it doesn't exist. An existing file must end
+/// with an XZ stream (rs-zip's or a standard one), or appending would leave it undecodable.
+///
+/// # Returns
+/// * `io::Result<(BufWriter<File>, u64)>` - The writer, and the file's length before appending.
+fn open_xz_for_append(path: &Path) -> io::Result<(BufWriter<File>, u64)> {
+    let mut file = fs::OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    let len = file.metadata()?.len();
+    if len > 0 {
+        // Every XZ stream ends with a footer whose last two bytes are "YZ".
+        let mut magic = [0u8; 2];
+        file.seek(SeekFrom::Start(len.saturating_sub(2)))?;
+        if len < XZ_FOOTER_LEN || file.read_exact(&mut magic).is_err() || magic != *b"YZ" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} does not end with an XZ stream, so nothing can be appended to it", path.display()),
+            ));
+        }
+    }
+    Ok((BufWriter::new(file), len))
+}
+
+/// Creates an RSZ archive from a list of files: a header, solid blocks (compressed with
+/// `options.codec`, split as `options.solid` says) with the concatenated file contents in
+/// `options.order`, and an index of names, sizes and offsets. With a password
+/// or recipients the block is encrypted (XChaCha20-Poly1305 under a random key that is
+/// wrapped once per password or recipient), and with `encrypt_index` the index is too.
+/// Every entry's SHA-256 is recorded in the index; with a signing key, the header and
+/// index are signed. Repeated contents are stored once, as `options.dedup` says.
 ///
 /// # Arguments
 /// * `files` - Slice of PathBufs representing the input files.
 /// * `output_path` - Path to the output archive file.
-/// * `compression_level` - Compression strength (0–9).
+/// * `options` - Compression level, overwrite policy, encryption and source removal for the archive.
+///   With `remove_sources`, only regular files are deleted; directories are left in place.
 ///
 /// # Returns
-/// * `Result<(), io::Error>` - Ok on success or an error.
+/// * `Result<Option<DedupStats>, io::Error>` - What deduplication saved, or `None` if the
+///   policy skipped the output.
 pub fn create_padded_archive(
     files: &[PathBuf],
     output_path: &str,
-    compression_level: u32,
-) -> io::Result<()> {
-    let output_file = File::create(output_path)?;
-    let mut encoder = XzEncoder::new(output_file, compression_level);
+    options: &CompressOptions,
+) -> io::Result<Option<DedupStats>> {
+    RszWriter::check_options(options)?;
 
-    let valid_files: Vec<&PathBuf> = files
+    // Non-UTF-8 names are skipped, so only these files are ever candidates for removal.
+    let mut entries = files
         .iter()
         .filter(|p| p.to_str().is_some())
-        .collect();
+        .map(|path| Ok((path.clone(), file_io::archive_entry_name(path)?)))
+        .collect::<io::Result<Vec<_>>>()?;
+    solid::sort_names(&mut entries, |(_, name)| name, options.order, options.solid);
 
-    encoder.write_u64::<LittleEndian>(valid_files.len() as u64)?;
+    // The newest input decides whether an existing archive counts as up to date.
+    let source_modified = files
+        .iter()
+        .filter_map(|p| p.metadata().and_then(|m| m.modified()).ok())
+        .max();
+    let Some(OutputFile { writer: output_file, created }) =
+        file_io::create_output_file_with_policy(Path::new(output_path), options.overwrite, source_modified)?
+    else {
+        return Ok(None);
+    };
 
-    for file_path in valid_files {
-        let file_name = file_path.to_str().unwrap();
-        let mut file = File::open(file_path)?;
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer)?;
-        let original_size = buffer.len() as u64;
+    let total_in = entries
+        .iter()
+        .filter_map(|(path, _)| path.metadata().ok())
+        .map(|m| m.len())
+        .sum();
+    let result = RszWriter::new(output_file, options, entries.len() as u64, Some(total_in)).and_then(|mut writer| {
+        for (path, name) in &entries {
+            let mut file = File::open(path)?;
+            let entry = ArchiveEntry::from_metadata(name.clone(), &file.metadata()?);
+            // A file the size of an earlier one is hashed first, and only stored if it differs.
+            if writer.may_duplicate(entry.size) {
+                let (len, sha256) = rsz::hash_contents(&mut (&mut file).take(entry.size))?;
+                if len == entry.size && writer.add_duplicate(&entry, sha256) {
+                    continue;
+                }
+                file.seek(SeekFrom::Start(0))?;
+            }
+            writer.add_file(&entry, &mut file)?;
+        }
+        writer.finish()
+    });
+    let (key, stats) = match result {
+        Ok(finished) => finished,
+        Err(e) => {
+            // Don't leave a truncated archive behind that looks like a finished one.
+            file_io::remove_partial_output(Path::new(output_path), created);
+            return Err(e);
+        }
+    };
 
-        // Write metadata
-        encoder.write_u64::<LittleEndian>(file_name.len() as u64)?;
-        encoder.write_all(file_name.as_bytes())?;
-        encoder.write_u64::<LittleEndian>(original_size)?;
+    if options.remove_sources {
+        File::open(output_path)?.sync_all()?;
+        let unlock = rsz::Unlock {
+            content_key: key.as_deref(),
+            ..Default::default()
+        };
+        crate::decompression::verify_rsz(output_path, &entries, &unlock)?;
+        for (path, _) in &entries {
+            fs::remove_file(path)?;
+        }
+    }
+    Ok(Some(stats))
+}
+
+/// Writes an RSZ archive one entry at a time: the header first, then every file's contents
+/// into solid blocks, and the index (plus signature and trailer) on `finish`. Everything
+/// goes into one block unless `options.solid` asks for smaller ones, some files are stored
+/// (see [`StoreRules`]) or the codec picks a different variant for them (see
+/// [`Codec::variant_for`]); each run of files with the same variant gets its own.
+pub(crate) struct RszWriter<'a> {
+    /// The block entries are being appended to.
+    block: Option<OpenBlock>,
+    /// The archive file while no block is open.
+    output: Option<CountingWriter<BufWriter<File>>>,
+    written: Rc<Cell<u64>>,
+    options: &'a CompressOptions,
+    header: rsz::Header,
+    key: Option<ContentKey>,
+    index: rsz::Index,
+    tracker: Tracker<'a>,
+    bytes_in: u64,
+    /// Sizes of the files added so far, to tell which files may repeat one of them.
+    sizes: HashSet<u64>,
+    /// Where each distinct file's contents are, by size and SHA-256.
+    contents: HashMap<(u64, [u8; HASH_LEN]), (u32, u64)>,
+    /// Where each distinct chunk is, by SHA-256 (with `DedupMode::Chunks`).
+    chunks: HashMap<[u8; HASH_LEN], Extent>,
+    dedup: DedupStats,
+}
+
+/// A block being written: its encoder and where it started.
+struct OpenBlock {
+    encoder: Box<dyn Encoder>,
+    writer: SharedWriter<BlockWriter<CountingWriter<BufWriter<File>>>>,
+    codec_id: u8,
+    /// The label of the codec variant the block is compressed with, if any.
+    variant: Option<&'static str>,
+    /// The directory of the file that started the block, for per-directory blocks.
+    dir: String,
+    offset: u64,
+    nonce_prefix: [u8; crypto::NONCE_PREFIX_LEN],
+    raw_len: u64,
+}
 
-        // Write original content WITHOUT padding
-        let mut cursor = io::Cursor::new(buffer);
-        io::copy(&mut cursor, &mut encoder)?;
+impl<'a> RszWriter<'a> {
+    /// Rejects codecs the archive could not be read back with and encryption options
+    /// that cannot be honoured.
+    pub(crate) fn check_options(options: &CompressOptions) -> io::Result<()> {
+        // Blocks are decoded by the codec registered under the stored id.
+        if !codec::by_id(options.codec.id()).is_ok_and(|codec| codec.name() == options.codec.name()) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("codec {} is not registered, so RSZ archives cannot use it", options.codec.name()),
+            ));
+        }
+        let encrypt = options.password.is_some() || !options.recipients.is_empty();
+        if options.encrypt_index && !encrypt {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "encrypting the index requires a password or recipients",
+            ));
+        }
+        if options.recipients.len() + usize::from(options.password.is_some()) > u8::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("an archive can have at most {} recipients", u8::MAX),
+            ));
+        }
+        Ok(())
     }
 
-    let mut output = encoder.finish()?;
-    output.flush()?;
-    Ok(())
-}
\ No newline at end of file
+    /// Writes the header (with a key slot per password or recipient) and starts the block.
+    pub(crate) fn new(
+        output: BufWriter<File>,
+        options: &'a CompressOptions,
+        entry_count: u64,
+        total_in: Option<u64>,
+    ) -> io::Result<Self> {
+        let mut output = CountingWriter::new(output);
+        let mut header = rsz::Header {
+            version: rsz::VERSION,
+            flags: rsz::FLAG_HASHED | rsz::FLAG_BLOCK_CODECS,
+            slots: Vec::new(),
+        };
+        if options.signing_key.is_some() {
+            header.flags |= rsz::FLAG_SIGNED;
+        }
+        if options.dedup == DedupMode::Chunks {
+            header.flags |= rsz::FLAG_EXTENTS;
+        }
+        let key = if options.password.is_some() || !options.recipients.is_empty() {
+            header.flags |= rsz::FLAG_ENCRYPTED;
+            // The key slots are bound to these flags, so neither can be dropped later.
+            header.flags |= if options.encrypt_index {
+                rsz::FLAG_INDEX_ENCRYPTED
+            } else {
+                rsz::FLAG_INDEX_AUTHENTICATED
+            };
+            let key = crypto::generate_key();
+            if let Some(password) = &options.password {
+                header.slots.push(KeySlot::for_passphrase(&key, password, &header.aad())?);
+            }
+            for recipient in &options.recipients {
+                header.slots.push(KeySlot::for_recipient(&key, recipient, &header.aad())?);
+            }
+            Some(key)
+        } else {
+            None
+        };
+        header.write_to(&mut output)?;
+
+        Ok(RszWriter {
+            block: None,
+            written: output.counter(),
+            output: Some(output),
+            options,
+            header,
+            key,
+            index: rsz::Index::default(),
+            tracker: Tracker::new(options.progress.as_deref(), entry_count, total_in),
+            bytes_in: 0,
+            sizes: HashSet::new(),
+            contents: HashMap::new(),
+            chunks: HashMap::new(),
+            dedup: DedupStats::default(),
+        })
+    }
+
+    /// Ends the current block, if any, and starts a new one compressed with `variant` (or
+    /// the options' codec).
+    fn start_block(&mut self, variant: Option<(&'static str, Arc<dyn Codec>)>) -> io::Result<()> {
+        self.end_block()?;
+        let output = self.output.take().expect("no block is open");
+        let offset = output.count();
+        let nonce_prefix = crypto::random_bytes();
+        let writer = SharedWriter::new(BlockWriter::new(output, self.key.as_ref(), nonce_prefix));
+        let (label, codec) = match variant {
+            Some((label, codec)) => (Some(label), codec),
+            None => (None, Arc::clone(&self.options.codec)),
+        };
+        let encoder = codec.encoder(Box::new(writer.handle()), self.options.level)?;
+        self.block = Some(OpenBlock {
+            encoder,
+            writer,
+            codec_id: codec.id(),
+            variant: label,
+            dir: String::new(),
+            offset,
+            nonce_prefix,
+            raw_len: 0,
+        });
+        Ok(())
+    }
+
+    /// Finishes the current block, if any, and records it in the index.
+    fn end_block(&mut self) -> io::Result<()> {
+        let Some(block) = self.block.take() else {
+            return Ok(());
+        };
+        block.encoder.finish()?;
+        let output = block.writer.into_inner()?.finish()?;
+        self.index.blocks.push(rsz::BlockInfo {
+            offset: block.offset,
+            stored_len: output.count() - block.offset,
+            raw_len: block.raw_len,
+            codec: block.codec_id,
+            nonce_prefix: block.nonce_prefix,
+        });
+        self.output = Some(output);
+        Ok(())
+    }
+
+    /// Whether a file of `size` bytes may have the same contents as an earlier one, so the
+    /// caller should hash it and try [`add_duplicate`](RszWriter::add_duplicate) first.
+    pub(crate) fn may_duplicate(&self, size: u64) -> bool {
+        self.options.dedup != DedupMode::Off && size > 0 && self.sizes.contains(&size)
+    }
+
+    /// Adds `entry` as a copy of an earlier file with the same contents, if there is one.
+    ///
+    /// # Arguments
+    /// * `entry` - The file to add.
+    /// * `sha256` - The SHA-256 of its contents.
+    ///
+    /// # Returns
+    /// * `bool` - false if no earlier file has these contents; nothing was added then.
+    pub(crate) fn add_duplicate(&mut self, entry: &ArchiveEntry, sha256: [u8; HASH_LEN]) -> bool {
+        let Some(&(block, offset)) = self.contents.get(&(entry.size, sha256)) else {
+            return false;
+        };
+        self.tracker.start_entry(self.index.entries.len() as u64, &entry.name, self.bytes_in, self.written.get());
+        self.index.entries.push(rsz::EntryInfo {
+            name: entry.name.clone(),
+            size: entry.size,
+            block,
+            offset,
+            sha256: Some(sha256),
+            extents: Vec::new(),
+        });
+        self.bytes_in += entry.size;
+        self.dedup.files += 1;
+        self.dedup.bytes_saved += entry.size;
+        self.tracker.report(self.bytes_in, self.written.get());
+        true
+    }
+
+    /// Appends a file, copying exactly `entry.size` bytes from `data`. RSZ records only the
+    /// name, size and SHA-256 of each entry. With `DedupMode::Chunks`, chunks that are in
+    /// the archive already are not stored again.
+    pub(crate) fn add_file(&mut self, entry: &ArchiveEntry, data: &mut dyn Read) -> io::Result<()> {
+        let entry_offset = self.bytes_in;
+        self.tracker.start_entry(self.index.entries.len() as u64, &entry.name, entry_offset, self.written.get());
+
+        // The start of the file decides whether it is stored and otherwise which codec variant
+        // (and so which block) it goes to; empty files just join the current block.
+        let mut head = vec![0u8; store::PROBE_LEN.min(usize::try_from(entry.size).unwrap_or(usize::MAX))];
+        let head_len = rsz::read_up_to(data, &mut head)?;
+        head.truncate(head_len);
+        let variant = if head_len > 0 && self.options.store.should_store(&entry.name, &head) {
+            Some(("store", Arc::new(Stored) as Arc<dyn Codec>))
+        } else {
+            self.options.codec.variant_for(&head[..head_len.min(codec::SNIFF_LEN)])
+        };
+        let label = variant.as_ref().map(|(label, _)| *label);
+        let solid = self.options.solid;
+        if self.block.as_ref().is_none_or(|block| {
+            head_len > 0 && (block.variant != label || solid.starts_block(block.raw_len, &block.dir, &entry.name))
+        }) {
+            self.start_block(variant)?;
+            let block = self.block.as_mut().expect("a block was just started");
+            block.dir = solid::parent(&entry.name).to_string();
+        }
+        let block = self.block.as_mut().expect("a block was just started");
+        let start = Extent {
+            block: self.index.blocks.len() as u32,
+            offset: block.raw_len,
+            len: 0,
+        };
+
+        let mut data = HashingReader::new(Cursor::new(head).chain(data.take(entry.size - head_len as u64)));
+        let (tracker, written) = (&self.tracker, &self.written);
+        let (size, mut extents) = if self.options.dedup == DedupMode::Chunks {
+            write_chunks(&mut data, block, start.block, &mut self.chunks, &mut self.dedup, |copied| {
+                tracker.report(entry_offset + copied, written.get())
+            })?
+        } else {
+            let size = progress::copy_with_progress(&mut data, &mut block.encoder, |_, _, copied| {
+                tracker.report(entry_offset + copied, written.get())
+            })?;
+            block.raw_len += size;
+            (size, Vec::new())
+        };
+        if size != entry.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrank while it was being archived", entry.name),
+            ));
+        }
+        // Contents in one run need no extents; `block` and `offset` say where they are.
+        let first = extents.first().copied().unwrap_or(start);
+        if extents.len() == 1 {
+            extents.clear();
+        }
+        let sha256 = data.digest();
+        self.index.entries.push(rsz::EntryInfo {
+            name: entry.name.clone(),
+            size,
+            block: first.block,
+            offset: first.offset,
+            sha256: Some(sha256),
+            extents,
+        });
+        if self.options.dedup != DedupMode::Off && size > 0 {
+            self.sizes.insert(size);
+            self.contents.entry((size, sha256)).or_insert((first.block, first.offset));
+        }
+        self.bytes_in += size;
+        Ok(())
+    }
+
+    /// Ends the last block and writes the index, signature and trailer.
+    ///
+    /// # Returns
+    /// * `Result<(Option<ContentKey>, DedupStats), io::Error>` - The content key of an
+    ///   encrypted archive, so it can be verified without unlocking a key slot, and what
+    ///   deduplication saved.
+    pub(crate) fn finish(mut self) -> io::Result<(Option<ContentKey>, DedupStats)> {
+        // An archive without files still gets its (empty) block.
+        if self.block.is_none() && self.index.blocks.is_empty() {
+            self.start_block(None)?;
+        }
+        self.end_block()?;
+        let mut output = self.output.take().expect("every block has been ended");
+
+        let index_offset = output.count();
+        let packed_index = rsz::pack_index(&self.index, &self.header, self.key.as_ref(), self.options.level)?;
+        output.write_all(&packed_index)?;
+        if let Some(signing_key) = &self.options.signing_key {
+            rsz::write_signature(&mut output, signing_key, &self.header, &packed_index)?;
+        }
+        rsz::write_trailer(&mut output, index_offset, packed_index.len() as u64)?;
+        output.flush()?;
+        self.tracker.report(self.bytes_in, output.count());
+        Ok((self.key, self.dedup))
+    }
+}
+
+/// Splits `data` into content-defined chunks and writes those not in `chunks` yet to
+/// `block` (block number `block_index`), recording where they went.
+///
+/// # Returns
+/// * `io::Result<(u64, Vec<Extent>)>` - The number of bytes read, and where they are in the
+///   archive, with adjacent runs merged.
+fn write_chunks(
+    data: &mut dyn Read,
+    block: &mut OpenBlock,
+    block_index: u32,
+    chunks: &mut HashMap<[u8; HASH_LEN], Extent>,
+    stats: &mut DedupStats,
+    mut progress: impl FnMut(u64),
+) -> io::Result<(u64, Vec<Extent>)> {
+    let mut chunker = Chunker::new(data);
+    let mut extents: Vec<Extent> = Vec::new();
+    let (mut copied, mut saved) = (0u64, 0u64);
+    while let Some(chunk) = chunker.next_chunk()? {
+        let len = chunk.len() as u64;
+        let hash: [u8; HASH_LEN] = Sha256::digest(chunk).into();
+        let extent = match chunks.get(&hash) {
+            Some(&extent) => {
+                stats.chunks += 1;
+                saved += len;
+                extent
+            }
+            None => {
+                block.encoder.write_all(chunk)?;
+                let extent = Extent { block: block_index, offset: block.raw_len, len };
+                block.raw_len += len;
+                chunks.insert(hash, extent);
+                extent
+            }
+        };
+        match extents.last_mut() {
+            Some(last) if last.block == extent.block && last.offset + last.len == extent.offset => last.len += len,
+            _ => extents.push(extent),
+        }
+        copied += len;
+        progress(copied);
+    }
+    // A file made only of chunks stored before is a copy, even if split up differently.
+    if copied > 0 && saved == copied {
+        stats.files += 1;
+    }
+    stats.bytes_saved += saved;
+    Ok((copied, extents))
+}
@@ -0,0 +1,233 @@
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Read};
+use std::path::{Component, Path};
+
+use crate::cli::ArchiveFormat;
+use crate::codec;
+use crate::compression::{CompressOptions, RszWriter};
+use crate::decompression::{self, ExtractOptions};
+use crate::dedup::DedupStats;
+use crate::entry::{ArchiveEntry, EntryKind};
+use crate::file_io::{self, OutputFile};
+use crate::rsz;
+use crate::sevenzip;
+use crate::tarball::{self, TarWriter};
+use crate::zipfile::{self, ZipWriter};
+
+/// What a conversion wrote, and what the destination format could not hold.
+#[derive(Debug, Default, Clone)]
+pub struct ConvertReport {
+    /// Files written to the destination.
+    pub files: u64,
+    /// Directory entries written to the destination.
+    pub directories: u64,
+    /// Total size of the files written.
+    pub bytes: u64,
+    /// Entries left out, with the reason (e.g. `"docs/latest (symlink)"`).
+    pub skipped: Vec<String>,
+    /// Directory entries dropped because the destination has none (their files are kept).
+    pub lost_directories: u64,
+    /// Entries whose unix permissions were dropped.
+    pub lost_modes: u64,
+    /// Entries whose modification time was dropped.
+    pub lost_times: u64,
+    /// Entries whose owner and group were dropped.
+    pub lost_owners: u64,
+    /// What deduplication saved (RSZ destinations only).
+    pub dedup: DedupStats,
+}
+
+impl fmt::Display for ConvertReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} files ({} bytes)", self.files, self.bytes)?;
+        if self.directories > 0 {
+            write!(f, " and {} directories", self.directories)?;
+        }
+        if !self.dedup.is_empty() {
+            write!(f, "; {}", self.dedup)?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies every entry of an archive into a new archive of another (or the same) format,
+/// streaming one entry at a time. Formats are chosen by file name as for `extract`: the
+/// source may be RSZ, tar, ZIP or 7z, the destination RSZ, tar or ZIP.
+///
+/// Directories, permissions, modification times and owners are kept where the destination
+/// can store them (tar keeps all four, ZIP all but owners, RSZ none of them). Links and
+/// special files cannot be written to any format and are skipped. Entry names are made
+/// relative, and names containing `..` are rejected.
+///
+/// # Arguments
+/// * `input` - Path to the source archive.
+/// * `output` - Path to the archive to create. A tar archive is compressed with `write.codec`
+///   unless the name ends in `.tar`; `tarball::tar_codec` finds the codec a name implies.
+/// * `read` - Password, identities and trusted signers for the source.
+/// * `write` - Codec, level, ZIP method, encryption, signing and overwrite policy for the
+///   destination. The overwrite policy compares against the source archive's time.
+///
+/// # Returns
+/// * `Result<Option<ConvertReport>, io::Error>` - What was converted and lost, or `None`
+///   if the policy skipped the output. On error the partial output is removed.
+pub fn convert_archive(
+    input: &Path,
+    output: &Path,
+    read: &ExtractOptions,
+    write: &CompressOptions,
+) -> io::Result<Option<ConvertReport>> {
+    let from = ArchiveFormat::from_path(input);
+    let to = ArchiveFormat::from_path(output);
+    match to {
+        ArchiveFormat::Rsz => RszWriter::check_options(write)?,
+        ArchiveFormat::Tar => TarWriter::check_options(write)?,
+        ArchiveFormat::Zip => ZipWriter::check_options(write)?,
+        ArchiveFormat::SevenZ | ArchiveFormat::Xz => {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{} cannot be written; convert to an RSZ, tar or ZIP archive", output.display()),
+            ));
+        }
+    }
+
+    // Anything without an archive name is read as RSZ, whose legacy layout is a bare XZ
+    // stream; other compressed single files would only fail with a confusing error there.
+    if from == ArchiveFormat::Rsz
+        && let Some(codec) = codec::from_path(input)
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} is a single {} file, not an archive; convert reads RSZ, tar, ZIP and 7z archives",
+                input.display(),
+                codec.name()
+            ),
+        ));
+    }
+
+    let source_modified = fs::metadata(input)?.modified().ok();
+    let Some(OutputFile { writer: file, created }) =
+        file_io::create_output_file_with_policy(output, write.overwrite, source_modified)?
+    else {
+        return Ok(None);
+    };
+    let mut report = ConvertReport::default();
+    let result = Writer::new(to, file, output, write).and_then(|mut writer| {
+        let mut visit = |entry: &ArchiveEntry, data: &mut dyn Read| writer.add(entry, data, &mut report);
+        match from {
+            ArchiveFormat::Tar => tarball::read_tar_entries(input, read, &mut visit),
+            ArchiveFormat::Zip => zipfile::read_zip_entries(input, read, &mut visit),
+            ArchiveFormat::SevenZ => sevenzip::read_7z_entries(input, read, &mut visit),
+            ArchiveFormat::Rsz | ArchiveFormat::Xz => decompression::read_rsz_entries(input, read, &mut visit),
+        }?;
+        writer.finish(&mut report)
+    });
+    if let Err(e) = result {
+        // Don't leave a truncated archive behind that looks like a finished one.
+        file_io::remove_partial_output(output, created);
+        return Err(e);
+    }
+    Ok(Some(report))
+}
+
+/// The destination archive, with what its format can record.
+enum Writer<'a> {
+    Rsz(Box<RszWriter<'a>>),
+    Tar(TarWriter<'a>),
+    Zip(ZipWriter<'a>),
+}
+
+impl<'a> Writer<'a> {
+    fn new(format: ArchiveFormat, file: BufWriter<File>, path: &Path, options: &'a CompressOptions) -> io::Result<Self> {
+        Ok(match format {
+            ArchiveFormat::Tar => Writer::Tar(TarWriter::new(file, path, options, 0, None)?),
+            ArchiveFormat::Zip => Writer::Zip(ZipWriter::new(file, options, 0, None)),
+            _ => Writer::Rsz(Box::new(RszWriter::new(file, options, 0, None)?)),
+        })
+    }
+
+    /// Writes one source entry, or records why it was left out, and counts what was lost.
+    fn add(&mut self, entry: &ArchiveEntry, data: &mut dyn Read, report: &mut ConvertReport) -> io::Result<()> {
+        let path = match entry.kind {
+            EntryKind::Directory => match file_io::safe_extract_dir(&entry.name)? {
+                Some(path) => path,
+                // The archive root itself (`./`) is not an entry of its own.
+                None => return Ok(()),
+            },
+            _ => file_io::safe_extract_path(&entry.name)?,
+        };
+        let name = path
+            .components()
+            .filter_map(|c| match c {
+                Component::Normal(part) => Some(part.to_string_lossy()),
+                _ => None,
+            })
+            .collect::<Vec<_>>()
+            .join("/");
+        let entry = ArchiveEntry { name, ..entry.clone() };
+
+        match (entry.kind, &mut *self) {
+            (EntryKind::Other(what), _) => {
+                report.skipped.push(format!("{} ({})", entry.name, what));
+                return Ok(());
+            }
+            (EntryKind::Directory, Writer::Rsz(_)) => {
+                report.lost_directories += 1;
+                return Ok(());
+            }
+            (EntryKind::Directory, Writer::Tar(writer)) => writer.add_directory(&entry)?,
+            (EntryKind::Directory, Writer::Zip(writer)) => writer.add_directory(&entry)?,
+            (EntryKind::File, Writer::Rsz(writer)) => add_rsz_file(writer, &entry, data)?,
+            (EntryKind::File, Writer::Tar(writer)) => writer.add_file(&entry, data)?,
+            (EntryKind::File, Writer::Zip(writer)) => writer.add_file(&entry, data)?,
+        }
+        match entry.kind {
+            EntryKind::Directory => report.directories += 1,
+            _ => {
+                report.files += 1;
+                report.bytes += entry.size;
+            }
+        }
+
+        let (modes, times, owners) = match self {
+            Writer::Rsz(_) => (false, false, false),
+            Writer::Tar(_) => (true, true, true),
+            Writer::Zip(_) => (true, true, false),
+        };
+        report.lost_modes += u64::from(entry.mode.is_some() && !modes);
+        report.lost_times += u64::from(entry.modified.is_some() && !times);
+        report.lost_owners += u64::from(entry.owner.is_some() && !owners);
+        Ok(())
+    }
+
+    fn finish(self, report: &mut ConvertReport) -> io::Result<()> {
+        match self {
+            Writer::Rsz(writer) => {
+                report.dedup = writer.finish()?.1;
+                Ok(())
+            }
+            Writer::Tar(writer) => writer.finish(),
+            Writer::Zip(writer) => writer.finish(),
+        }
+    }
+}
+
+/// Largest file a conversion reads into memory to check whether it repeats an earlier one;
+/// the source can only be read once, so larger files are always stored in full.
+const MAX_DEDUP_BUFFER: u64 = 64 * 1024 * 1024;
+
+/// Adds a file to an RSZ destination, as a copy of an earlier file when it has the same
+/// contents.
+fn add_rsz_file(writer: &mut RszWriter, entry: &ArchiveEntry, data: &mut dyn Read) -> io::Result<()> {
+    if !writer.may_duplicate(entry.size) || entry.size > MAX_DEDUP_BUFFER {
+        return writer.add_file(entry, data);
+    }
+    let mut contents = Vec::new();
+    data.take(entry.size).read_to_end(&mut contents)?;
+    let (_, sha256) = rsz::hash_contents(&mut &contents[..])?;
+    if contents.len() as u64 == entry.size && writer.add_duplicate(entry, sha256) {
+        return Ok(());
+    }
+    writer.add_file(entry, &mut &contents[..])
+}
@@ -0,0 +1,814 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use bech32::{Bech32, Hrp};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::aead::{Aead, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use x25519_dalek::{EphemeralSecret, PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+/// Length of content keys and key-encryption keys in bytes.
+pub const KEY_LEN: usize = 32;
+/// Plaintext bytes per authenticated chunk of encrypted archive data.
+const CHUNK_SIZE: usize = 64 * 1024;
+/// Poly1305 tag appended to every sealed message.
+const TAG_LEN: usize = 16;
+/// Random part of the per-block nonce; the rest is the chunk counter.
+pub const NONCE_PREFIX_LEN: usize = 16;
+const NONCE_LEN: usize = 24;
+/// Bytes [`seal`] adds to a message: the nonce in front and the tag behind.
+pub const SEAL_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+const SALT_LEN: usize = 16;
+/// Marks the final chunk of a block so truncation is detected.
+const LAST_CHUNK: u64 = 1 << 63;
+/// Upper bound on Argon2 memory accepted from an archive header (4 GiB).
+const MAX_KDF_MEMORY_KIB: u32 = 4 * 1024 * 1024;
+/// Upper bound on Argon2 passes accepted from an archive header.
+const MAX_KDF_TIME: u32 = 16;
+/// Upper bound on Argon2 lanes accepted from an archive header.
+const MAX_KDF_LANES: u32 = 16;
+
+const SLOT_PASSPHRASE: u8 = 1;
+const SLOT_X25519: u8 = 2;
+
+/// Bech32 prefix of recipient public keys.
+const RECIPIENT_HRP: &str = "rszpub";
+/// Bech32 prefix of identity (private key) strings, written in upper case.
+const IDENTITY_HRP: &str = "rsz-secret-key-";
+/// HKDF `info` for the key that wraps the content key in an X25519 slot.
+const X25519_INFO: &[u8] = b"rs-zip/v2/x25519";
+
+/// A 256-bit key that is wiped from memory when dropped.
+pub type ContentKey = Zeroizing<[u8; KEY_LEN]>;
+
+/// An archive password. The bytes are wiped from memory when dropped and never printed.
+#[derive(Clone)]
+pub struct Passphrase(Zeroizing<Vec<u8>>);
+
+impl Passphrase {
+    /// Wraps the raw bytes of a password.
+    pub fn new(bytes: impl Into<Vec<u8>>) -> Self {
+        Passphrase(Zeroizing::new(bytes.into()))
+    }
+
+    /// Returns the password bytes.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<&str> for Passphrase {
+    fn from(s: &str) -> Self {
+        Passphrase::new(s.as_bytes())
+    }
+}
+
+impl fmt::Debug for Passphrase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Passphrase(..)")
+    }
+}
+
+/// Argon2id cost parameters, stored in the archive next to the salt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// Memory cost in KiB.
+    pub m_cost_kib: u32,
+    /// Number of passes.
+    pub t_cost: u32,
+    /// Degree of parallelism.
+    pub p_cost: u32,
+}
+
+impl Default for KdfParams {
+    /// 64 MiB, 3 passes, 1 lane (RFC 9106's memory-constrained recommendation).
+    fn default() -> Self {
+        KdfParams {
+            m_cost_kib: 64 * 1024,
+            t_cost: 3,
+            p_cost: 1,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Rejects costs above what rs-zip accepts from an archive: the header is untrusted, and
+    /// unbounded costs would let it stall every attempt to open it.
+    fn check(&self) -> io::Result<()> {
+        if self.m_cost_kib > MAX_KDF_MEMORY_KIB {
+            return Err(invalid_data("key derivation asks for more than 4 GiB of memory"));
+        }
+        if self.t_cost > MAX_KDF_TIME {
+            return Err(invalid_data(&format!("key derivation asks for more than {} passes", MAX_KDF_TIME)));
+        }
+        if self.p_cost > MAX_KDF_LANES {
+            return Err(invalid_data(&format!("key derivation asks for more than {} lanes", MAX_KDF_LANES)));
+        }
+        Ok(())
+    }
+}
+
+/// Generates a fresh random content key.
+pub fn generate_key() -> ContentKey {
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    OsRng.fill_bytes(key.as_mut());
+    key
+}
+
+/// Fills an array with random bytes from the operating system.
+pub fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0u8; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+/// Derives a key-encryption key from a passphrase with Argon2id.
+///
+/// # Arguments
+/// * `passphrase` - The password to stretch.
+/// * `salt` - Random per-archive salt.
+/// * `params` - Argon2id cost parameters.
+///
+/// # Returns
+/// * `io::Result<ContentKey>` - The derived key, or `InvalidData` for unusable parameters.
+pub fn derive_key(passphrase: &Passphrase, salt: &[u8], params: KdfParams) -> io::Result<ContentKey> {
+    params.check()?;
+    let argon_params = Params::new(params.m_cost_kib, params.t_cost, params.p_cost, Some(KEY_LEN))
+        .map_err(|e| invalid_data(&format!("invalid key derivation parameters: {}", e)))?;
+    let argon = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params);
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    argon
+        .hash_password_into(passphrase.as_bytes(), salt, key.as_mut())
+        .map_err(|e| invalid_data(&format!("key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+/// Encrypts `plaintext` as a single message under a fresh random nonce.
+/// The nonce is prepended to the returned ciphertext.
+pub fn seal(key: &[u8; KEY_LEN], aad: &[u8], plaintext: &[u8]) -> io::Result<Vec<u8>> {
+    let nonce = random_bytes::<NONCE_LEN>();
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .map_err(|_| io::Error::other("encryption failed"))?;
+    let mut sealed = nonce.to_vec();
+    sealed.extend_from_slice(&ciphertext);
+    Ok(sealed)
+}
+
+/// Decrypts a message produced by [`seal`].
+///
+/// # Returns
+/// * `io::Result<Vec<u8>>` - The plaintext, or `InvalidData` if authentication fails.
+pub fn open(key: &[u8; KEY_LEN], aad: &[u8], sealed: &[u8]) -> io::Result<Vec<u8>> {
+    if sealed.len() < NONCE_LEN + TAG_LEN {
+        return Err(invalid_data("encrypted section is truncated"));
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    let cipher = XChaCha20Poly1305::new(key.into());
+    cipher
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| invalid_data("encrypted section failed authentication (corrupted or tampered)"))
+}
+
+/// An X25519 public key that archives can be encrypted to, written as `rszpub1...`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Recipient(PublicKey);
+
+impl Recipient {
+    /// Returns the raw 32-byte public key.
+    pub fn as_bytes(&self) -> &[u8; KEY_LEN] {
+        self.0.as_bytes()
+    }
+}
+
+impl fmt::Display for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hrp = Hrp::parse_unchecked(RECIPIENT_HRP);
+        let encoded = bech32::encode::<Bech32>(hrp, self.as_bytes()).map_err(|_| fmt::Error)?;
+        f.write_str(&encoded)
+    }
+}
+
+impl fmt::Debug for Recipient {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Recipient({})", self)
+    }
+}
+
+impl FromStr for Recipient {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let bytes = decode_key(s.trim(), RECIPIENT_HRP)
+            .ok_or_else(|| invalid_input(&format!("invalid recipient public key: {}", s.trim())))?;
+        Ok(Recipient(PublicKey::from(*bytes)))
+    }
+}
+
+/// An X25519 private key that can open archives encrypted to its [`Recipient`].
+/// Written as `RSZ-SECRET-KEY-1...`; the key is wiped from memory when dropped.
+#[derive(Clone)]
+pub struct Identity(StaticSecret);
+
+impl Identity {
+    /// Generates a new random identity.
+    pub fn generate() -> Self {
+        Identity(StaticSecret::random_from_rng(OsRng))
+    }
+
+    /// Returns the public key archives should be encrypted to.
+    pub fn to_public(&self) -> Recipient {
+        Recipient(PublicKey::from(&self.0))
+    }
+
+    /// Returns the identity in its text form, `RSZ-SECRET-KEY-1...`.
+    pub fn to_secret_string(&self) -> Zeroizing<String> {
+        let hrp = Hrp::parse_unchecked(IDENTITY_HRP);
+        let bytes = Zeroizing::new(self.0.to_bytes());
+        Zeroizing::new(
+            bech32::encode_upper::<Bech32>(hrp, bytes.as_ref()).expect("32-byte keys always encode"),
+        )
+    }
+}
+
+impl fmt::Debug for Identity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Identity({})", self.to_public())
+    }
+}
+
+impl FromStr for Identity {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let bytes = decode_key(s.trim(), IDENTITY_HRP)
+            .ok_or_else(|| invalid_input("invalid identity (expected RSZ-SECRET-KEY-1...)"))?;
+        Ok(Identity(StaticSecret::from(*bytes)))
+    }
+}
+
+/// Parses an identity file: one `RSZ-SECRET-KEY-1...` per line, with blank lines and
+/// `#` comments ignored.
+///
+/// # Returns
+/// * `io::Result<Vec<Identity>>` - The identities, or `InvalidInput` if the file has none
+///   or a line is not a valid identity.
+pub fn parse_identities(text: &str) -> io::Result<Vec<Identity>> {
+    let identities = key_lines(text)
+        .map(Identity::from_str)
+        .collect::<io::Result<Vec<_>>>()?;
+    if identities.is_empty() {
+        return Err(invalid_input("no identities found"));
+    }
+    Ok(identities)
+}
+
+/// Parses a recipients file: one `rszpub1...` per line, with blank lines and `#`
+/// comments ignored.
+pub fn parse_recipients(text: &str) -> io::Result<Vec<Recipient>> {
+    key_lines(text).map(Recipient::from_str).collect()
+}
+
+pub(crate) fn key_lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+}
+
+/// Decodes a Bech32 key string with the given prefix (case-insensitive) into 32 bytes.
+pub(crate) fn decode_key(s: &str, expected_hrp: &str) -> Option<Zeroizing<[u8; KEY_LEN]>> {
+    let (hrp, data) = bech32::decode(s).ok()?;
+    let data = Zeroizing::new(data);
+    if hrp.to_lowercase() != expected_hrp {
+        return None;
+    }
+    to_key(&data)
+}
+
+/// Derives the key that wraps the content key for one X25519 recipient.
+fn x25519_wrap_key(shared: &[u8; KEY_LEN], ephemeral: &[u8; KEY_LEN], recipient: &Recipient) -> ContentKey {
+    let mut salt = [0u8; 2 * KEY_LEN];
+    salt[..KEY_LEN].copy_from_slice(ephemeral);
+    salt[KEY_LEN..].copy_from_slice(recipient.as_bytes());
+    let mut key = Zeroizing::new([0u8; KEY_LEN]);
+    Hkdf::<Sha256>::new(Some(&salt), shared)
+        .expand(X25519_INFO, key.as_mut())
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key
+}
+
+/// One way of recovering an archive's content key, stored in the archive header.
+#[derive(Debug, Clone)]
+pub enum KeySlot {
+    /// The content key wrapped with an Argon2id-derived key.
+    Passphrase {
+        params: KdfParams,
+        salt: [u8; SALT_LEN],
+        wrapped: Vec<u8>,
+    },
+    /// The content key wrapped for one X25519 recipient, using a fresh ephemeral key.
+    X25519 {
+        ephemeral: [u8; KEY_LEN],
+        wrapped: Vec<u8>,
+    },
+}
+
+impl KeySlot {
+    /// Wraps `content_key` so it can be recovered with `passphrase`.
+    ///
+    /// # Arguments
+    /// * `content_key` - The archive's content key.
+    /// * `passphrase` - Password that will unlock the slot.
+    /// * `aad` - Header bytes the slot is bound to.
+    pub fn for_passphrase(
+        content_key: &[u8; KEY_LEN],
+        passphrase: &Passphrase,
+        aad: &[u8],
+    ) -> io::Result<KeySlot> {
+        let params = KdfParams::default();
+        let salt = random_bytes::<SALT_LEN>();
+        let kek = derive_key(passphrase, &salt, params)?;
+        Ok(KeySlot::Passphrase {
+            params,
+            salt,
+            wrapped: seal(&kek, aad, content_key)?,
+        })
+    }
+
+    /// Wraps `content_key` so it can be recovered with the identity behind `recipient`.
+    ///
+    /// # Arguments
+    /// * `content_key` - The archive's content key.
+    /// * `recipient` - Public key that will unlock the slot.
+    /// * `aad` - Header bytes the slot is bound to.
+    pub fn for_recipient(
+        content_key: &[u8; KEY_LEN],
+        recipient: &Recipient,
+        aad: &[u8],
+    ) -> io::Result<KeySlot> {
+        let secret = EphemeralSecret::random_from_rng(OsRng);
+        let ephemeral = PublicKey::from(&secret).to_bytes();
+        let shared = secret.diffie_hellman(&recipient.0);
+        if !shared.was_contributory() {
+            return Err(invalid_input(&format!("unusable recipient public key: {}", recipient)));
+        }
+        let kek = x25519_wrap_key(shared.as_bytes(), &ephemeral, recipient);
+        Ok(KeySlot::X25519 {
+            ephemeral,
+            wrapped: seal(&kek, aad, content_key)?,
+        })
+    }
+
+    /// Tries to recover the content key from this slot with a passphrase.
+    ///
+    /// # Returns
+    /// * `io::Result<Option<ContentKey>>` - The key, or `None` if the passphrase does not
+    ///   match or this is not a passphrase slot.
+    pub fn unlock_with_passphrase(
+        &self,
+        passphrase: &Passphrase,
+        aad: &[u8],
+    ) -> io::Result<Option<ContentKey>> {
+        match self {
+            KeySlot::Passphrase { params, salt, wrapped } => {
+                let kek = derive_key(passphrase, salt, *params)?;
+                Ok(open_key(&kek, aad, wrapped))
+            }
+            KeySlot::X25519 { .. } => Ok(None),
+        }
+    }
+
+    /// Tries to recover the content key from this slot with an identity.
+    ///
+    /// # Returns
+    /// * `Option<ContentKey>` - The key, or `None` if the slot was not made for this identity.
+    pub fn unlock_with_identity(&self, identity: &Identity, aad: &[u8]) -> Option<ContentKey> {
+        match self {
+            KeySlot::X25519 { ephemeral, wrapped } => {
+                let shared = identity.0.diffie_hellman(&PublicKey::from(*ephemeral));
+                if !shared.was_contributory() {
+                    return None;
+                }
+                let kek = x25519_wrap_key(shared.as_bytes(), ephemeral, &identity.to_public());
+                open_key(&kek, aad, wrapped)
+            }
+            KeySlot::Passphrase { .. } => None,
+        }
+    }
+
+    /// Serialises the slot as `kind u8, length u32, payload`.
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let mut payload = Vec::new();
+        let kind = match self {
+            KeySlot::Passphrase { params, salt, wrapped } => {
+                payload.write_u32::<LittleEndian>(params.m_cost_kib)?;
+                payload.write_u32::<LittleEndian>(params.t_cost)?;
+                payload.write_u32::<LittleEndian>(params.p_cost)?;
+                payload.extend_from_slice(salt);
+                payload.extend_from_slice(wrapped);
+                SLOT_PASSPHRASE
+            }
+            KeySlot::X25519 { ephemeral, wrapped } => {
+                payload.extend_from_slice(ephemeral);
+                payload.extend_from_slice(wrapped);
+                SLOT_X25519
+            }
+        };
+        writer.write_u8(kind)?;
+        writer.write_u32::<LittleEndian>(payload.len() as u32)?;
+        writer.write_all(&payload)
+    }
+
+    /// Reads a slot written by [`KeySlot::write_to`].
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<KeySlot> {
+        let kind = reader.read_u8()?;
+        let len = reader.read_u32::<LittleEndian>()? as usize;
+        if len > 64 * 1024 {
+            return Err(invalid_data("key slot is implausibly large"));
+        }
+        let mut payload = vec![0u8; len];
+        reader.read_exact(&mut payload)?;
+        let mut payload = &payload[..];
+        match kind {
+            SLOT_PASSPHRASE => {
+                let params = KdfParams {
+                    m_cost_kib: payload.read_u32::<LittleEndian>()?,
+                    t_cost: payload.read_u32::<LittleEndian>()?,
+                    p_cost: payload.read_u32::<LittleEndian>()?,
+                };
+                params.check()?;
+                let mut salt = [0u8; SALT_LEN];
+                payload.read_exact(&mut salt)?;
+                Ok(KeySlot::Passphrase {
+                    params,
+                    salt,
+                    wrapped: payload.to_vec(),
+                })
+            }
+            SLOT_X25519 => {
+                let mut ephemeral = [0u8; KEY_LEN];
+                payload.read_exact(&mut ephemeral)?;
+                Ok(KeySlot::X25519 {
+                    ephemeral,
+                    wrapped: payload.to_vec(),
+                })
+            }
+            other => Err(invalid_data(&format!("unknown key slot type {}", other))),
+        }
+    }
+}
+
+/// Encrypts everything written to it as a sequence of authenticated chunks
+/// (the STREAM construction): each chunk's nonce is the block's random prefix plus a
+/// counter, and the final chunk is flagged so a truncated block fails to decrypt.
+pub struct EncryptingWriter<W: Write> {
+    inner: W,
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u64,
+    buffer: Vec<u8>,
+}
+
+impl<W: Write> EncryptingWriter<W> {
+    /// Starts an encrypted block with the given key and random nonce prefix.
+    pub fn new(inner: W, key: &[u8; KEY_LEN], nonce_prefix: [u8; NONCE_PREFIX_LEN]) -> Self {
+        EncryptingWriter {
+            inner,
+            cipher: XChaCha20Poly1305::new(key.into()),
+            nonce_prefix,
+            counter: 0,
+            buffer: Vec::with_capacity(CHUNK_SIZE),
+        }
+    }
+
+    /// Returns a reference to the underlying writer.
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    fn seal_chunk(&mut self, last: bool) -> io::Result<()> {
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+        let ciphertext = self
+            .cipher
+            .encrypt(XNonce::from_slice(&nonce), self.buffer.as_slice())
+            .map_err(|_| io::Error::other("encryption failed"))?;
+        self.inner.write_all(&ciphertext)?;
+        self.buffer.clear();
+        self.counter += 1;
+        Ok(())
+    }
+
+    /// Seals the final (possibly empty) chunk and returns the underlying writer.
+    pub fn finish(mut self) -> io::Result<W> {
+        self.seal_chunk(true)?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for EncryptingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // A full chunk is only sealed once more data arrives, so the last one can be flagged.
+        if self.buffer.len() == CHUNK_SIZE && !buf.is_empty() {
+            self.seal_chunk(false)?;
+        }
+        let n = buf.len().min(CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Reads back a block written by [`EncryptingWriter`], authenticating every chunk.
+pub struct DecryptingReader<R: Read> {
+    inner: R,
+    cipher: XChaCha20Poly1305,
+    nonce_prefix: [u8; NONCE_PREFIX_LEN],
+    counter: u64,
+    remaining: u64,
+    plain: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> DecryptingReader<R> {
+    /// Starts reading an encrypted block of `ciphertext_len` bytes.
+    pub fn new(
+        inner: R,
+        key: &[u8; KEY_LEN],
+        nonce_prefix: [u8; NONCE_PREFIX_LEN],
+        ciphertext_len: u64,
+    ) -> Self {
+        DecryptingReader {
+            inner,
+            cipher: XChaCha20Poly1305::new(key.into()),
+            nonce_prefix,
+            counter: 0,
+            remaining: ciphertext_len,
+            plain: Vec::new(),
+            pos: 0,
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<()> {
+        let chunk_len = self.remaining.min((CHUNK_SIZE + TAG_LEN) as u64) as usize;
+        if chunk_len < TAG_LEN {
+            return Err(invalid_data("encrypted block is truncated"));
+        }
+        let mut ciphertext = vec![0u8; chunk_len];
+        self.inner.read_exact(&mut ciphertext)?;
+        self.remaining -= chunk_len as u64;
+        let last = self.remaining == 0;
+        let nonce = chunk_nonce(&self.nonce_prefix, self.counter, last);
+        self.plain = self
+            .cipher
+            .decrypt(XNonce::from_slice(&nonce), ciphertext.as_slice())
+            .map_err(|_| invalid_data("archive data failed authentication (corrupted or tampered)"))?;
+        self.pos = 0;
+        self.counter += 1;
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for DecryptingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.plain.len() {
+            if self.remaining == 0 {
+                return Ok(0);
+            }
+            self.next_chunk()?;
+        }
+        let n = buf.len().min(self.plain.len() - self.pos);
+        buf[..n].copy_from_slice(&self.plain[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+fn chunk_nonce(prefix: &[u8; NONCE_PREFIX_LEN], counter: u64, last: bool) -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce[..NONCE_PREFIX_LEN].copy_from_slice(prefix);
+    let tagged = if last { counter | LAST_CHUNK } else { counter };
+    nonce[NONCE_PREFIX_LEN..].copy_from_slice(&tagged.to_be_bytes());
+    nonce
+}
+
+/// Unwraps a sealed content key, keeping the plaintext out of unzeroized memory.
+fn open_key(kek: &[u8; KEY_LEN], aad: &[u8], wrapped: &[u8]) -> Option<ContentKey> {
+    let key = Zeroizing::new(open(kek, aad, wrapped).ok()?);
+    to_key(&key)
+}
+
+fn to_key(bytes: &[u8]) -> Option<ContentKey> {
+    let array: [u8; KEY_LEN] = bytes.try_into().ok()?;
+    Some(Zeroizing::new(array))
+}
+
+fn invalid_input(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Argon2 at the default 64 MiB is slow in debug builds; the slot format is the same.
+    const TEST_PARAMS: KdfParams = KdfParams {
+        m_cost_kib: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    fn passphrase_slot(content_key: &[u8; KEY_LEN], passphrase: &str, aad: &[u8]) -> KeySlot {
+        let salt = random_bytes::<SALT_LEN>();
+        let kek = derive_key(&Passphrase::from(passphrase), &salt, TEST_PARAMS).unwrap();
+        KeySlot::Passphrase {
+            params: TEST_PARAMS,
+            salt,
+            wrapped: seal(&kek, aad, content_key).unwrap(),
+        }
+    }
+
+    fn encrypt(key: &[u8; KEY_LEN], prefix: [u8; NONCE_PREFIX_LEN], data: &[u8]) -> Vec<u8> {
+        let mut writer = EncryptingWriter::new(Vec::new(), key, prefix);
+        writer.write_all(data).unwrap();
+        writer.finish().unwrap()
+    }
+
+    fn decrypt(key: &[u8; KEY_LEN], prefix: [u8; NONCE_PREFIX_LEN], ciphertext: &[u8]) -> io::Result<Vec<u8>> {
+        let mut reader = DecryptingReader::new(ciphertext, key, prefix, ciphertext.len() as u64);
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        Ok(data)
+    }
+
+    #[test]
+    fn passphrase_slot_unlocks_with_the_right_password_only() {
+        let key = generate_key();
+        let slot = passphrase_slot(&key, "correct horse", b"header");
+
+        let unlocked = slot.unlock_with_passphrase(&"correct horse".into(), b"header").unwrap();
+        assert_eq!(unlocked.as_deref(), Some(&*key));
+        assert!(slot.unlock_with_passphrase(&"battery staple".into(), b"header").unwrap().is_none());
+        assert!(slot.unlock_with_identity(&Identity::generate(), b"header").is_none());
+    }
+
+    #[test]
+    fn slot_is_bound_to_the_header() {
+        // The header bytes (magic, version, flags) are the slot's associated data, so clearing
+        // a flag such as "index encrypted" makes every slot fail to open.
+        let key = generate_key();
+        let slot = passphrase_slot(&key, "pw", b"RSZ\x02\x00\x03");
+        assert!(slot.unlock_with_passphrase(&"pw".into(), b"RSZ\x02\x00\x01").unwrap().is_none());
+    }
+
+    #[test]
+    fn slot_survives_serialisation() {
+        let key = generate_key();
+        let mut bytes = Vec::new();
+        passphrase_slot(&key, "pw", b"aad").write_to(&mut bytes).unwrap();
+
+        let slot = KeySlot::read_from(&mut bytes.as_slice()).unwrap();
+        let unlocked = slot.unlock_with_passphrase(&"pw".into(), b"aad").unwrap();
+        assert_eq!(unlocked.as_deref(), Some(&*key));
+    }
+
+    #[test]
+    fn slot_with_excessive_kdf_cost_is_rejected() {
+        let mut bytes = Vec::new();
+        KeySlot::Passphrase {
+            params: KdfParams { t_cost: u32::MAX, ..TEST_PARAMS },
+            salt: [0; SALT_LEN],
+            wrapped: vec![0; SEAL_OVERHEAD + KEY_LEN],
+        }
+        .write_to(&mut bytes)
+        .unwrap();
+
+        let err = KeySlot::read_from(&mut bytes.as_slice()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let salt = [0; SALT_LEN];
+        let err = derive_key(&"pw".into(), &salt, KdfParams { p_cost: 17, ..TEST_PARAMS }).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn sealed_message_is_authenticated() {
+        let key = generate_key();
+        let mut sealed = seal(&key, b"aad", b"message").unwrap();
+        assert_eq!(open(&key, b"aad", &sealed).unwrap(), b"message");
+        assert!(open(&key, b"other", &sealed).is_err());
+        assert!(open(&generate_key(), b"aad", &sealed).is_err());
+
+        let last = sealed.len() - 1;
+        sealed[last] ^= 1;
+        assert!(open(&key, b"aad", &sealed).is_err());
+    }
+
+    #[test]
+    fn chunked_round_trip() {
+        let key = generate_key();
+        let prefix = random_bytes::<NONCE_PREFIX_LEN>();
+        for len in [0, 1, CHUNK_SIZE, 2 * CHUNK_SIZE + 123] {
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let ciphertext = encrypt(&key, prefix, &data);
+            assert_eq!(ciphertext.len(), len + len.div_ceil(CHUNK_SIZE).max(1) * TAG_LEN);
+            assert_eq!(decrypt(&key, prefix, &ciphertext).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn truncated_final_chunk_is_rejected() {
+        let key = generate_key();
+        let prefix = random_bytes::<NONCE_PREFIX_LEN>();
+        let ciphertext = encrypt(&key, prefix, &vec![7u8; 2 * CHUNK_SIZE + 123]);
+
+        // Cut inside the final chunk.
+        assert!(decrypt(&key, prefix, &ciphertext[..ciphertext.len() - 1]).is_err());
+        // Cut at a chunk boundary: every remaining chunk is intact, but the one now at the end
+        // was not sealed as the last.
+        assert!(decrypt(&key, prefix, &ciphertext[..2 * (CHUNK_SIZE + TAG_LEN)]).is_err());
+    }
+
+    #[test]
+    fn reordered_or_tampered_chunks_are_rejected() {
+        let key = generate_key();
+        let prefix = random_bytes::<NONCE_PREFIX_LEN>();
+        let ciphertext = encrypt(&key, prefix, &vec![7u8; 2 * CHUNK_SIZE + 123]);
+        let chunk = CHUNK_SIZE + TAG_LEN;
+
+        let mut swapped = ciphertext.clone();
+        swapped[..chunk].copy_from_slice(&ciphertext[chunk..2 * chunk]);
+        swapped[chunk..2 * chunk].copy_from_slice(&ciphertext[..chunk]);
+        assert!(decrypt(&key, prefix, &swapped).is_err());
+
+        let mut flipped = ciphertext.clone();
+        flipped[100] ^= 1;
+        assert!(decrypt(&key, prefix, &flipped).is_err());
+
+        assert!(decrypt(&key, random_bytes::<NONCE_PREFIX_LEN>(), &ciphertext).is_err());
+        assert!(decrypt(&generate_key(), prefix, &ciphertext).is_err());
+    }
+
+    #[test]
+    fn recipient_slot_unlocks_with_its_identity_only() {
+        let key = generate_key();
+        let identity = Identity::generate();
+        let slot = KeySlot::for_recipient(&key, &identity.to_public(), b"header").unwrap();
+
+        assert_eq!(slot.unlock_with_identity(&identity, b"header").as_deref(), Some(&*key));
+        assert!(slot.unlock_with_identity(&Identity::generate(), b"header").is_none());
+        assert!(slot.unlock_with_identity(&identity, b"other header").is_none());
+        assert!(slot.unlock_with_passphrase(&"pw".into(), b"header").unwrap().is_none());
+
+        let mut bytes = Vec::new();
+        slot.write_to(&mut bytes).unwrap();
+        let slot = KeySlot::read_from(&mut bytes.as_slice()).unwrap();
+        assert_eq!(slot.unlock_with_identity(&identity, b"header").as_deref(), Some(&*key));
+    }
+
+    #[test]
+    fn low_order_keys_are_refused() {
+        // The all-zero point makes the shared secret zero whatever the other key is.
+        let zero = Recipient(PublicKey::from([0u8; KEY_LEN]));
+        let err = KeySlot::for_recipient(&generate_key(), &zero, b"header").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+
+        let slot = KeySlot::X25519 {
+            ephemeral: [0; KEY_LEN],
+            wrapped: vec![0; SEAL_OVERHEAD + KEY_LEN],
+        };
+        assert!(slot.unlock_with_identity(&Identity::generate(), b"header").is_none());
+    }
+
+    #[test]
+    fn keys_survive_their_text_form() {
+        let identity = Identity::generate();
+        let recipient = identity.to_public();
+        assert!(recipient.to_string().starts_with("rszpub1"));
+        assert!(identity.to_secret_string().starts_with("RSZ-SECRET-KEY-1"));
+
+        let parsed: Identity = identity.to_secret_string().parse().unwrap();
+        assert_eq!(parsed.to_public(), recipient);
+        let text = format!("# team keys\n\n{}\n  {}  \n", recipient, Identity::generate().to_public());
+        let recipients = parse_recipients(&text).unwrap();
+        assert_eq!(recipients.len(), 2);
+        assert_eq!(recipients[0], recipient);
+
+        // A public key is not an identity, and vice versa.
+        assert!(recipient.to_string().parse::<Identity>().is_err());
+        assert!(identity.to_secret_string().parse::<Recipient>().is_err());
+        assert!(parse_identities("# nothing here\n").is_err());
+    }
+}
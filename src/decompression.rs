@@ -1,14 +1,63 @@
 use std::fs::File;
-use std::io::{self, Read, Write};
-use std::path::Path;
-use xz2::read::XzDecoder;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
 use byteorder::{ReadBytesExt, LittleEndian};
 use std::io::BufReader;
 use std::fs;
+use std::sync::Arc;
+use crate::codec::{self, Codec};
+use crate::entry::{ArchiveEntry, EntryKind};
+use crate::file_io::{self, OutputFile, OverwritePolicy};
+use crate::lzma::LimitedDecoder;
+use crate::crypto::{Identity, Passphrase};
+use crate::progress::{self, CountingReader, Progress, Tracker};
+use crate::rsz::{self, HashingReader};
+use crate::seekable::{ByteRange, XzSeekableReader};
+use crate::signature::VerifyingKey;
+use std::cell::Cell;
+
+/// Options shared by single-file decompression and RSZ extraction.
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+    /// What to do when an output file already exists.
+    pub overwrite: OverwritePolicy,
+    /// Delete the compressed input once it has been fully decompressed and synced
+    /// (single-file mode only; RSZ archives are never removed by extraction).
+    pub remove_source: bool,
+    /// Receives progress updates while data is decompressed.
+    pub progress: Option<Arc<dyn Progress>>,
+    /// Password for encrypted RSZ archives.
+    pub password: Option<Passphrase>,
+    /// Private keys for RSZ archives encrypted to public-key recipients.
+    pub identities: Vec<Identity>,
+    /// Trusted signing keys: when non-empty, RSZ archives must carry a valid signature by
+    /// one of them. Signatures on archives are checked either way.
+    pub signers: Vec<VerifyingKey>,
+    /// Decode single files with this codec instead of detecting it from their first bytes;
+    /// needed for formats without a header, such as [`crate::lzma::RawLzma`].
+    pub codec: Option<Arc<dyn Codec>>,
+    /// Most memory the XZ and LZMA decoders may use, in bytes. Data that needs more (an
+    /// untrusted archive asking for a huge dictionary, say) fails with an error naming the
+    /// amount. `None` means no limit.
+    pub memlimit: Option<u64>,
+}
+
+impl ExtractOptions {
+    fn unlock(&self) -> rsz::Unlock<'_> {
+        rsz::Unlock {
+            password: self.password.as_ref(),
+            identities: &self.identities,
+            content_key: None,
+            signers: &self.signers,
+            memlimit: self.memlimit,
+        }
+    }
+}
 
 /// Decompresses data from the input reader and writes the original (unpadded) content
 /// to the output writer. The function expects an 8-byte header at the beginning of the stream
-/// that represents the original unpadded size in Little Endian format.
+/// that represents the original unpadded size in Little Endian format. Streams concatenated
+/// after the first one are decoded as well.
 /// 
 /// # Arguments
 /// * `input` - A reader implementing the Read trait for compressed data.
@@ -17,62 +66,675 @@ use std::fs;
 /// # Returns
 /// * `Result<(), std::io::Error>` - Ok on success, or an Io error on failure.
 pub fn decompress_lzma<R: Read, W: Write>(
-    mut input: R,
+    input: R,
     output: &mut W,
 ) -> Result<(), std::io::Error> {
-    // Read the first 8 bytes of the input as the original unpadded size.
-    let original_size = input.read_u64::<LittleEndian>()?;
-    
-    // Create an XZ decoder for the remaining input stream.
-    let mut decoder = XzDecoder::new(input);
-    
-    // Decompress the data into a buffer.
-    let mut decompressed_data = Vec::new();
-    std::io::copy(&mut decoder, &mut decompressed_data)?;
-    
-    // Truncate the decompressed data to the original size.
-    output.write_all(&decompressed_data[..(original_size as usize)])?;
+    decompress_lzma_tracked(input, output, u64::MAX, &Tracker::new(None, 1, None))
+}
+
+/// `decompress_lzma` with the decoder limited to `memlimit` bytes of memory and progress
+/// reported through `tracker` as the stream is decoded.
+///
+/// The file may go on after the first stream, as `cat a.xz b.xz` or `--append` leave it:
+/// every further rs-zip stream (size header and padding) or standard XZ stream is decoded
+/// in turn, and stream padding between them is skipped, as `xz` does.
+fn decompress_lzma_tracked<R: Read, W: Write>(
+    input: R,
+    output: &mut W,
+    memlimit: u64,
+    tracker: &Tracker,
+) -> Result<(), std::io::Error> {
+    let input = CountingReader::new(input);
+    let bytes_in = input.counter();
+    let mut input = BufReader::new(input);
+    let mut decompressed = 0u64;
+    let mut streams = 0;
+
+    // The next bytes to look at: either an 8-byte size followed by the XZ magic, the XZ
+    // magic of a standard stream, or four bytes of stream padding.
+    let mut head = [0u8; 8 + codec::XZ_MAGIC.len()];
+    let mut head_len = 0;
+    loop {
+        head_len += rsz::read_up_to(&mut input, &mut head[head_len..])?;
+        let peeked = &head[..head_len];
+        if peeked.is_empty() && streams > 0 {
+            break;
+        }
+        // After a stream, eight null bytes are stream padding rather than a size header.
+        if peeked.get(8..) == Some(&codec::XZ_MAGIC[..]) && (streams == 0 || peeked[..8] != [0; 8]) {
+            // The first 8 bytes are the original size; the stream holds the padded data.
+            let original_size = u64::from_le_bytes(peeked[..8].try_into().unwrap());
+            let stream = io::Cursor::new(&peeked[8..]).chain(&mut input);
+            let mut decoder = LimitedDecoder::xz_single(stream, memlimit)?;
+            let mut data = Vec::new();
+            progress::copy_with_progress(&mut decoder, &mut data, |_, _, copied| {
+                tracker.report(bytes_in.get(), decompressed + copied.min(original_size))
+            })?;
+            if (data.len() as u64) < original_size {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "XZ stream holds less data than its header says",
+                ));
+            }
+            output.write_all(&data[..original_size as usize])?;
+            decompressed += original_size;
+        } else if peeked.starts_with(&codec::XZ_MAGIC) {
+            let stream = io::Cursor::new(peeked).chain(&mut input);
+            let mut decoder = LimitedDecoder::xz_single(stream, memlimit)?;
+            decompressed += progress::copy_with_progress(&mut decoder, output, |_, _, copied| {
+                tracker.report(bytes_in.get(), decompressed + copied)
+            })?;
+        } else if streams > 0 && peeked.starts_with(&[0; 4]) {
+            head.copy_within(4..head_len, 0);
+            head_len -= 4;
+            continue;
+        } else if streams == 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "not an rs-zip XZ file"));
+        } else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data after the end of the XZ stream is neither stream padding nor another XZ stream",
+            ));
+        }
+        head_len = 0;
+        streams += 1;
+    }
+    output.flush()?;
+    tracker.report(bytes_in.get(), decompressed);
+    Ok(())
+}
+
+/// Decompresses a single-file stream, detecting its format: a standard XZ, zstd, gzip,
+/// bzip2 or LZ4 stream, or rs-zip's own XZ framing (size header and padding, see
+/// `decompress_lzma`).
+///
+/// # Arguments
+/// * `input` - A reader implementing the Read trait for compressed data.
+/// * `output` - A mutable reference to a writer implementing the Write trait for decompressed data.
+///
+/// # Returns
+/// * `Result<(), std::io::Error>` - Ok on success, an `InvalidData` error for unknown formats.
+pub fn decompress_stream<R: Read, W: Write>(input: R, output: &mut W) -> io::Result<()> {
+    decompress_stream_tracked(input, output, u64::MAX, &Tracker::new(None, 1, None))
+}
+
+/// `decompress_stream` with XZ and LZMA decoders limited to `memlimit` bytes of memory and
+/// progress reported through `tracker`.
+fn decompress_stream_tracked<R: Read, W: Write>(
+    mut input: R,
+    output: &mut W,
+    memlimit: u64,
+    tracker: &Tracker,
+) -> io::Result<()> {
+    // rs-zip's framing puts the XZ magic after an 8-byte size; check it first, since the
+    // size bytes could happen to look like another format's magic.
+    let mut head = [0u8; 8 + codec::MAGIC_LEN];
+    let read = rsz::read_up_to(&mut input, &mut head)?;
+    let head = &head[..read];
+    let input = io::Cursor::new(head.to_vec()).chain(input);
+    if head.get(8..8 + codec::XZ_MAGIC.len()) == Some(&codec::XZ_MAGIC[..]) {
+        return decompress_lzma_tracked(input, output, memlimit, tracker);
+    }
+    let Some(codec) = codec::detect(head) else {
+        let names: Vec<String> = codec::registered()
+            .iter()
+            .map(|codec| codec.name().to_string())
+            .collect();
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unrecognised compressed format (expected {})", names.join(", ")),
+        ));
+    };
+    decode_stream(input, output, codec.as_ref(), memlimit, tracker)
+}
+
+/// Decodes a whole `codec` stream from `input` into `output`, within `memlimit` bytes of
+/// decoder memory.
+fn decode_stream<R: Read, W: Write>(
+    input: R,
+    output: &mut W,
+    codec: &dyn Codec,
+    memlimit: u64,
+    tracker: &Tracker,
+) -> io::Result<()> {
+    let mut input = CountingReader::new(input);
+    let bytes_in = input.counter();
+    let mut decoder = codec.decoder_with_memlimit(Box::new(&mut input), memlimit)?;
+    let copied = progress::copy_with_progress(&mut decoder, output, |_, _, copied| {
+        tracker.report(bytes_in.get(), copied)
+    })?;
     output.flush()?;
-    
+    tracker.report(bytes_in.get(), copied);
     Ok(())
 }
 
+/// Decompresses a single compressed file on disk (any supported codec, detected from its
+/// contents unless `options.codec` names one), honouring the overwrite policy.
+///
+/// # Arguments
+/// * `input_path` - Path of the compressed file.
+/// * `output_path` - Path of the decompressed file to write.
+/// * `options` - Overwrite policy, source removal, decoder memory limit and, for headerless
+///   formats, the codec.
+///
+/// # Returns
+/// * `Result<(), std::io::Error>` - Ok on success (including when the policy skips the output).
+pub fn decompress_file(
+    input_path: &Path,
+    output_path: &Path,
+    options: &ExtractOptions,
+) -> io::Result<()> {
+    file_io::check_distinct_paths(input_path, output_path)?;
+    let input_file = File::open(input_path)?;
+    let metadata = input_file.metadata()?;
+    let source_modified = metadata.modified().ok();
+    let mut output_file = match file_io::create_output_file_with_policy(
+        output_path,
+        options.overwrite,
+        source_modified,
+    )? {
+        Some(file) => file,
+        None => return Ok(()),
+    };
+    let created = output_file.created;
+    let output_file = &mut output_file.writer;
+    let mut tracker = Tracker::new(options.progress.as_deref(), 1, Some(metadata.len()));
+    tracker.start_entry(0, &input_path.to_string_lossy(), 0, 0);
+    let memlimit = options.memlimit.unwrap_or(u64::MAX);
+    let result = match &options.codec {
+        Some(codec) => decode_stream(input_file, &mut *output_file, codec.as_ref(), memlimit, &tracker),
+        None => decompress_stream_tracked(input_file, &mut *output_file, memlimit, &tracker),
+    };
+    if let Err(e) = result {
+        // Don't leave a truncated output behind that looks like a finished one.
+        file_io::remove_partial_output(output_path, created);
+        return Err(e);
+    }
 
-/// Extracts an archive compressed using RSZ format (LZMA with custom metadata).
-/// This function reads the number of files from the archive header, then iterates
-/// through each file, reading its name, original size, and content (with padding),
-/// and writes the original data to disk.
+    if options.remove_source {
+        output_file.get_ref().sync_all()?;
+        fs::remove_file(input_path)?;
+    }
+    Ok(())
+}
+
+/// Decompresses `range` of the original data of an `.xz` file on disk, decoding only the
+/// XZ blocks that hold it (see [`XzSeekableReader`]). A range running past the end of the
+/// data stops there.
+///
+/// # Arguments
+/// * `input_path` - Path of the `.xz` file.
+/// * `output_path` - Path of the file to write the bytes to.
+/// * `range` - Offset and length of the bytes, in the decompressed data.
+/// * `options` - Overwrite policy and decoder memory limit.
+///
+/// # Returns
+/// * `Result<(), std::io::Error>` - Ok on success (including when the policy skips the output);
+///   `InvalidInput` if the range starts past the end of the data.
+pub fn decompress_range(
+    input_path: &Path,
+    output_path: &Path,
+    range: ByteRange,
+    options: &ExtractOptions,
+) -> io::Result<()> {
+    file_io::check_distinct_paths(input_path, output_path)?;
+    let input_file = File::open(input_path)?;
+    let source_modified = input_file.metadata()?.modified().ok();
+    let mut reader = XzSeekableReader::with_memlimit(BufReader::new(input_file), options.memlimit.unwrap_or(u64::MAX))?;
+    if range.offset > reader.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("the range starts at {}, past the end of the {} bytes of data", range.offset, reader.len()),
+        ));
+    }
+    let len = range.len.min(reader.len() - range.offset);
+    let mut output_file = match file_io::create_output_file_with_policy(
+        output_path,
+        options.overwrite,
+        source_modified,
+    )? {
+        Some(file) => file,
+        None => return Ok(()),
+    };
+    let created = output_file.created;
+    let output_file = &mut output_file.writer;
+    let mut tracker = Tracker::new(options.progress.as_deref(), 1, Some(len));
+    tracker.start_entry(0, &input_path.to_string_lossy(), 0, 0);
+    reader.seek(SeekFrom::Start(range.offset))?;
+    let result = progress::copy_with_progress(&mut reader.take(len), output_file, |_, _, copied| {
+        tracker.report(copied, copied)
+    })
+    .and_then(|_| output_file.flush());
+    if let Err(e) = result {
+        // Don't leave a truncated output behind that looks like a finished one.
+        file_io::remove_partial_output(output_path, created);
+        return Err(e);
+    }
+    Ok(())
+}
+
+/// Extracts an RSZ archive into the current directory, writing every entry to the
+/// path it was stored under (see [`file_io::safe_extract_path`]). Both the current format and legacy (bare XZ) archives
+/// are supported; encrypted archives need `options.password` or a matching identity.
 ///
 /// # Arguments
 /// * `archive_path` - Path to the `.rsz` archive file.
+/// * `options` - Overwrite policy, password or identities and progress reporting. RSZ entries carry no
+///   timestamps, so `KeepNewer` compares existing files against the archive's own mtime.
 ///
 /// # Returns
-/// * `Result<(), std::io::Error>` - Ok if all files are extracted successfully.
-pub fn extract_archive<P: AsRef<Path>>(archive_path: P) -> io::Result<()> {
+/// * `Result<(), std::io::Error>` - Ok if all files are extracted (or skipped by the policy);
+///   a `PermissionDenied` error if no password or identity unlocks the archive.
+pub fn extract_archive<P: AsRef<Path>>(archive_path: P, options: &ExtractOptions) -> io::Result<()> {
     let file = File::open(archive_path)?;
-    let decoder = XzDecoder::new(file);
-    let mut reader = BufReader::new(decoder);
+    let metadata = file.metadata()?;
+    let archive_modified = metadata.modified().ok();
+    let unlock = options.unlock();
+    let mut tracker = Tracker::new(options.progress.as_deref(), 0, Some(metadata.len()));
+    let mut bytes_out = 0u64;
+    // The file being written; removed if it fails to extract or to verify.
+    let mut pending: Option<PathBuf> = None;
 
-    let file_count = reader.read_u64::<LittleEndian>()?;
-
-    for _ in 0..file_count {
-        let name_len = reader.read_u64::<LittleEndian>()? as usize;
-        let mut name_buf = vec![0u8; name_len];
-        reader.read_exact(&mut name_buf)?;
-        let file_name = String::from_utf8_lossy(&name_buf).into_owned();
-        
-        let original_size = reader.read_u64::<LittleEndian>()?;
-        let mut content = vec![0u8; original_size as usize];
-        reader.read_exact(&mut content)?;
+    let result = for_each_entry(file, &unlock, |entry| {
+        pending = None;
+        tracker.set_entry_count(entry.count);
+        tracker.start_entry(entry.index, &entry.info.name, entry.archive_bytes.get(), bytes_out);
 
+        let path = file_io::safe_extract_path(&entry.info.name)?;
         // Create parent directories
-        if let Some(parent) = Path::new(&file_name).parent() {
-            fs::create_dir_all(parent)?;
+        if let Some(parent) = path.parent() {
+            file_io::create_extract_dir(parent)?;
+        }
+
+        if let Some(OutputFile { writer: mut output_file, created }) =
+            file_io::create_extracted_file(&path, options.overwrite, archive_modified)?
+        {
+            pending = created.then_some(path);
+            let entry_start = bytes_out;
+            progress::copy_with_progress(entry.data, &mut output_file, |_, _, copied| {
+                tracker.report(entry.archive_bytes.get(), entry_start + copied)
+            })?;
+            output_file.flush()?;
+        }
+        bytes_out += entry.info.size;
+        Ok(())
+    });
+    if result.is_err()
+        && let Some(path) = pending
+    {
+        file_io::remove_partial_output(&path, true);
+    }
+    result?;
+
+    tracker.report(metadata.len(), bytes_out);
+    Ok(())
+}
+
+/// What [`list_archive`] and [`test_archive`] report about an RSZ archive.
+#[derive(Debug, Clone)]
+pub struct ArchiveListing {
+    /// The archived files, in index order. Legacy archives record no offsets or hashes.
+    pub entries: Vec<rsz::EntryInfo>,
+    /// The key that signed the archive, if it is signed; the signature has been verified.
+    pub signer: Option<VerifyingKey>,
+}
+
+/// Lists the files in an RSZ archive. Current-format archives are listed from their index
+/// without decompressing any contents; legacy archives have to be read through.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.rsz` archive file.
+/// * `options` - Password or identities (needed for encrypted archives) and trusted signers.
+///
+/// # Returns
+/// * `Result<ArchiveListing, std::io::Error>` - The entries and the verified signer, if any.
+pub fn list_archive<P: AsRef<Path>>(archive_path: P, options: &ExtractOptions) -> io::Result<ArchiveListing> {
+    let mut file = File::open(archive_path)?;
+    if rsz::is_v2(&mut file)? {
+        let archive = rsz::OpenArchive::open(file, &options.unlock())?;
+        return Ok(ArchiveListing {
+            entries: archive.index.entries,
+            signer: archive.signer,
+        });
+    }
+    let mut entries = Vec::new();
+    let signer = for_each_entry(file, &options.unlock(), |entry| {
+        entries.push(entry.info.clone());
+        Ok(())
+    })?;
+    Ok(ArchiveListing { entries, signer })
+}
+
+/// Reads every entry of an RSZ archive without writing anything, checking the signature,
+/// the XZ integrity checks and each entry's SHA-256.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.rsz` archive file.
+/// * `options` - Password or identities, trusted signers and progress reporting.
+///
+/// # Returns
+/// * `Result<ArchiveListing, std::io::Error>` - The verified entries and signer, or the
+///   first problem found.
+pub fn test_archive<P: AsRef<Path>>(archive_path: P, options: &ExtractOptions) -> io::Result<ArchiveListing> {
+    let file = File::open(archive_path)?;
+    let archive_len = file.metadata()?.len();
+    let mut tracker = Tracker::new(options.progress.as_deref(), 0, Some(archive_len));
+    let mut entries = Vec::new();
+    let mut bytes_out = 0u64;
+
+    let signer = for_each_entry(file, &options.unlock(), |entry| {
+        tracker.set_entry_count(entry.count);
+        tracker.start_entry(entry.index, &entry.info.name, entry.archive_bytes.get(), bytes_out);
+        let entry_start = bytes_out;
+        progress::copy_with_progress(entry.data, &mut io::sink(), |_, _, read| {
+            tracker.report(entry.archive_bytes.get(), entry_start + read)
+        })?;
+        bytes_out += entry.info.size;
+        entries.push((entry.index, entry.info.clone()));
+        Ok(())
+    })?;
+
+    tracker.report(archive_len, bytes_out);
+    entries.sort_by_key(|(index, _)| *index);
+    Ok(ArchiveListing {
+        entries: entries.into_iter().map(|(_, info)| info).collect(),
+        signer,
+    })
+}
+
+/// Reads every entry of an RSZ archive in stored order, for conversion to another format.
+/// RSZ keeps no permissions or times, so only names, sizes and contents are passed on.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.rsz` archive file.
+/// * `options` - Password, identities and trusted signers; the rest is unused.
+/// * `visit` - Called with each entry and its contents, whose hash is checked.
+pub(crate) fn read_rsz_entries(
+    archive_path: &Path,
+    options: &ExtractOptions,
+    visit: &mut dyn FnMut(&ArchiveEntry, &mut dyn Read) -> io::Result<()>,
+) -> io::Result<()> {
+    for_each_entry(File::open(archive_path)?, &options.unlock(), |entry| {
+        let info = ArchiveEntry {
+            name: entry.info.name.clone(),
+            kind: EntryKind::File,
+            size: entry.info.size,
+            mode: None,
+            modified: None,
+            owner: None,
+        };
+        visit(&info, entry.data)
+    })?;
+    Ok(())
+}
+
+/// Returns whether an RSZ archive is encrypted and therefore needs a password or identity
+/// to extract.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.rsz` archive file.
+///
+/// # Returns
+/// * `Result<bool, std::io::Error>` - True for encrypted archives; legacy archives never are.
+pub fn is_encrypted_archive<P: AsRef<Path>>(archive_path: P) -> io::Result<bool> {
+    let mut file = File::open(archive_path)?;
+    if !rsz::is_v2(&mut file)? {
+        return Ok(false);
+    }
+    Ok(rsz::Header::read_from(&mut file)?.is_encrypted())
+}
+
+/// Checks that an RSZ archive holds exactly the given files, in order, with identical
+/// names, sizes and contents. Used before deleting sources that were just archived.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.rsz` archive file.
+/// * `files` - The files the archive is expected to contain.
+/// * `options` - Supplies the password or identities for encrypted archives.
+///
+/// # Returns
+/// * `Result<(), std::io::Error>` - Ok if the archive matches, an `InvalidData` error otherwise.
+pub fn verify_archive<P: AsRef<Path>>(
+    archive_path: P,
+    files: &[PathBuf],
+    options: &ExtractOptions,
+) -> io::Result<()> {
+    let entries = files
+        .iter()
+        .map(|path| Ok((path.clone(), file_io::archive_entry_name(path)?)))
+        .collect::<io::Result<Vec<_>>>()?;
+    verify_rsz(archive_path, &entries, &options.unlock())
+}
+
+/// `verify_archive` with an explicit way to unlock the archive, checking each entry against
+/// the file it was read from and the name it was stored under. Names that would not extract
+/// (see [`file_io::safe_extract_path`]) fail the check, so sources are never deleted for an
+/// archive that can't give them back.
+pub(crate) fn verify_rsz<P: AsRef<Path>>(
+    archive_path: P,
+    entries: &[(PathBuf, String)],
+    unlock: &rsz::Unlock,
+) -> io::Result<()> {
+    let mismatch = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+    let mut seen = 0usize;
+
+    for_each_entry(File::open(archive_path)?, unlock, |entry| {
+        if entry.count != entries.len() as u64 {
+            return Err(mismatch(format!(
+                "archive holds {} files, expected {}",
+                entry.count,
+                entries.len()
+            )));
+        }
+        let (source, expected) = &entries[entry.index as usize];
+        if entry.info.name != *expected {
+            return Err(mismatch(format!(
+                "archive entry {} does not match {}",
+                entry.info.name,
+                source.display()
+            )));
         }
-        
-        let mut output_file = File::create(&file_name)?;
-        output_file.write_all(&content)?;
+        file_io::safe_extract_path(&entry.info.name)?;
+        seen += 1;
+        compare_with_source(entry.data, entry.info.size, source)
+    })?;
+
+    if seen != entries.len() {
+        return Err(mismatch(format!(
+            "archive holds {} files, expected {}",
+            seen,
+            entries.len()
+        )));
     }
+    Ok(())
+}
 
+/// Fails with `InvalidData` unless the `size` bytes read from `archived` are exactly the
+/// contents of the file at `source`.
+pub(crate) fn compare_with_source(archived: &mut dyn Read, size: u64, source: &Path) -> io::Result<()> {
+    let mismatch = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+    let mut reader = BufReader::new(File::open(source)?);
+    let mut remaining = size;
+    let mut archived_chunk = [0u8; 8192];
+    let mut source_chunk = [0u8; 8192];
+    while remaining > 0 {
+        let n = remaining.min(archived_chunk.len() as u64) as usize;
+        archived.read_exact(&mut archived_chunk[..n])?;
+        reader.read_exact(&mut source_chunk[..n]).map_err(|_| {
+            mismatch(format!("{} is shorter than its archived copy", source.display()))
+        })?;
+        if archived_chunk[..n] != source_chunk[..n] {
+            return Err(mismatch(format!(
+                "{} differs from its archived copy",
+                source.display()
+            )));
+        }
+        remaining -= n as u64;
+    }
+    if reader.read(&mut source_chunk)? != 0 {
+        return Err(mismatch(format!(
+            "{} is longer than its archived copy",
+            source.display()
+        )));
+    }
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Largest deduplicated file `for_each_entry` keeps in memory to hand to each of its copies;
+/// copies of larger files decode the block again.
+const MAX_REPLAY_LEN: u64 = 64 * 1024 * 1024;
+
+/// One archive entry handed to a `for_each_entry` visitor.
+struct EntryData<'a> {
+    /// Position of the entry in the archive's entry table.
+    index: u64,
+    /// Number of entries in the archive.
+    count: u64,
+    info: &'a rsz::EntryInfo,
+    /// The entry's contents; whatever the visitor leaves unread is skipped.
+    data: &'a mut dyn Read,
+    /// Archive bytes read so far.
+    archive_bytes: &'a Cell<u64>,
+}
+
+/// Walks the entries of an RSZ archive (current or legacy layout) in stored order, then
+/// those assembled from extents, checking each entry's hash (when recorded) once the
+/// visitor is done with it.
+///
+/// # Returns
+/// * `io::Result<Option<VerifyingKey>>` - The verified signer of a signed archive.
+fn for_each_entry<F>(file: File, unlock: &rsz::Unlock, mut visit: F) -> io::Result<Option<VerifyingKey>>
+where
+    F: FnMut(EntryData) -> io::Result<()>,
+{
+    // A second handle for the entries assembled from extents, which are read last.
+    let spare = file.try_clone()?;
+    let mut reader = CountingReader::new(file);
+    let archive_bytes = reader.counter();
+
+    if !rsz::is_v2(&mut reader)? {
+        // Legacy layout: a single XZ stream of [count] then [name len, name, size, data]...
+        rsz::check_signer(None, unlock.signers)?;
+        let mut reader = BufReader::new(LimitedDecoder::xz(BufReader::new(reader), unlock.memlimit.unwrap_or(u64::MAX))?);
+        let file_count = reader.read_u64::<LittleEndian>()?;
+        for index in 0..file_count {
+            let name_len = reader.read_u64::<LittleEndian>()? as usize;
+            let mut name_buf = vec![0u8; name_len];
+            reader.read_exact(&mut name_buf)?;
+            let name = String::from_utf8_lossy(&name_buf).into_owned();
+            let size = reader.read_u64::<LittleEndian>()?;
+            let info = rsz::EntryInfo {
+                name,
+                size,
+                block: 0,
+                offset: 0,
+                sha256: None,
+                extents: Vec::new(),
+            };
+            let mut data = HashingReader::new((&mut reader).take(size));
+            visit(EntryData {
+                index,
+                count: file_count,
+                info: &info,
+                data: &mut data,
+                archive_bytes: &archive_bytes,
+            })?;
+            finish_entry(&mut data, &info)?;
+        }
+        return Ok(None);
+    }
+
+    let mut archive = rsz::OpenArchive::open(reader, unlock)?;
+    let signer = archive.signer;
+    let entries = archive.index.entries.clone();
+    // Visit entries in storage order so every block is decoded once, front to back.
+    let mut order: Vec<usize> = (0..entries.len()).filter(|&i| entries[i].extents.is_empty()).collect();
+    order.sort_by_key(|&i| (entries[i].block, entries[i].offset));
+
+    let mut next = 0;
+    while next < order.len() {
+        // Decode one block front to back for as many entries as follow in it.
+        let block = entries[order[next]].block;
+        let mut decoder = archive.block_reader(block as usize)?;
+        let mut position = 0u64;
+        while let Some(&i) = order.get(next) {
+            let entry = &entries[i];
+            if entry.block != block || entry.offset < position {
+                break;
+            }
+            io::copy(&mut (&mut decoder).take(entry.offset - position), &mut io::sink())?;
+            // Deduplicated copies of a file share its contents; unless they are large, the
+            // contents are decoded once and replayed for every copy.
+            let copies = order[next + 1..]
+                .iter()
+                .take_while(|&&j| {
+                    let copy = &entries[j];
+                    (copy.block, copy.offset, copy.size) == (entry.block, entry.offset, entry.size)
+                })
+                .count();
+            if copies > 0 && entry.size <= MAX_REPLAY_LEN {
+                let mut contents = Vec::new();
+                (&mut decoder).take(entry.size).read_to_end(&mut contents)?;
+                for &j in &order[next..=next + copies] {
+                    let mut data = HashingReader::new((&contents[..]).take(entry.size));
+                    visit(EntryData {
+                        index: j as u64,
+                        count: entries.len() as u64,
+                        info: &entries[j],
+                        data: &mut data,
+                        archive_bytes: &archive_bytes,
+                    })?;
+                    finish_entry(&mut data, &entries[j])?;
+                }
+                next += copies + 1;
+            } else {
+                let mut data = HashingReader::new((&mut decoder).take(entry.size));
+                visit(EntryData {
+                    index: i as u64,
+                    count: entries.len() as u64,
+                    info: entry,
+                    data: &mut data,
+                    archive_bytes: &archive_bytes,
+                })?;
+                finish_entry(&mut data, entry)?;
+                next += 1;
+            }
+            position = entry.offset + entry.size;
+        }
+    }
+
+    for (i, entry) in entries.iter().enumerate().filter(|(_, entry)| !entry.extents.is_empty()) {
+        let mut extents = rsz::ExtentReader::new(&archive, &spare, &entry.extents);
+        let mut data = HashingReader::new((&mut extents).take(entry.size));
+        visit(EntryData {
+            index: i as u64,
+            count: entries.len() as u64,
+            info: entry,
+            data: &mut data,
+            archive_bytes: &archive_bytes,
+        })?;
+        finish_entry(&mut data, entry)?;
+    }
+    Ok(signer)
+}
+
+/// Skips whatever a visitor left unread and fails if the entry was cut short or its
+/// contents do not match the hash recorded in the index.
+fn finish_entry<R: Read>(data: &mut HashingReader<io::Take<R>>, info: &rsz::EntryInfo) -> io::Result<()> {
+    io::copy(data, &mut io::sink())?;
+    if data.get_ref().limit() > 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("archive is truncated inside {}", info.name),
+        ));
+    }
+    if let Some(expected) = info.sha256
+        && data.digest() != expected
+    {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} failed its integrity check (SHA-256 mismatch)", info.name),
+        ));
+    }
+    Ok(())
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+use std::io::{self, Read};
+
+use clap::ValueEnum;
+
+use crate::lzma;
+
+/// Chunks are cut where the rolling hash of the last 64 bytes has this many leading zero
+/// bits, so they average 8 KiB...
+const CUT_BITS: u32 = 13;
+/// ...but are never shorter than this (except at the end of a file)...
+const MIN_CHUNK: usize = 2 * 1024;
+/// ...or longer than this.
+const MAX_CHUNK: usize = 64 * 1024;
+
+/// Random values for the gear hash, one per byte value.
+static GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    // splitmix64, so the table needs no build step or dependency.
+    let mut table = [0u64; 256];
+    let mut state = 0x9E37_79B9_7F4A_7C15u64;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+/// How RSZ archives avoid storing the same data twice, selected with `--dedup`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DedupMode {
+    /// Store every file in full
+    Off,
+    /// Store files with the same contents once; the copies point at the first one
+    #[default]
+    Files,
+    /// Also split files into content-defined chunks of about 8 KiB and store each distinct
+    /// chunk once, so near-identical files share what they have in common
+    Chunks,
+}
+
+/// What deduplication saved while writing an archive.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DedupStats {
+    /// Files whose whole contents were already in the archive.
+    pub files: u64,
+    /// Chunks of other files that were already in the archive.
+    pub chunks: u64,
+    /// Bytes of file contents that were not stored again.
+    pub bytes_saved: u64,
+}
+
+impl DedupStats {
+    /// Whether deduplication found anything.
+    pub fn is_empty(&self) -> bool {
+        self.bytes_saved == 0
+    }
+}
+
+impl fmt::Display for DedupStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} duplicate files", self.files)?;
+        if self.chunks > 0 {
+            write!(f, " and {} repeated chunks", self.chunks)?;
+        }
+        write!(f, ", {} not stored again", lzma::format_size(self.bytes_saved))
+    }
+}
+
+/// Splits a stream into content-defined chunks: cut points depend only on the bytes just
+/// before them (a gear hash, as in FastCDC), so an insertion or deletion only changes the
+/// chunks around it and the rest of the file still matches its other copies.
+pub(crate) struct Chunker<R> {
+    reader: R,
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+    eof: bool,
+}
+
+impl<R: Read> Chunker<R> {
+    pub(crate) fn new(reader: R) -> Self {
+        Chunker { reader, buf: vec![0; 2 * MAX_CHUNK], start: 0, end: 0, eof: false }
+    }
+
+    /// Returns the next chunk, or `None` at the end of the stream.
+    pub(crate) fn next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        if self.end - self.start < MAX_CHUNK && !self.eof {
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+            while self.end < self.buf.len() {
+                match self.reader.read(&mut self.buf[self.end..]) {
+                    Ok(0) => {
+                        self.eof = true;
+                        break;
+                    }
+                    Ok(n) => self.end += n,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        if self.start == self.end {
+            return Ok(None);
+        }
+        let len = cut_point(&self.buf[self.start..self.end]);
+        self.start += len;
+        Ok(Some(&self.buf[self.start - len..self.start]))
+    }
+}
+
+/// Length of the chunk at the start of `data`, which holds at least [`MAX_CHUNK`] bytes
+/// unless the stream ends sooner.
+fn cut_point(data: &[u8]) -> usize {
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(MAX_CHUNK).skip(MIN_CHUNK) {
+        // Shifting ages each byte out of the hash after 64 steps; the top bits depend on
+        // all of them.
+        hash = (hash << 1).wrapping_add(GEAR[usize::from(byte)]);
+        if hash >> (64 - CUT_BITS) == 0 {
+            return i + 1;
+        }
+    }
+    data.len().min(MAX_CHUNK)
+}
@@ -0,0 +1,63 @@
+use std::fs::Metadata;
+use std::time::SystemTime;
+
+/// What an archive entry is. Only files and directories can be written to archives.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+    /// A symlink, hard link, device or other special entry (its tar type or a description).
+    Other(&'static str),
+}
+
+/// An archive entry described independently of the archive format, so it can be read from
+/// one format and written to another. Formats that do not record an attribute leave it `None`.
+#[derive(Debug, Clone)]
+pub struct ArchiveEntry {
+    /// Relative path with `/` separators.
+    pub name: String,
+    pub kind: EntryKind,
+    /// Size of the contents in bytes (0 for directories).
+    pub size: u64,
+    /// Unix permission bits (`0o7777` at most).
+    pub mode: Option<u32>,
+    pub modified: Option<SystemTime>,
+    /// Owner and group ids.
+    pub owner: Option<(u64, u64)>,
+}
+
+impl ArchiveEntry {
+    /// Describes a file or directory on disk that is stored as `name`.
+    pub fn from_metadata(name: String, metadata: &Metadata) -> Self {
+        ArchiveEntry {
+            name,
+            kind: if metadata.is_dir() { EntryKind::Directory } else { EntryKind::File },
+            size: if metadata.is_dir() { 0 } else { metadata.len() },
+            mode: unix_mode(metadata),
+            modified: metadata.modified().ok(),
+            owner: unix_owner(metadata),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn unix_mode(metadata: &Metadata) -> Option<u32> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn unix_mode(_metadata: &Metadata) -> Option<u32> {
+    None
+}
+
+#[cfg(unix)]
+fn unix_owner(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.uid().into(), metadata.gid().into()))
+}
+
+#[cfg(not(unix))]
+fn unix_owner(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
@@ -1,8 +1,26 @@
 use std::fs::{self, File};
 use std::io::{BufReader, BufWriter, Result};
 use std::path::Path;
+use std::time::SystemTime;
 use walkdir::WalkDir;
-use std::path::PathBuf;
+use std::path::{Component, PathBuf};
+use crate::codec::{self, Codec};
+
+/// What to do when an output file already exists.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverwritePolicy {
+    /// Fail with an `AlreadyExists` error (the default, like `xz`).
+    #[default]
+    Refuse,
+    /// Replace the existing file.
+    Force,
+    /// Leave the existing file untouched and skip the write.
+    NoClobber,
+    /// Replace the existing file only if the source is newer than it.
+    KeepNewer,
+    /// Rename the existing file to `<name>.~N~` before writing.
+    BackupNumbered,
+}
 
 /// Opens a file for reading with buffered I/O
 /// 
@@ -33,6 +51,150 @@ pub fn create_output_file(path: &str) -> Result<BufWriter<File>> {
     Ok(BufWriter::new(file))
 }
 
+/// Creates a file for writing, applying an overwrite policy if it already exists
+///
+/// # Arguments
+/// * `path` - Path to the output file
+/// * `policy` - How to treat an existing file at `path`
+/// * `source_modified` - Modification time of the data being written, used by `KeepNewer`
+///
+/// # Returns
+/// * `Result<Option<OutputFile>>` - The writer, or `None` if the policy says to skip this file
+pub fn create_output_file_with_policy(
+    path: &Path,
+    policy: OverwritePolicy,
+    source_modified: Option<SystemTime>,
+) -> Result<Option<OutputFile>> {
+    create_output(path, policy, source_modified, false)
+}
+
+/// Like [`create_output_file_with_policy`], for a file extracted from an archive. The archive
+/// picks the name, so an existing device, FIFO or socket at `path` is replaced rather than
+/// written into
+///
+/// # Arguments
+/// * `path` - Path from [`safe_extract_path`], below a directory made by [`create_extract_dir`]
+/// * `policy` - How to treat an existing file at `path`
+/// * `source_modified` - Modification time of the entry, used by `KeepNewer`
+///
+/// # Returns
+/// * `Result<Option<OutputFile>>` - The writer, or `None` if the policy says to skip this file
+pub fn create_extracted_file(
+    path: &Path,
+    policy: OverwritePolicy,
+    source_modified: Option<SystemTime>,
+) -> Result<Option<OutputFile>> {
+    create_output(path, policy, source_modified, true)
+}
+
+fn create_output(
+    path: &Path,
+    policy: OverwritePolicy,
+    source_modified: Option<SystemTime>,
+    replace_special: bool,
+) -> Result<Option<OutputFile>> {
+    let existing = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return create_new(path),
+        Err(e) => return Err(e),
+    };
+
+    if existing.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{} exists and is a directory", path.display()),
+        ));
+    }
+
+    match policy {
+        OverwritePolicy::Refuse => {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!(
+                    "{} already exists (use --force, --no-clobber, --keep-newer or --backup)",
+                    path.display()
+                ),
+            ));
+        }
+        OverwritePolicy::NoClobber => return Ok(None),
+        OverwritePolicy::KeepNewer => {
+            if let (Ok(existing_modified), Some(source_modified)) =
+                (existing.modified(), source_modified)
+                && existing_modified > source_modified
+            {
+                return Ok(None);
+            }
+        }
+        OverwritePolicy::BackupNumbered => {
+            fs::rename(path, numbered_backup_path(path))?;
+            return create_new(path);
+        }
+        OverwritePolicy::Force => {}
+    }
+
+    // Writing through a symlink could reach any file on the system, so the link itself is
+    // replaced (like `xz -f` does).
+    if existing.file_type().is_symlink() || (replace_special && !existing.is_file()) {
+        fs::remove_file(path)?;
+        return create_new(path);
+    }
+    let file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)?;
+    // A device or FIFO written through `path` is not ours to remove.
+    let created = existing.is_file();
+    Ok(Some(OutputFile { writer: BufWriter::new(file), created }))
+}
+
+/// Creates a file that must not exist yet; `create_new` also closes the race with another
+/// process creating it (or a symlink in its place) meanwhile.
+fn create_new(path: &Path) -> Result<Option<OutputFile>> {
+    let file = fs::OpenOptions::new().write(true).create_new(true).open(path)?;
+    Ok(Some(OutputFile { writer: BufWriter::new(file), created: true }))
+}
+
+/// An output file opened by [`create_output_file_with_policy`].
+pub struct OutputFile {
+    pub writer: BufWriter<File>,
+    /// Whether `path` is now a regular file holding nothing but what we write: a new file,
+    /// or an existing regular file that was truncated. Only then may a failed write remove it.
+    pub created: bool,
+}
+
+/// Removes an output file whose write failed, so a truncated file isn't mistaken for a
+/// finished one. Does nothing unless `created` (see [`OutputFile::created`]) and `path` is
+/// still a regular file, so an existing device such as `/dev/null` is never unlinked.
+///
+/// # Arguments
+/// * `path` - Path of the output file
+/// * `created` - Whether the failed write created the file
+pub fn remove_partial_output(path: &Path, created: bool) {
+    if created && fs::symlink_metadata(path).is_ok_and(|m| m.is_file()) {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Returns the first unused GNU-style numbered backup name (`<name>.~N~`) for a path
+///
+/// # Arguments
+/// * `path` - Path of the file being backed up
+///
+/// # Returns
+/// * `PathBuf` - Backup path that does not exist yet
+pub fn numbered_backup_path(path: &Path) -> PathBuf {
+    let mut n = 1u64;
+    loop {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".~{}~", n));
+        let candidate = PathBuf::from(name);
+        if fs::symlink_metadata(&candidate).is_err() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
 
 /// Validates that the input path exists and is a file
 /// 
@@ -42,35 +204,311 @@ pub fn create_output_file(path: &str) -> Result<BufWriter<File>> {
 /// # Returns
 /// * `Result<()>` - Ok if valid, error otherwise
 pub fn validate_input_path(path: &str) -> Result<()> {
-    let metadata = std::fs::metadata(path)?;
+    validate_input(Path::new(path), false)
+}
+
+/// Validates that an input exists and can be read, optionally accepting directories
+///
+/// # Arguments
+/// * `path` - Path to validate
+/// * `allow_dirs` - Whether a directory is an acceptable input (RSZ archives)
+///
+/// # Returns
+/// * `Result<()>` - Ok if valid, an error naming the path otherwise
+pub fn validate_input(path: &Path, allow_dirs: bool) -> Result<()> {
+    if allow_dirs && fs::metadata(path).is_ok_and(|m| m.is_dir()) {
+        fs::read_dir(path).map_err(|e| with_path(e, path))?;
+        return Ok(());
+    }
+    check_input_file(path).map_err(|e| with_path(e, path))
+}
+
+/// Checks that a single-file-mode input is a regular file that can be read. Errors don't
+/// name the path, as batch reports already start with it.
+///
+/// # Arguments
+/// * `path` - Path to check
+///
+/// # Returns
+/// * `Result<()>` - Ok if the file can be read
+pub fn check_input_file(path: &Path) -> Result<()> {
+    let metadata = fs::metadata(path)?;
+    if metadata.is_dir() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "is a directory; XZ mode compresses single files (use -f rsz to archive directories)",
+        ));
+    }
     if !metadata.is_file() {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "is not a regular file"));
+    }
+    File::open(path)?;
+    Ok(())
+}
+
+/// Checks that an output file would not be read back as one of its own inputs
+///
+/// # Arguments
+/// * `output` - Path of the file about to be written
+/// * `inputs` - Input files/directories given on the command line
+/// * `recursive` - Whether input directories are walked recursively
+///
+/// # Returns
+/// * `Result<()>` - Ok if the output is outside the inputs, an `InvalidInput` error otherwise
+pub fn check_output_outside_inputs(output: &Path, inputs: &[PathBuf], recursive: bool) -> Result<()> {
+    // The output may not exist yet, so resolve its directory and re-attach the file name.
+    let output = match (output.parent(), output.file_name()) {
+        (Some(parent), Some(name)) => {
+            let parent = if parent.as_os_str().is_empty() { Path::new(".") } else { parent };
+            match fs::canonicalize(parent) {
+                Ok(parent) => parent.join(name),
+                Err(_) => return Ok(()),
+            }
+        }
+        _ => return Ok(()),
+    };
+
+    for input in inputs {
+        let Ok(input) = fs::canonicalize(input) else {
+            continue;
+        };
+        let collected = if input.is_dir() {
+            if recursive {
+                output.starts_with(&input)
+            } else {
+                output.parent() == Some(input.as_path())
+            }
+        } else {
+            output == input
+        };
+        if collected {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!(
+                    "output {} is inside input {}; it would be read while it is being written",
+                    output.display(),
+                    input.display()
+                ),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Fails if writing `output` would clobber `input` itself (e.g. decompressing a file with no suffix)
+///
+/// # Arguments
+/// * `input` - Path of the file being read
+/// * `output` - Path of the file about to be written
+///
+/// # Returns
+/// * `Result<()>` - Ok if the paths refer to different files
+pub fn check_distinct_paths(input: &Path, output: &Path) -> Result<()> {
+    if let (Ok(input), Ok(output)) = (fs::canonicalize(input), fs::canonicalize(output))
+        && input == output
+    {
         return Err(std::io::Error::new(
             std::io::ErrorKind::InvalidInput,
-            "Input path must be a file",
+            format!("output {} would overwrite its own input", output.display()),
         ));
     }
     Ok(())
 }
 
+/// Prefixes an I/O error message with the path it concerns, keeping its kind
+fn with_path(error: std::io::Error, path: &Path) -> std::io::Error {
+    std::io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+}
+
 /// Generates a default output path based on operation type
 /// 
 /// # Arguments
 /// * `input_path` - Original file path
-/// * `is_compression` - True for compression, false for decompression
+/// * `codec` - The codec to compress with, or `None` when decompressing
 /// 
 /// # Returns
-/// * `String` - Generated output path
-pub fn default_output_path(input_path: &str, is_compression: bool) -> String {
-    if is_compression {
-        format!("{}.xz", input_path)
-    } else {
-        input_path
-            .trim_end_matches(".xz")
-            .trim_end_matches(".lzma")
-            .to_string()
+/// * `String` - Generated output path: `<input>.<ext>` when compressing, the input without
+///   its compression suffix (any registered codec's, e.g. `.xz`, `.lzma`, `.zst`) otherwise
+pub fn default_output_path(input_path: &str, codec: Option<&dyn Codec>) -> String {
+    match codec {
+        Some(codec) => format!("{}.{}", input_path, codec.extension()),
+        None => codec::registered()
+            .iter()
+            .find_map(|codec| strip_suffix(input_path, codec.extension()))
+            .unwrap_or(input_path)
+            .to_string(),
+    }
+}
+
+/// Generates the default decompressed path for a file compressed with a known codec
+///
+/// # Arguments
+/// * `input_path` - Compressed file path
+/// * `codec` - The codec the file was compressed with
+///
+/// # Returns
+/// * `String` - The input without the codec's suffix, or unchanged if it has none
+pub fn strip_codec_suffix(input_path: &str, codec: &dyn Codec) -> String {
+    strip_suffix(input_path, codec.extension())
+        .unwrap_or(input_path)
+        .to_string()
+}
+
+/// `path` without `.<extension>` at its end, if it ends that way
+fn strip_suffix<'a>(path: &'a str, extension: &str) -> Option<&'a str> {
+    path.strip_suffix(extension)?.strip_suffix('.')
+}
+
+/// Generates a default archive path from the first input, e.g. `dir/` -> `dir.rsz`
+///
+/// # Arguments
+/// * `input_path` - First input file or directory
+/// * `extension` - Archive extension without the leading dot, e.g. `rsz` or `tar.xz`
+///
+/// # Returns
+/// * `PathBuf` - Generated archive path
+pub fn default_archive_path(input_path: &Path, extension: &str) -> PathBuf {
+    // `components` drops trailing separators, so `dir/` names the directory itself.
+    let trimmed: PathBuf = input_path.components().collect();
+    let mut name = trimmed.into_os_string();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+/// Turns a file path into the name it is stored under in a tar archive: `/`-separated,
+/// with any root, drive prefix and `.` components dropped
+///
+/// # Arguments
+/// * `path` - Path of the file being archived
+///
+/// # Returns
+/// * `Result<String>` - The entry name; `InvalidInput` for `..` components or non-UTF-8 names
+pub fn archive_entry_name(path: &Path) -> Result<String> {
+    let mut parts = Vec::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str().ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{}: file name is not valid UTF-8", path.display()),
+                )
+            })?),
+            Component::ParentDir => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    format!("{}: paths containing .. cannot be archived", path.display()),
+                ));
+            }
+            Component::RootDir | Component::Prefix(_) | Component::CurDir => {}
+        }
+    }
+    if parts.is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("{}: not a file name", path.display()),
+        ));
+    }
+    Ok(parts.join("/"))
+}
+
+/// Maps an archive entry name to the path it is extracted to, below the current directory.
+/// Leading `/` and drive prefixes are dropped (like `tar`), so absolute names extract
+/// relative to the current directory
+///
+/// # Arguments
+/// * `name` - Entry name as stored in the archive
+///
+/// # Returns
+/// * `Result<PathBuf>` - The relative output path; `InvalidData` if the name is empty or
+///   contains `..`, which could write outside the extraction directory
+pub fn safe_extract_path(name: &str) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in Path::new(name).components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::ParentDir => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("refusing to extract {}: the name leaves the extraction directory", name),
+                ));
+            }
+            Component::RootDir | Component::Prefix(_) | Component::CurDir => {}
+        }
     }
+    if path.as_os_str().is_empty() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("refusing to extract entry with empty name {:?}", name),
+        ));
+    }
+    Ok(path)
 }
 
+/// Creates the extraction directory `dir` (from [`safe_extract_path`] or [`safe_extract_dir`])
+/// and any missing parents, refusing to go through an existing symlink: an archive could
+/// plant one (or find one) to write outside the extraction directory
+///
+/// # Arguments
+/// * `dir` - Relative path of the directory
+///
+/// # Returns
+/// * `Result<()>` - Ok once the directory exists; `InvalidData` if the path leads through a symlink
+pub fn create_extract_dir(dir: &Path) -> Result<()> {
+    let mut prefix = PathBuf::new();
+    for component in dir.components() {
+        prefix.push(component);
+        match fs::symlink_metadata(&prefix) {
+            Ok(metadata) if metadata.file_type().is_symlink() => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("refusing to extract into {}: it is a symbolic link", prefix.display()),
+                ));
+            }
+            Ok(_) => {}
+            // Nothing below a missing directory exists yet either.
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => break,
+            Err(e) => return Err(e),
+        }
+    }
+    fs::create_dir_all(dir)
+}
+
+/// Like [`safe_extract_path`] for directory entries, which may name the extraction
+/// directory itself (archives often start with a `./` entry)
+///
+/// # Returns
+/// * `Result<Option<PathBuf>>` - The directory to create, or `None` for the root itself
+pub fn safe_extract_dir(name: &str) -> Result<Option<PathBuf>> {
+    let is_root = Path::new(name)
+        .components()
+        .all(|c| matches!(c, Component::RootDir | Component::Prefix(_) | Component::CurDir));
+    if is_root {
+        return Ok(None);
+    }
+    safe_extract_path(name).map(Some)
+}
+
+/// Applies an archive entry's permissions (unix only, set-id and sticky bits dropped)
+/// and modification time to an extracted file
+///
+/// # Arguments
+/// * `file` - The extracted file
+/// * `mode` - Stored unix mode, if the archive records one
+/// * `modified` - Stored modification time, if any
+pub fn restore_metadata(file: &File, mode: Option<u32>, modified: Option<SystemTime>) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(fs::Permissions::from_mode(mode & 0o777))?;
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    if let Some(modified) = modified {
+        file.set_modified(modified)?;
+    }
+    Ok(())
+}
 
 /// Recursively collect file paths
 /// Collects file paths from the given list of paths.
@@ -0,0 +1,272 @@
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+use std::path::Path;
+
+use crate::codec::{self, Builtin, Codec, XZ_MAGIC};
+use crate::lzma::{self, Filter};
+use crate::seekable::{self, StreamIndex};
+
+/// XZ's filter ids, as stored in block headers.
+const FILTER_DELTA: u64 = 0x03;
+const FILTER_LZMA2: u64 = 0x21;
+
+/// What `rs-zip info` reports about an `.xz` file, like `xz -l`: read from its stream
+/// headers, indexes and block headers (and rs-zip's size header) without decompressing it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XzInfo {
+    /// Whether the file is in rs-zip's single-file format: an 8-byte original size in front
+    /// of the XZ stream.
+    pub size_header: bool,
+    pub streams: usize,
+    pub blocks: usize,
+    /// Size of the file.
+    pub compressed_size: u64,
+    /// Size of the original data (for rs-zip's format, without the padding of small files).
+    pub uncompressed_size: u64,
+    /// The integrity checks of the streams, without repeats: `None`, `CRC32`, `CRC64`,
+    /// `SHA-256` or `Unknown-N`.
+    pub checks: Vec<String>,
+    /// The largest LZMA2 dictionary of any block, which is most of what decoding needs;
+    /// `None` when the file holds no blocks.
+    pub dict_size: Option<u32>,
+    /// The filter chain of the first block, e.g. `x86, lzma2`.
+    pub filters: Vec<String>,
+}
+
+impl XzInfo {
+    /// Compressed size divided by uncompressed size, as `xz -l` shows it.
+    pub fn ratio(&self) -> Option<f64> {
+        (self.uncompressed_size > 0).then(|| self.compressed_size as f64 / self.uncompressed_size as f64)
+    }
+
+    /// Formats the information as a JSON object, with `file` as its first member.
+    pub fn to_json(&self, file: &str) -> String {
+        let strings = |values: &[String]| values.iter().map(|value| json_string(value)).collect::<Vec<_>>().join(", ");
+        format!(
+            "{{\"file\": {}, \"format\": \"{}\", \"streams\": {}, \"blocks\": {}, \"compressed_size\": {}, \
+             \"uncompressed_size\": {}, \"ratio\": {}, \"checks\": [{}], \"dict_size\": {}, \"filters\": [{}]}}",
+            json_string(file),
+            if self.size_header { "rs-zip" } else { "xz" },
+            self.streams,
+            self.blocks,
+            self.compressed_size,
+            self.uncompressed_size,
+            self.ratio().map_or("null".to_string(), |ratio| format!("{:.3}", ratio)),
+            strings(&self.checks),
+            self.dict_size.map_or("null".to_string(), |size| size.to_string()),
+            strings(&self.filters),
+        )
+    }
+}
+
+impl fmt::Display for XzInfo {
+    /// The `xz -lv` style report, one field per line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let format = if self.size_header { "rs-zip single-file XZ (size header, then XZ)" } else { "XZ" };
+        writeln!(f, "  Format:            {}", format)?;
+        writeln!(f, "  Streams:           {}", self.streams)?;
+        writeln!(f, "  Blocks:            {}", self.blocks)?;
+        writeln!(f, "  Compressed size:   {}", size_with_bytes(self.compressed_size))?;
+        writeln!(f, "  Uncompressed size: {}", size_with_bytes(self.uncompressed_size))?;
+        match self.ratio() {
+            Some(ratio) => writeln!(f, "  Ratio:             {:.3}", ratio)?,
+            None => writeln!(f, "  Ratio:             ---")?,
+        }
+        writeln!(f, "  Check:             {}", self.checks.join(", "))?;
+        match self.dict_size {
+            Some(size) => writeln!(f, "  Dictionary size:   {}", lzma::format_size(size.into()))?,
+            None => writeln!(f, "  Dictionary size:   ---")?,
+        }
+        write!(f, "  Filters:           {}", if self.filters.is_empty() { "---".to_string() } else { self.filters.join(", ") })
+    }
+}
+
+/// A size in the largest fitting unit, followed by the exact byte count when that is rounded.
+fn size_with_bytes(bytes: u64) -> String {
+    match lzma::format_size(bytes) {
+        short if bytes < 1024 => short,
+        short => format!("{} ({} B)", short, bytes),
+    }
+}
+
+/// Reads the information `rs-zip info` shows about an `.xz` file (standard, multi-stream or
+/// in rs-zip's single-file format).
+///
+/// # Arguments
+/// * `path` - The compressed file.
+///
+/// # Returns
+/// * `io::Result<XzInfo>` - Sizes, streams, blocks, checks and filters; `InvalidInput` for
+///   files of another codec, `InvalidData` for damaged XZ files.
+pub fn xz_info(path: &Path) -> io::Result<XzInfo> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut head = [0u8; 8 + codec::MAGIC_LEN];
+    let read = crate::rsz::read_up_to(&mut reader, &mut head)?;
+    let head = &head[..read];
+    // As when decompressing, rs-zip's size header is checked for first: its bytes could
+    // pass for another format's magic.
+    let framed = head.get(8..8 + XZ_MAGIC.len()) == Some(&XZ_MAGIC[..]);
+    if let Some(codec) = codec::detect(head).filter(|codec| !framed && codec.id() != Builtin::Xz.id()) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{} data has no index to read; info only reads .xz files", codec.name()),
+        ));
+    }
+
+    let streams = seekable::read_streams(&mut reader)?;
+    let mut checks = Vec::new();
+    for stream in &streams {
+        let check = check_name(stream.stream_flags[1]);
+        if !checks.contains(&check) {
+            checks.push(check);
+        }
+    }
+    let mut dict_size = None;
+    let mut filters = None;
+    for block in streams.iter().flat_map(|stream| &stream.blocks) {
+        let chain = read_filters(&mut reader, block.offset)?;
+        for (id, props) in &chain {
+            if *id == FILTER_LZMA2 {
+                dict_size = dict_size.max(Some(lzma2_dict_size(props)?));
+            }
+        }
+        filters.get_or_insert(chain);
+    }
+
+    Ok(XzInfo {
+        size_header: streams.first().is_some_and(|stream| stream.size_header.is_some()),
+        streams: streams.len(),
+        blocks: streams.iter().map(|stream| stream.blocks.len()).sum(),
+        compressed_size: reader.seek(SeekFrom::End(0))?,
+        uncompressed_size: streams.iter().map(StreamIndex::data_len).sum(),
+        checks,
+        dict_size,
+        filters: filters
+            .unwrap_or_default()
+            .iter()
+            .map(|(id, props)| filter_name(*id, props))
+            .collect(),
+    })
+}
+
+/// Reads the filter chain from the header of the block at `offset`.
+///
+/// # Returns
+/// * `io::Result<Vec<(u64, Vec<u8>)>>` - Each filter's id and properties, in order.
+fn read_filters<R: Read + Seek>(reader: &mut R, offset: u64) -> io::Result<Vec<(u64, Vec<u8>)>> {
+    // The first byte gives the header size; the header ends with its CRC32.
+    let mut size = [0u8; 1];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut size)?;
+    if size[0] == 0 {
+        return Err(corrupt("an XZ block header is damaged"));
+    }
+    let mut header = vec![0u8; (usize::from(size[0]) + 1) * 4];
+    header[0] = size[0];
+    reader.read_exact(&mut header[1..])?;
+    let (body, crc) = header.split_at(header.len() - 4);
+    if crc32fast::hash(body).to_le_bytes() != crc {
+        return Err(corrupt("an XZ block header is damaged"));
+    }
+
+    let flags = body[1];
+    let mut fields = &body[2..];
+    // Optional compressed and uncompressed sizes come before the filters.
+    for present in [0x40, 0x80] {
+        if flags & present != 0 {
+            seekable::read_varint(&mut fields)?;
+        }
+    }
+    let mut chain = Vec::new();
+    for _ in 0..=(flags & 0x03) {
+        let id = seekable::read_varint(&mut fields)?;
+        let len = seekable::read_varint(&mut fields)? as usize;
+        if len > fields.len() {
+            return Err(corrupt("an XZ block header is damaged"));
+        }
+        let (props, rest) = fields.split_at(len);
+        chain.push((id, props.to_vec()));
+        fields = rest;
+    }
+    Ok(chain)
+}
+
+/// Decodes LZMA2's one-byte dictionary size: 2 or 3 times a power of two, from 4 KiB.
+fn lzma2_dict_size(props: &[u8]) -> io::Result<u32> {
+    match props {
+        [40] => Ok(u32::MAX),
+        &[bits] if bits < 40 => Ok((2 | u32::from(bits & 1)) << (bits / 2 + 11)),
+        _ => Err(corrupt("an XZ block header has invalid LZMA2 properties")),
+    }
+}
+
+fn filter_name(id: u64, props: &[u8]) -> String {
+    let filter = match id {
+        FILTER_LZMA2 => return "lzma2".to_string(),
+        FILTER_DELTA => Filter::Delta(u32::from(props.first().copied().unwrap_or(0)) + 1),
+        0x04 => Filter::X86,
+        0x05 => Filter::PowerPc,
+        0x06 => return "ia64".to_string(),
+        0x07 => Filter::Arm,
+        0x08 => Filter::ArmThumb,
+        0x09 => Filter::Sparc,
+        0x0A => Filter::Arm64,
+        0x0B => Filter::RiscV,
+        _ => return format!("{:#x}", id),
+    };
+    filter.to_string()
+}
+
+/// Names the integrity check in a stream's flags, as `xz -l` does.
+fn check_name(check: u8) -> String {
+    match check {
+        0x00 => "None".to_string(),
+        0x01 => "CRC32".to_string(),
+        0x04 => "CRC64".to_string(),
+        0x0A => "SHA-256".to_string(),
+        other => format!("Unknown-{}", other),
+    }
+}
+
+/// Quotes `value` as a JSON string.
+fn json_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if u32::from(c) < 0x20 => quoted.push_str(&format!("\\u{:04x}", u32::from(c))),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn corrupt(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn padded_concatenated_streams_match_xz_list() {
+        // `xz a; 8 null bytes; xz b`, as made by xz 5.x; the null bytes are stream padding.
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/padded-streams.xz");
+        let info = xz_info(&path).unwrap();
+
+        // What `xz -l` reports for the file.
+        assert!(!info.size_header);
+        assert_eq!((info.streams, info.blocks), (2, 2));
+        assert_eq!((info.compressed_size, info.uncompressed_size), (160, 42));
+        assert_eq!(format!("{:.3}", info.ratio().unwrap()), "3.810");
+        assert_eq!(info.checks, ["CRC64"]);
+    }
+}
@@ -1,4 +1,21 @@
+pub mod batch;
 pub mod cli;
+pub mod codec;
 pub mod compression;
+pub mod convert;
+pub mod crypto;
+pub mod dedup;
 pub mod decompression;
+pub mod entry;
 pub mod file_io;
+pub mod info;
+pub mod lzma;
+pub mod progress;
+pub mod rsz;
+pub mod seekable;
+pub mod sevenzip;
+pub mod signature;
+pub mod solid;
+pub mod store;
+pub mod tarball;
+pub mod zipfile;
@@ -0,0 +1,926 @@
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use clap::ValueEnum;
+use liblzma::bufread::XzDecoder;
+use liblzma::stream::{self, Action, Check, Filters, LzmaOptions, Status, Stream, PRESET_EXTREME};
+use liblzma::write::XzEncoder;
+
+use crate::codec::{Builtin, Codec, Encoder};
+
+/// Smallest dictionary liblzma accepts.
+const DICT_SIZE_MIN: u32 = 4096;
+/// Largest dictionary liblzma's encoder accepts (1.5 GiB).
+const DICT_SIZE_MAX: u32 = 1536 << 20;
+
+/// Longest match length the LZMA encoder looks for.
+const NICE_LEN_MAX: u32 = 273;
+/// Most filters an XZ stream can run in front of LZMA2.
+const MAX_FILTERS: usize = 3;
+
+/// The LZMA variant of a headerless stream, selected with `--raw`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RawFormat {
+    /// LZMA1, as inside `.lzma` files but without their 13-byte header
+    Lzma1,
+    /// LZMA2, as inside `.xz` files but without the container
+    Lzma2,
+}
+
+/// The properties a raw stream is encoded with. The stream does not record them, so the
+/// decoder has to be given the same ones.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LzmaProps {
+    /// Literal context bits (0-8; lc + lp at most 4 for LZMA2).
+    pub lc: u32,
+    /// Literal position bits (0-4).
+    pub lp: u32,
+    /// Position bits (0-4).
+    pub pb: u32,
+    /// Dictionary size in bytes.
+    pub dict_size: u32,
+}
+
+impl Default for LzmaProps {
+    /// liblzma's defaults at preset 6: lc=3, lp=0, pb=2 and an 8 MiB dictionary.
+    fn default() -> Self {
+        LzmaProps {
+            lc: 3,
+            lp: 0,
+            pb: 2,
+            dict_size: 8 << 20,
+        }
+    }
+}
+
+impl LzmaProps {
+    /// Decodes the 5 LZMA1 property bytes found at the start of a `.lzma` header: the
+    /// lc/lp/pb byte `(pb * 5 + lp) * 9 + lc` followed by the little-endian dictionary size.
+    pub fn from_lzma1_bytes(bytes: &[u8; 5]) -> io::Result<Self> {
+        if bytes[0] >= 9 * 5 * 5 {
+            return Err(invalid_props(format!("invalid lc/lp/pb byte 0x{:02x}", bytes[0])));
+        }
+        let byte = u32::from(bytes[0]);
+        let props = LzmaProps {
+            lc: byte % 9,
+            lp: byte / 9 % 5,
+            pb: byte / 45,
+            dict_size: u32::from_le_bytes([bytes[1], bytes[2], bytes[3], bytes[4]]).max(DICT_SIZE_MIN),
+        };
+        props.check(RawFormat::Lzma1)?;
+        Ok(props)
+    }
+
+    /// Decodes the LZMA2 dictionary size byte (as in an XZ filter header or a 7z coder).
+    /// lc, lp and pb keep their defaults; LZMA2 streams carry their own.
+    pub fn from_lzma2_byte(byte: u8) -> io::Result<Self> {
+        let dict_size = match byte {
+            0..40 => (2 | u32::from(byte & 1)) << (byte / 2 + 11),
+            40 => u32::MAX,
+            _ => return Err(invalid_props(format!("invalid LZMA2 dictionary byte 0x{:02x}", byte))),
+        };
+        Ok(LzmaProps {
+            dict_size,
+            ..LzmaProps::default()
+        })
+    }
+
+    /// Checks the properties against the limits of `format`.
+    pub fn check(&self, format: RawFormat) -> io::Result<()> {
+        let (lc_max, lp_max) = match format {
+            RawFormat::Lzma1 => (8, 4),
+            RawFormat::Lzma2 => (4, 4),
+        };
+        if self.lc > lc_max || self.lp > lp_max || self.pb > 4 {
+            return Err(invalid_props(format!(
+                "lc must be 0-{}, lp 0-{} and pb 0-4 (got lc={}, lp={}, pb={})",
+                lc_max, lp_max, self.lc, self.lp, self.pb
+            )));
+        }
+        if format == RawFormat::Lzma2 && self.lc + self.lp > 4 {
+            return Err(invalid_props(format!(
+                "lc + lp must not exceed 4 for LZMA2 (got {})",
+                self.lc + self.lp
+            )));
+        }
+        if self.dict_size < DICT_SIZE_MIN {
+            return Err(invalid_props(format!(
+                "the dictionary must be at least {} bytes (got {})",
+                DICT_SIZE_MIN, self.dict_size
+            )));
+        }
+        Ok(())
+    }
+
+    /// Encoder options: `preset`'s match finder settings with these properties.
+    fn options(&self, preset: u32) -> io::Result<LzmaOptions> {
+        let mut options = LzmaOptions::new_preset(preset).map_err(io::Error::other)?;
+        options
+            .literal_context_bits(self.lc)
+            .literal_position_bits(self.lp)
+            .position_bits(self.pb)
+            .dict_size(self.dict_size);
+        Ok(options)
+    }
+}
+
+impl FromStr for LzmaProps {
+    type Err = io::Error;
+
+    /// Parses `lc=3,lp=0,pb=2,dict=8MiB` (any subset; the rest keep their defaults), or
+    /// encoded properties in hex: 5 bytes for LZMA1 (e.g. `5d00008000`) or the 1-byte
+    /// LZMA2 dictionary size (e.g. `16`).
+    fn from_str(s: &str) -> io::Result<Self> {
+        let s = s.trim();
+        if !s.contains('=') {
+            let hex = s.strip_prefix("0x").unwrap_or(s);
+            let bytes = parse_hex(hex).ok_or_else(|| {
+                invalid_props(format!("{} is neither key=value pairs nor hex-encoded properties", s))
+            })?;
+            return match bytes.as_slice() {
+                [byte] => LzmaProps::from_lzma2_byte(*byte),
+                [_, _, _, _, _] => LzmaProps::from_lzma1_bytes(bytes.as_slice().try_into().unwrap()),
+                _ => Err(invalid_props(format!(
+                    "encoded properties are 5 bytes (LZMA1) or 1 byte (LZMA2), not {}",
+                    bytes.len()
+                ))),
+            };
+        }
+
+        let mut props = LzmaProps::default();
+        for pair in s.split(',') {
+            let (key, value) = pair
+                .split_once('=')
+                .ok_or_else(|| invalid_props(format!("expected key=value, got {}", pair)))?;
+            let value = value.trim();
+            let number = || {
+                value
+                    .parse::<u32>()
+                    .map_err(|_| invalid_props(format!("{} must be a number, got {}", key.trim(), value)))
+            };
+            match key.trim().to_ascii_lowercase().as_str() {
+                "lc" => props.lc = number()?,
+                "lp" => props.lp = number()?,
+                "pb" => props.pb = number()?,
+                "dict" => props.dict_size = parse_dict_size(value)?,
+                other => {
+                    return Err(invalid_props(format!(
+                        "unknown property {} (expected lc, lp, pb or dict)",
+                        other
+                    )));
+                }
+            }
+        }
+        // LZMA1 allows the widest ranges; the stream's own format is checked when it is used.
+        props.check(RawFormat::Lzma1)?;
+        Ok(props)
+    }
+}
+
+/// Parses a dictionary size in bytes, with an optional binary suffix as `xz` accepts
+/// (`KiB`, `MiB`, `GiB`, or just `K`, `M`, `G`).
+///
+/// # Returns
+/// * `io::Result<u32>` - The size; `InvalidInput` if it is malformed or outside
+///   liblzma's 4 KiB - 1.5 GiB range.
+pub fn parse_dict_size(value: &str) -> io::Result<u32> {
+    let size = parse_size(value)?;
+    if !(u64::from(DICT_SIZE_MIN)..=u64::from(DICT_SIZE_MAX)).contains(&size) {
+        return Err(invalid_props(format!(
+            "the dictionary size must be between 4 KiB and 1536 MiB (got {})",
+            value.trim()
+        )));
+    }
+    Ok(size as u32)
+}
+
+/// Parses a decoder memory limit, such as `256MiB` (same suffixes as [`parse_dict_size`]).
+///
+/// # Returns
+/// * `io::Result<u64>` - The limit in bytes; `InvalidInput` if it is malformed or zero.
+pub fn parse_memlimit(value: &str) -> io::Result<u64> {
+    match parse_size(value)? {
+        0 => Err(invalid_props("the memory limit must be more than 0".to_string())),
+        limit => Ok(limit),
+    }
+}
+
+/// Parses the amount of input per XZ block, such as `16MiB` (same suffixes as
+/// [`parse_dict_size`]).
+///
+/// # Returns
+/// * `io::Result<u64>` - The block size in bytes; `InvalidInput` if it is malformed or zero.
+pub fn parse_block_size(value: &str) -> io::Result<u64> {
+    match parse_size(value)? {
+        0 => Err(invalid_props("the block size must be more than 0".to_string())),
+        size => Ok(size),
+    }
+}
+
+/// Parses a size in bytes with an optional `KiB`, `MiB` or `GiB` (or `K`, `M`, `G`) suffix.
+pub(crate) fn parse_size(value: &str) -> io::Result<u64> {
+    let value = value.trim();
+    let digits = value.find(|c: char| !c.is_ascii_digit()).unwrap_or(value.len());
+    let (number, suffix) = value.split_at(digits);
+    let shift = match suffix.trim().to_ascii_lowercase().as_str() {
+        "" => 0,
+        "k" | "kib" => 10,
+        "m" | "mib" => 20,
+        "g" | "gib" => 30,
+        _ => return Err(invalid_props(format!("unknown size suffix in {} (use KiB, MiB or GiB)", value))),
+    };
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(1 << shift))
+        .ok_or_else(|| invalid_props(format!("invalid size {}", value)))
+}
+
+/// Formats a size for messages, in MiB (or KiB below 1 MiB, bytes below 1 KiB).
+pub(crate) fn format_size(bytes: u64) -> String {
+    let (unit, name) = match bytes {
+        0..1024 => return format!("{} B", bytes),
+        1024..0x100000 => (1 << 10, "KiB"),
+        _ => (1 << 20, "MiB"),
+    };
+    if bytes.is_multiple_of(unit) {
+        format!("{} {}", bytes / unit, name)
+    } else {
+        format!("{:.1} {}", bytes as f64 / unit as f64, name)
+    }
+}
+
+/// How the LZMA encoder finds repeated data, selected with `--match-finder`.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchFinder {
+    /// Hash chain on 2- and 3-byte sequences (fastest, presets 0 and 1)
+    Hc3,
+    /// Hash chain on 2-, 3- and 4-byte sequences (presets 2 and 3)
+    Hc4,
+    /// Binary tree on 2-byte sequences
+    Bt2,
+    /// Binary tree on 2- and 3-byte sequences
+    Bt3,
+    /// Binary tree on 2-, 3- and 4-byte sequences (best ratio, presets 4-9)
+    Bt4,
+}
+
+impl MatchFinder {
+    fn name(self) -> &'static str {
+        match self {
+            MatchFinder::Hc3 => "hc3",
+            MatchFinder::Hc4 => "hc4",
+            MatchFinder::Bt2 => "bt2",
+            MatchFinder::Bt3 => "bt3",
+            MatchFinder::Bt4 => "bt4",
+        }
+    }
+
+    /// The shortest match the finder can report, which `nice_len` must not go below.
+    fn min_len(self) -> u32 {
+        match self {
+            MatchFinder::Bt2 => 2,
+            MatchFinder::Hc3 | MatchFinder::Bt3 => 3,
+            MatchFinder::Hc4 | MatchFinder::Bt4 => 4,
+        }
+    }
+
+    fn to_liblzma(self) -> stream::MatchFinder {
+        match self {
+            MatchFinder::Hc3 => stream::MatchFinder::HashChain3,
+            MatchFinder::Hc4 => stream::MatchFinder::HashChain4,
+            MatchFinder::Bt2 => stream::MatchFinder::BinaryTree2,
+            MatchFinder::Bt3 => stream::MatchFinder::BinaryTree3,
+            MatchFinder::Bt4 => stream::MatchFinder::BinaryTree4,
+        }
+    }
+}
+
+/// Adjustments to the LZMA2 encoder settings picked by the compression level. Anything
+/// left unset keeps the preset's value, so the default changes nothing.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LzmaTuning {
+    /// Use the slower extreme variant of the preset (like `xz --extreme`).
+    pub extreme: bool,
+    /// Dictionary size in bytes (4 KiB - 1.5 GiB). Larger dictionaries find repeats
+    /// further apart, at the cost of memory on both sides.
+    pub dict_size: Option<u32>,
+    /// Literal context bits (0-4, lc + lp at most 4).
+    pub lc: Option<u32>,
+    /// Literal position bits (0-4).
+    pub lp: Option<u32>,
+    /// Position bits (0-4).
+    pub pb: Option<u32>,
+    /// Match length at which the encoder stops looking for a longer one (2-273).
+    pub nice_len: Option<u32>,
+    /// Match finder.
+    pub match_finder: Option<MatchFinder>,
+}
+
+impl LzmaTuning {
+    /// Whether nothing is tuned, so the plain preset applies.
+    pub fn is_default(&self) -> bool {
+        *self == LzmaTuning::default()
+    }
+
+    /// Checks the settings against liblzma's limits at compression level `level`.
+    pub fn check(&self, level: u32) -> io::Result<()> {
+        self.options(level).map(drop)
+    }
+
+    /// Encoder options: the preset for `level` (and `extreme`) with the tuned values applied.
+    ///
+    /// # Returns
+    /// * `io::Result<LzmaOptions>` - The options; `InvalidInput` naming the offending
+    ///   setting if liblzma would reject them.
+    pub fn options(&self, level: u32) -> io::Result<LzmaOptions> {
+        let lc = self.lc.unwrap_or(LzmaProps::default().lc);
+        let lp = self.lp.unwrap_or(LzmaProps::default().lp);
+        for (name, value) in [("lc", self.lc), ("lp", self.lp), ("pb", self.pb)] {
+            if let Some(value) = value
+                && value > 4
+            {
+                return Err(invalid_props(format!("{} must be 0-4 for LZMA2 (got {})", name, value)));
+            }
+        }
+        if lc + lp > 4 {
+            return Err(invalid_props(format!(
+                "lc + lp must not exceed 4 for LZMA2 (got lc={}, lp={}{})",
+                lc,
+                lp,
+                if self.lc.is_none() { "; lc defaults to 3" } else { "" }
+            )));
+        }
+        if let Some(size) = self.dict_size
+            && !(DICT_SIZE_MIN..=DICT_SIZE_MAX).contains(&size)
+        {
+            return Err(invalid_props(format!(
+                "the dictionary size must be between 4 KiB and 1536 MiB (got {} bytes)",
+                size
+            )));
+        }
+        if let Some(nice_len) = self.nice_len {
+            // Presets 0-3 use hash chains (hc3 only at 0); extreme and 4-9 use bt4.
+            let finder = self.match_finder.unwrap_or(match level {
+                0 if !self.extreme => MatchFinder::Hc3,
+                0..=3 if !self.extreme => MatchFinder::Hc4,
+                _ => MatchFinder::Bt4,
+            });
+            if !(finder.min_len()..=NICE_LEN_MAX).contains(&nice_len) {
+                return Err(invalid_props(format!(
+                    "the nice length must be between {} and {} with the {} match finder (got {})",
+                    finder.min_len(),
+                    NICE_LEN_MAX,
+                    finder.name(),
+                    nice_len
+                )));
+            }
+        }
+
+        let preset = if self.extreme { level | PRESET_EXTREME } else { level };
+        let mut options = LzmaOptions::new_preset(preset).map_err(io::Error::other)?;
+        options.literal_context_bits(lc).literal_position_bits(lp);
+        if let Some(pb) = self.pb {
+            options.position_bits(pb);
+        }
+        if let Some(size) = self.dict_size {
+            options.dict_size(size);
+        }
+        if let Some(nice_len) = self.nice_len {
+            options.nice_len(nice_len);
+        }
+        if let Some(finder) = self.match_finder {
+            options.match_finder(finder.to_liblzma());
+        }
+        Ok(options)
+    }
+}
+
+/// The integrity check stored with each XZ block, selected with `--check`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum IntegrityCheck {
+    /// No check (fastest; corruption is only caught if it breaks decoding)
+    None,
+    /// CRC-32
+    Crc32,
+    /// CRC-64, as xz writes by default
+    #[default]
+    Crc64,
+    /// SHA-256
+    Sha256,
+}
+
+impl IntegrityCheck {
+    fn to_liblzma(self) -> Check {
+        match self {
+            IntegrityCheck::None => Check::None,
+            IntegrityCheck::Crc32 => Check::Crc32,
+            IntegrityCheck::Crc64 => Check::Crc64,
+            IntegrityCheck::Sha256 => Check::Sha256,
+        }
+    }
+}
+
+/// A filter run in front of LZMA2 that makes the data easier to compress. The BCJ filters
+/// turn relative branch addresses in machine code into absolute ones, so repeated calls
+/// to the same function look alike; delta stores each byte as the difference from the
+/// one `distance` bytes earlier, which suits sampled data such as uncompressed audio.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Filter {
+    /// x86 and x86-64 code
+    X86,
+    /// 32-bit ARM code
+    Arm,
+    /// ARM Thumb and Thumb-2 code
+    ArmThumb,
+    /// ARM64 (AArch64) code
+    Arm64,
+    /// Big-endian PowerPC code
+    PowerPc,
+    /// SPARC code
+    Sparc,
+    /// RISC-V code
+    RiscV,
+    /// Byte-wise delta with the given distance (1-256), e.g. 4 for 16-bit stereo samples
+    Delta(u32),
+}
+
+impl Filter {
+    /// Adds the filter to `filters`.
+    fn push(self, filters: &mut Filters) -> io::Result<()> {
+        match self {
+            Filter::X86 => filters.x86(),
+            Filter::Arm => filters.arm(),
+            Filter::ArmThumb => filters.arm_thumb(),
+            Filter::Arm64 => filters.arm64(),
+            Filter::PowerPc => filters.powerpc(),
+            Filter::Sparc => filters.sparc(),
+            Filter::RiscV => filters.riscv(),
+            Filter::Delta(distance) => {
+                if !(1..=256).contains(&distance) {
+                    return Err(invalid_props(format!(
+                        "the delta distance must be between 1 and 256 (got {})",
+                        distance
+                    )));
+                }
+                // The property byte stores the distance minus one.
+                filters.delta_properties(&[(distance - 1) as u8]).map_err(io::Error::other)?
+            }
+        };
+        Ok(())
+    }
+
+    /// The BCJ filter for the executable that starts with `head`: an ELF or PE file built
+    /// for an architecture a filter exists for.
+    pub fn detect_bcj(head: &[u8]) -> Option<Filter> {
+        let u16_at = |offset: usize, big_endian: bool| {
+            let bytes: [u8; 2] = head.get(offset..offset + 2)?.try_into().ok()?;
+            Some(if big_endian { u16::from_be_bytes(bytes) } else { u16::from_le_bytes(bytes) })
+        };
+        if head.starts_with(b"\x7fELF") {
+            let big_endian = *head.get(5)? == 2;
+            return match u16_at(18, big_endian)? {
+                3 | 62 => Some(Filter::X86),
+                40 => Some(Filter::Arm),
+                183 => Some(Filter::Arm64),
+                20 | 21 if big_endian => Some(Filter::PowerPc),
+                2 | 18 | 43 => Some(Filter::Sparc),
+                243 => Some(Filter::RiscV),
+                _ => None,
+            };
+        }
+        if head.starts_with(b"MZ") {
+            let pe_offset = u32::from_le_bytes(head.get(0x3c..0x40)?.try_into().ok()?) as usize;
+            if head.get(pe_offset..pe_offset.checked_add(4)?)? != b"PE\0\0" {
+                return None;
+            }
+            return match u16_at(pe_offset + 4, false)? {
+                0x014c | 0x8664 => Some(Filter::X86),
+                0x01c0 => Some(Filter::Arm),
+                0x01c2 | 0x01c4 => Some(Filter::ArmThumb),
+                0xaa64 => Some(Filter::Arm64),
+                _ => None,
+            };
+        }
+        None
+    }
+
+    /// The filter's name, as accepted by `--filters`.
+    fn name(self) -> &'static str {
+        match self {
+            Filter::X86 => "x86",
+            Filter::Arm => "arm",
+            Filter::ArmThumb => "armthumb",
+            Filter::Arm64 => "arm64",
+            Filter::PowerPc => "powerpc",
+            Filter::Sparc => "sparc",
+            Filter::RiscV => "riscv",
+            Filter::Delta(_) => "delta",
+        }
+    }
+}
+
+impl fmt::Display for Filter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Filter::Delta(distance) => write!(f, "delta:{}", distance),
+            _ => f.write_str(self.name()),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = io::Error;
+
+    /// Parses a filter name (`x86`, `arm`, `armthumb`, `arm64`, `powerpc`, `sparc`,
+    /// `riscv`), or `delta:N` for a delta filter with distance N (`delta` alone means 1).
+    fn from_str(s: &str) -> io::Result<Self> {
+        let s = s.trim().to_ascii_lowercase();
+        let (name, distance) = match s.split_once(':') {
+            Some((name, distance)) => (name, Some(distance)),
+            None => (s.as_str(), None),
+        };
+        let filter = match name {
+            "x86" => Filter::X86,
+            "arm" => Filter::Arm,
+            "armthumb" | "arm-thumb" => Filter::ArmThumb,
+            "arm64" | "aarch64" => Filter::Arm64,
+            "powerpc" | "ppc" => Filter::PowerPc,
+            "sparc" => Filter::Sparc,
+            "riscv" => Filter::RiscV,
+            "delta" => {
+                let distance = match distance {
+                    Some(distance) => distance
+                        .parse::<u32>()
+                        .ok()
+                        .filter(|d| (1..=256).contains(d))
+                        .ok_or_else(|| {
+                            invalid_props(format!("the delta distance must be between 1 and 256 (got {})", distance))
+                        })?,
+                    None => 1,
+                };
+                return Ok(Filter::Delta(distance));
+            }
+            _ => {
+                return Err(invalid_props(format!(
+                    "unknown filter {} (expected x86, arm, armthumb, arm64, powerpc, sparc, riscv or delta:N)",
+                    name
+                )));
+            }
+        };
+        if distance.is_some() {
+            return Err(invalid_props(format!("the {} filter takes no options", name)));
+        }
+        Ok(filter)
+    }
+}
+
+/// The XZ codec with [`LzmaTuning`] applied to its LZMA2 filter, optionally behind a chain
+/// of [`Filter`]s, and a choice of integrity check. It writes ordinary `.xz` streams under the built-in codec's id and
+/// name, so it can stand in for it anywhere, including RSZ blocks, and the output is read
+/// back without knowing the settings.
+#[derive(Clone, Debug, Default)]
+pub struct TunedXz {
+    pub tuning: LzmaTuning,
+    /// Filters run in front of LZMA2, in order (at most 3).
+    pub filters: Vec<Filter>,
+    /// Put the matching BCJ filter in front of LZMA2 for ELF and PE executables (see
+    /// [`Codec::variant_for`]); other data is compressed with `filters`.
+    pub auto_bcj: bool,
+    /// The check stored with the data; decoders verify it.
+    pub check: IntegrityCheck,
+    /// Start a new XZ block after this many bytes of input, so that
+    /// [`XzSeekableReader`](crate::seekable::XzSeekableReader) can decode any part of the
+    /// data without the blocks before it. `None` writes a single block.
+    pub block_size: Option<u64>,
+}
+
+impl TunedXz {
+    pub fn new(tuning: LzmaTuning) -> Self {
+        TunedXz {
+            tuning,
+            ..TunedXz::default()
+        }
+    }
+
+    /// Whether this is the plain XZ codec: no tuning, filters or BCJ detection, CRC-64 and
+    /// a single block.
+    pub fn is_default(&self) -> bool {
+        self.tuning.is_default()
+            && self.filters.is_empty()
+            && !self.auto_bcj
+            && self.check == IntegrityCheck::Crc64
+            && self.block_size.is_none()
+    }
+
+    /// Checks the settings against liblzma's limits at compression level `level`.
+    pub fn check(&self, level: u32) -> io::Result<()> {
+        self.filter_chain(level).map(drop)
+    }
+
+    /// The filter chain: `filters`, then LZMA2 with the tuned options.
+    fn filter_chain(&self, level: u32) -> io::Result<Filters> {
+        if self.filters.len() > MAX_FILTERS {
+            return Err(invalid_props(format!(
+                "at most {} filters can run in front of LZMA2 (got {})",
+                MAX_FILTERS,
+                self.filters.len()
+            )));
+        }
+        let mut filters = Filters::new();
+        for filter in &self.filters {
+            filter.push(&mut filters)?;
+        }
+        filters.lzma2(&self.tuning.options(level)?);
+        Ok(filters)
+    }
+}
+
+impl Codec for TunedXz {
+    fn id(&self) -> u8 {
+        Builtin::Xz.id()
+    }
+
+    fn name(&self) -> &str {
+        Builtin::Xz.name()
+    }
+
+    fn matches(&self, header: &[u8]) -> bool {
+        Builtin::Xz.matches(header)
+    }
+
+    fn encoder<'a>(&self, writer: Box<dyn Write + 'a>, level: u32) -> io::Result<Box<dyn Encoder + 'a>> {
+        if level > 9 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Compression level must be between 0 and 9",
+            ));
+        }
+        let stream = Stream::new_stream_encoder(&self.filter_chain(level)?, self.check.to_liblzma()).map_err(io::Error::other)?;
+        let encoder = XzEncoder::new_stream(writer, stream);
+        Ok(match self.block_size {
+            Some(block_size) => Box::new(BlockSplitter { encoder, block_size, in_block: 0 }),
+            None => Box::new(encoder),
+        })
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        Builtin::Xz.decoder(reader)
+    }
+
+    fn decoder_with_memlimit<'a>(&self, reader: Box<dyn Read + 'a>, memlimit: u64) -> io::Result<Box<dyn Read + 'a>> {
+        Builtin::Xz.decoder_with_memlimit(reader, memlimit)
+    }
+
+    fn variant_for(&self, head: &[u8]) -> Option<(&'static str, Arc<dyn Codec>)> {
+        if !self.auto_bcj {
+            return None;
+        }
+        let filter = Filter::detect_bcj(head)?;
+        let variant = TunedXz {
+            tuning: self.tuning,
+            filters: vec![filter],
+            auto_bcj: false,
+            check: self.check,
+            block_size: self.block_size,
+        };
+        Some((filter.name(), Arc::new(variant)))
+    }
+}
+
+/// A headerless LZMA1 or LZMA2 stream, as used by bootloaders and firmware images. Nothing
+/// in the stream identifies it, so it is never detected and cannot be registered; pass it
+/// to single-file compression (`CompressOptions::codec`) and decompression
+/// (`ExtractOptions::codec`) explicitly, with the same properties on both sides.
+#[derive(Clone, Copy, Debug)]
+pub struct RawLzma {
+    format: RawFormat,
+    props: LzmaProps,
+}
+
+impl RawLzma {
+    pub fn new(format: RawFormat, props: LzmaProps) -> Self {
+        RawLzma { format, props }
+    }
+
+    fn filters(&self, preset: u32) -> io::Result<Filters> {
+        self.props.check(self.format)?;
+        let options = self.props.options(preset)?;
+        let mut filters = Filters::new();
+        match self.format {
+            RawFormat::Lzma1 => filters.lzma1(&options),
+            RawFormat::Lzma2 => filters.lzma2(&options),
+        };
+        Ok(filters)
+    }
+}
+
+impl Codec for RawLzma {
+    fn id(&self) -> u8 {
+        match self.format {
+            RawFormat::Lzma1 => 7,
+            RawFormat::Lzma2 => 8,
+        }
+    }
+
+    fn name(&self) -> &str {
+        match self.format {
+            RawFormat::Lzma1 => "lzma1-raw",
+            RawFormat::Lzma2 => "lzma2-raw",
+        }
+    }
+
+    fn extension(&self) -> &str {
+        match self.format {
+            RawFormat::Lzma1 => "lzma1",
+            RawFormat::Lzma2 => "lzma2",
+        }
+    }
+
+    fn encoder<'a>(&self, writer: Box<dyn Write + 'a>, level: u32) -> io::Result<Box<dyn Encoder + 'a>> {
+        if level > 9 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Compression level must be between 0 and 9",
+            ));
+        }
+        let stream = Stream::new_raw_encoder(&self.filters(level)?).map_err(io::Error::other)?;
+        Ok(Box::new(XzEncoder::new_stream(writer, stream)))
+    }
+
+    fn decoder<'a>(&self, reader: Box<dyn Read + 'a>) -> io::Result<Box<dyn Read + 'a>> {
+        let stream = Stream::new_raw_decoder(&self.filters(6)?).map_err(io::Error::other)?;
+        Ok(Box::new(RawDecoder(XzDecoder::new_stream(BufReader::new(reader), stream))))
+    }
+
+    fn decoder_with_memlimit<'a>(&self, reader: Box<dyn Read + 'a>, memlimit: u64) -> io::Result<Box<dyn Read + 'a>> {
+        // liblzma's raw decoder takes no limit, but the dictionary is most of what it needs.
+        if u64::from(self.props.dict_size) > memlimit {
+            return Err(memlimit_exceeded(self.props.dict_size.into(), memlimit));
+        }
+        self.decoder(reader)
+    }
+}
+
+/// Ends the current XZ block every `block_size` bytes of input. liblzma starts each block
+/// with an empty dictionary, so smaller blocks cost some compression.
+struct BlockSplitter<W: Write> {
+    encoder: XzEncoder<W>,
+    block_size: u64,
+    in_block: u64,
+}
+
+impl<W: Write> Write for BlockSplitter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let room = (self.block_size - self.in_block).min(buf.len() as u64) as usize;
+        let written = self.encoder.write(&buf[..room])?;
+        self.in_block += written as u64;
+        if self.in_block == self.block_size {
+            // A full flush is how liblzma's stream encoder ends a block.
+            self.flush()?;
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.in_block = 0;
+        self.encoder.flush()
+    }
+}
+
+impl<W: Write> Encoder for BlockSplitter<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        self.encoder.finish().map(drop)
+    }
+}
+
+/// An XZ or `.lzma` decoder that may use at most `memlimit` bytes. When the data needs
+/// more, the error says how much.
+pub(crate) struct LimitedDecoder<R: BufRead> {
+    reader: R,
+    stream: Stream,
+    memlimit: u64,
+    ended: bool,
+}
+
+impl<R: BufRead> LimitedDecoder<R> {
+    /// Decodes XZ data to the end of `reader`: one stream, or several concatenated ones
+    /// (as `cat a.xz b.xz` makes) with any stream padding between them.
+    pub(crate) fn xz(reader: R, memlimit: u64) -> io::Result<Self> {
+        let stream = Stream::new_stream_decoder(memlimit, stream::CONCATENATED).map_err(io::Error::other)?;
+        Ok(LimitedDecoder::new(reader, stream, memlimit))
+    }
+
+    /// Decodes a single XZ stream, leaving whatever follows it unread in `reader`.
+    pub(crate) fn xz_single(reader: R, memlimit: u64) -> io::Result<Self> {
+        let stream = Stream::new_stream_decoder(memlimit, 0).map_err(io::Error::other)?;
+        Ok(LimitedDecoder::new(reader, stream, memlimit))
+    }
+
+    /// Decodes a `.lzma` (LZMA_Alone) stream.
+    pub(crate) fn lzma_alone(reader: R, memlimit: u64) -> io::Result<Self> {
+        let stream = Stream::new_lzma_decoder(memlimit).map_err(io::Error::other)?;
+        Ok(LimitedDecoder::new(reader, stream, memlimit))
+    }
+
+    fn new(reader: R, stream: Stream, memlimit: u64) -> Self {
+        LimitedDecoder { reader, stream, memlimit, ended: false }
+    }
+
+    fn memlimit_error(&mut self) -> io::Error {
+        // liblzma doesn't report how much memory it wanted, but it refuses any new limit
+        // below that amount, so look for the smallest limit it accepts.
+        let (mut refused, mut accepted) = (self.memlimit, u64::MAX);
+        while accepted - refused > 1 {
+            let limit = refused + (accepted - refused) / 2;
+            if self.stream.set_memlimit(limit).is_ok() {
+                accepted = limit;
+            } else {
+                refused = limit;
+            }
+        }
+        memlimit_exceeded(accepted, self.memlimit)
+    }
+}
+
+impl<R: BufRead> Read for LimitedDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.ended {
+            return Ok(0);
+        }
+        loop {
+            let input = self.reader.fill_buf()?;
+            let eof = input.is_empty();
+            let (before_in, before_out) = (self.stream.total_in(), self.stream.total_out());
+            let action = if eof { Action::Finish } else { Action::Run };
+            let result = self.stream.process(input, buf, action);
+            let consumed = (self.stream.total_in() - before_in) as usize;
+            let read = (self.stream.total_out() - before_out) as usize;
+            self.reader.consume(consumed);
+
+            let status = match result {
+                Err(stream::Error::MemLimit) => return Err(self.memlimit_error()),
+                result => result?,
+            };
+            if status == Status::StreamEnd {
+                self.ended = true;
+                return Ok(read);
+            }
+            if read > 0 {
+                return Ok(read);
+            }
+            if eof {
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "compressed data is truncated"));
+            }
+            if consumed == 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupt xz stream"));
+            }
+        }
+    }
+}
+
+/// The error for data that needs `needed` bytes of memory to decode, over `memlimit`.
+pub(crate) fn memlimit_exceeded(needed: u64, memlimit: u64) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::OutOfMemory,
+        format!(
+            "decompressing needs {} of memory, more than the {} limit (raise it with --memlimit)",
+            format_size(needed),
+            format_size(memlimit)
+        ),
+    )
+}
+
+/// Fails if input is left over once the stream has ended. A raw stream read with the
+/// wrong format or properties often ends at once (an LZMA1 stream starts with the byte
+/// that ends an LZMA2 one), which would otherwise pass as empty output.
+struct RawDecoder<R: BufRead>(XzDecoder<R>);
+
+impl<R: BufRead> Read for RawDecoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.0.read(buf)?;
+        if n == 0 && !buf.is_empty() && !self.0.get_mut().fill_buf()?.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "data continues after the end of the raw LZMA stream (wrong --raw format or properties?)",
+            ));
+        }
+        Ok(n)
+    }
+}
+
+fn parse_hex(hex: &str) -> Option<Vec<u8>> {
+    if hex.is_empty() || !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn invalid_props(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
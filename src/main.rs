@@ -1,46 +1,460 @@
-pub mod cli;
-pub mod compression;
-pub mod decompression;
-pub mod file_io;
+use rs_zip::batch;
+use rs_zip::cli::{ArchiveFormat, Cli, Command};
+use rs_zip::codec::Codec;
+use rs_zip::compression::{self, CompressOptions};
+use rs_zip::convert;
+use rs_zip::crypto::Identity;
+use rs_zip::decompression::{self, ArchiveListing, ExtractOptions};
+use rs_zip::file_io::{self, OverwritePolicy};
+use rs_zip::progress::{Progress, TerminalProgress};
+use rs_zip::signature::SigningKey;
+use rs_zip::sevenzip;
+use rs_zip::tarball;
+use rs_zip::zipfile;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
 
-use cli::Cli;
-use std::fs::File;
-use std::io;
+fn main() -> ExitCode {
+    match run() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("rs-zip: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
 
-fn main() -> io::Result<()> {
+fn run() -> io::Result<()> {
     let args = Cli::parse_args();
+    if let Some(Command::Keygen { output, signing }) = &args.command {
+        return keygen(output.as_deref(), *signing, args.overwrite_policy());
+    }
+    if let Some(Command::Info { files, json }) = &args.command {
+        return info(files, *json);
+    }
     args.validate()?;
+    if let Some(Command::Convert { .. }) = &args.command {
+        return convert(&args);
+    }
 
     if args.decompress {
-        if args.format.to_lowercase() == "rsz" {
+        let options = ExtractOptions {
+            overwrite: args.overwrite_policy(),
+            remove_source: args.rm,
+            progress: None,
+            password: None,
+            identities: args.identities()?,
+            signers: args.signers()?,
+            codec: args.raw.map(|_| Arc::clone(&args.codec)),
+            memlimit: args.memlimit,
+        };
+        if args.format == ArchiveFormat::Tar {
+            let archive_path = args.inputs.first().unwrap().to_str().unwrap();
+            let bar = TerminalProgress::new(None).map(Arc::new);
+            let options = ExtractOptions {
+                progress: bar.clone().map(|bar| bar as Arc<dyn Progress>),
+                ..options
+            };
+            let result = match &args.command {
+                Some(Command::List { .. }) => tarball::list_tar_archive(archive_path, &options).map(|listing| print_listing(&listing)),
+                Some(Command::Test { .. }) => {
+                    tarball::test_tar_archive(archive_path, &options).map(|listing| print_test_result(archive_path, &listing))
+                }
+                _ => tarball::extract_tar_archive(archive_path, &options),
+            };
+            finish(bar.as_deref());
+            result
+        } else if args.format == ArchiveFormat::Zip {
+            let archive_path = args.inputs.first().unwrap().to_str().unwrap();
+            let bar = TerminalProgress::new(None).map(Arc::new);
+            let options = ExtractOptions {
+                progress: bar.clone().map(|bar| bar as Arc<dyn Progress>),
+                ..options
+            };
+            let result = match &args.command {
+                Some(Command::List { .. }) => zipfile::list_zip_archive(archive_path).map(|listing| print_listing(&listing)),
+                Some(Command::Test { .. }) => {
+                    zipfile::test_zip_archive(archive_path, &options).map(|listing| print_test_result(archive_path, &listing))
+                }
+                _ => zipfile::extract_zip_archive(archive_path, &options),
+            };
+            finish(bar.as_deref());
+            result
+        } else if args.format == ArchiveFormat::SevenZ {
+            let archive_path = args.inputs.first().unwrap().to_str().unwrap();
+            let password = if sevenzip::is_encrypted_7z_archive(archive_path)? {
+                Some(args.read_password(false)?)
+            } else {
+                None
+            };
+            let bar = TerminalProgress::new(None).map(Arc::new);
+            let options = ExtractOptions {
+                progress: bar.clone().map(|bar| bar as Arc<dyn Progress>),
+                password,
+                ..options
+            };
+            let result = match &args.command {
+                Some(Command::List { .. }) => sevenzip::list_7z_archive(archive_path, options.password.as_ref())
+                    .map(|listing| print_listing(&listing)),
+                Some(Command::Test { .. }) => sevenzip::test_7z_archive(archive_path, &options)
+                    .map(|listing| print_test_result(archive_path, &listing)),
+                _ => sevenzip::extract_7z_archive(archive_path, &options),
+            };
+            finish(bar.as_deref());
+            result
+        } else if args.format == ArchiveFormat::Rsz {
             // RSZ mode: use the archive input from args.inputs[0]
             let archive_path = args.inputs.first().unwrap().to_str().unwrap();
-            decompression::extract_archive(archive_path)
+            // Identities alone are enough; only ask for a password when there are none.
+            let encrypted = decompression::is_encrypted_archive(archive_path)?;
+            let password = if encrypted && (args.password_file.is_some() || args.identity.is_empty()) {
+                Some(args.read_password(false)?)
+            } else {
+                None
+            };
+            let bar = TerminalProgress::new(None).map(Arc::new);
+            let options = ExtractOptions {
+                progress: bar.clone().map(|bar| bar as Arc<dyn Progress>),
+                password,
+                ..options
+            };
+            let result = match &args.command {
+                Some(Command::List { .. }) => {
+                    decompression::list_archive(archive_path, &options).map(|listing| print_listing(&listing))
+                }
+                Some(Command::Test { .. }) => decompression::test_archive(archive_path, &options)
+                    .map(|listing| print_test_result(archive_path, &listing)),
+                _ => decompression::extract_archive(archive_path, &options),
+            };
+            finish(bar.as_deref());
+            result
         } else {
-            // XZ mode: if no output is provided, derive a default one.
-            let input_path = args.inputs.first().unwrap().to_str().unwrap();
-            let output_path = match &args.output {
-                Some(path) => path.to_str().unwrap().to_string(),
-                None => crate::file_io::default_output_path(input_path, false),
-            };
-            let input_file = File::open(input_path)?;
-            let mut output_file = file_io::create_output_file(&output_path)?;
-            decompression::decompress_lzma(input_file, &mut output_file)
+            // XZ mode: decompress every input to its own output.
+            let jobs = xz_jobs(&args, None);
+            let bar = batch_progress(&jobs);
+            let results =
+                batch::run_parallel(&jobs, batch::resolve_jobs(args.jobs), |(input, output)| {
+                    let options = ExtractOptions {
+                        progress: child_progress(bar.as_ref()),
+                        ..options.clone()
+                    };
+                    file_io::check_input_file(input)?;
+                    match args.range {
+                        Some(range) => decompression::decompress_range(input, output, range, &options),
+                        None => decompression::decompress_file(input, output, &options),
+                    }
+                });
+            finish(bar.as_ref());
+            report_batch(&jobs, results)
         }
     } else {
         // Compression branch
-        if args.format.to_lowercase() == "rsz" {
+        let options = CompressOptions {
+            level: args.level,
+            codec: Arc::clone(&args.codec),
+            overwrite: args.overwrite_policy(),
+            remove_sources: args.rm || args.remove_files,
+            progress: None,
+            password: None,
+            recipients: args.recipients()?,
+            encrypt_index: args.encrypt_index,
+            signing_key: args.signing_key()?,
+            zip_method: args.zip_method.unwrap_or_default(),
+            append: args.append,
+            store: args.store_rules(),
+            solid: args.solid.unwrap_or_default(),
+            order: args.order.unwrap_or_default(),
+            dedup: args.dedup.unwrap_or_default(),
+        };
+        if args.format == ArchiveFormat::Tar {
+            let files = file_io::collect_files(&args.inputs, args.recursive)?;
+            let output_path = args.output.as_ref().unwrap().to_str().unwrap();
+            let bar = TerminalProgress::new(None).map(Arc::new);
+            let options = CompressOptions {
+                progress: bar.clone().map(|bar| bar as Arc<dyn Progress>),
+                ..options
+            };
+            let result = tarball::create_tar_archive(&files, output_path, &options);
+            finish(bar.as_deref());
+            result
+        } else if args.format == ArchiveFormat::Zip {
+            let files = file_io::collect_files(&args.inputs, args.recursive)?;
+            let output_path = args.output.as_ref().unwrap().to_str().unwrap();
+            let bar = TerminalProgress::new(None).map(Arc::new);
+            let options = CompressOptions {
+                progress: bar.clone().map(|bar| bar as Arc<dyn Progress>),
+                ..options
+            };
+            let result = zipfile::create_zip_archive(&files, output_path, &options);
+            finish(bar.as_deref());
+            result
+        } else if args.format == ArchiveFormat::Rsz {
             // RSZ format: support multiple files using the custom archive format.
             let files = file_io::collect_files(&args.inputs, args.recursive)?;
             let output_path = args.output.as_ref().unwrap().to_str().unwrap();
-            compression::create_padded_archive(&files, output_path, args.level)
+            let password = if args.encrypt {
+                Some(args.read_password(true)?)
+            } else {
+                None
+            };
+            let bar = TerminalProgress::new(None).map(Arc::new);
+            let options = CompressOptions {
+                progress: bar.clone().map(|bar| bar as Arc<dyn Progress>),
+                password,
+                ..options
+            };
+            let result = compression::create_padded_archive(&files, output_path, &options);
+            finish(bar.as_deref());
+            if let Some(stats) = result?.filter(|stats| !stats.is_empty()) {
+                eprintln!("{}: {}", output_path, stats);
+            }
+            Ok(())
         } else {
-            // XZ format: compress a single file.
-            let input_path = args.inputs.first().unwrap().to_str().unwrap();
-            let mut input_file = File::open(input_path)?;
-            let output_path = args.output.as_ref().unwrap().to_str().unwrap();
-            let output_file = file_io::create_output_file(output_path)?;
-            compression::compress_lzma(&mut input_file, output_file, args.level)
+            // XZ format: compress every input to its own output.
+            let jobs = xz_jobs(&args, Some(args.codec.as_ref()));
+            let bar = batch_progress(&jobs);
+            let results =
+                batch::run_parallel(&jobs, batch::resolve_jobs(args.jobs), |(input, output)| {
+                    let options = CompressOptions {
+                        progress: child_progress(bar.as_ref()),
+                        ..options.clone()
+                    };
+                    file_io::check_input_file(input)?;
+                    compression::compress_file(input, output, &options)
+                });
+            finish(bar.as_ref());
+            report_batch(&jobs, results)
+        }
+    }
+}
+
+/// Converts `args.inputs[0]` into `args.output`, then reports what the new format could
+/// not keep.
+fn convert(args: &Cli) -> io::Result<()> {
+    let input = args.inputs.first().unwrap();
+    let output = args.output.as_ref().unwrap();
+    let password = match ArchiveFormat::from_path(input) {
+        ArchiveFormat::SevenZ if sevenzip::is_encrypted_7z_archive(input)? => Some(args.read_password(false)?),
+        // Identities alone are enough; only ask for a password when there are none.
+        ArchiveFormat::Rsz
+            if decompression::is_encrypted_archive(input)?
+                && (args.password_file.is_some() || args.identity.is_empty()) =>
+        {
+            Some(args.read_password(false)?)
         }
+        _ => None,
+    };
+    let read = ExtractOptions {
+        overwrite: args.overwrite_policy(),
+        remove_source: false,
+        progress: None,
+        password,
+        identities: args.identities()?,
+        signers: args.signers()?,
+        codec: None,
+        memlimit: args.memlimit,
+    };
+    let password = if args.encrypt {
+        Some(args.read_password(true)?)
+    } else {
+        None
+    };
+    let bar = TerminalProgress::new(None).map(Arc::new);
+    let write = CompressOptions {
+        level: args.level,
+        codec: Arc::clone(&args.codec),
+        overwrite: args.overwrite_policy(),
+        remove_sources: false,
+        progress: bar.clone().map(|bar| bar as Arc<dyn Progress>),
+        password,
+        recipients: args.recipients()?,
+        encrypt_index: args.encrypt_index,
+        signing_key: args.signing_key()?,
+        zip_method: args.zip_method.unwrap_or_default(),
+        append: false,
+        store: args.store_rules(),
+        solid: args.solid.unwrap_or_default(),
+        order: args.order.unwrap_or_default(),
+        dedup: args.dedup.unwrap_or_default(),
+    };
+    let result = convert::convert_archive(input, output, &read, &write);
+    finish(bar.as_deref());
+    let Some(report) = result? else {
+        return Ok(());
+    };
+
+    eprintln!("{} -> {}: {}", input.display(), output.display(), report);
+    for entry in &report.skipped {
+        eprintln!("rs-zip: warning: skipped {}, which {} cannot store", entry, output.display());
+    }
+    if report.lost_directories > 0 {
+        eprintln!(
+            "rs-zip: warning: {} cannot store directories; dropped {} directory entries (their files are kept)",
+            output.display(),
+            report.lost_directories
+        );
+    }
+    let dropped = [
+        (report.lost_modes, "permissions"),
+        (report.lost_times, "modification times"),
+        (report.lost_owners, "owners"),
+    ];
+    for (count, what) in dropped.into_iter().filter(|(count, _)| *count > 0) {
+        eprintln!(
+            "rs-zip: warning: {} cannot store {}; dropped them from {} entries",
+            output.display(),
+            what,
+            count
+        );
+    }
+    Ok(())
+}
+
+/// Generates a new identity (or, with `signing`, a signing key) and writes it to `output`
+/// (or stdout), printing the matching public key to stderr.
+fn keygen(output: Option<&Path>, signing: bool, policy: OverwritePolicy) -> io::Result<()> {
+    let (secret, public) = if signing {
+        let key = SigningKey::generate();
+        (key.to_secret_string(), key.verifying_key().to_string())
+    } else {
+        let identity = Identity::generate();
+        (identity.to_secret_string(), identity.to_public().to_string())
+    };
+    let contents = format!("# public key: {}\n{}\n", public, secret.as_str());
+    let contents = zeroize::Zeroizing::new(contents);
+    match output {
+        Some(path) => {
+            let Some(file_io::OutputFile { writer: mut file, .. }) =
+                file_io::create_output_file_with_policy(path, policy, None)?
+            else {
+                return Ok(());
+            };
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                file.get_ref()
+                    .set_permissions(std::fs::Permissions::from_mode(0o600))?;
+            }
+            file.write_all(contents.as_bytes())?;
+            file.flush()?;
+        }
+        None => io::stdout().write_all(contents.as_bytes())?,
+    }
+    eprintln!("Public key: {}", public);
+    Ok(())
+}
+
+/// Prints `rs-zip info` for every file, carrying on past files that can't be read.
+fn info(files: &[PathBuf], json: bool) -> io::Result<()> {
+    let mut failed = 0;
+    let mut objects = Vec::new();
+    for (n, file) in files.iter().enumerate() {
+        match rs_zip::info::xz_info(file) {
+            Ok(info) if json => objects.push(info.to_json(&file.to_string_lossy())),
+            Ok(info) => {
+                if n > 0 {
+                    println!();
+                }
+                println!("{}", file.display());
+                println!("{}", info);
+            }
+            Err(e) => {
+                eprintln!("rs-zip: {}: {}", file.display(), e);
+                failed += 1;
+            }
+        }
+    }
+    if json {
+        println!("[{}]", objects.join(",\n "));
+    }
+    if failed > 0 {
+        return Err(io::Error::other(format!("{} of {} files failed", failed, files.len())));
+    }
+    Ok(())
+}
+
+/// Prints one line per entry (size and name), then the signer on stderr.
+fn print_listing(listing: &ArchiveListing) {
+    for entry in &listing.entries {
+        println!("{:>12}  {}", entry.size, entry.name);
+    }
+    if let Some(signer) = &listing.signer {
+        eprintln!("Signed by {}", signer);
+    }
+}
+
+/// Prints the outcome of a successful `test`.
+fn print_test_result(archive_path: &str, listing: &ArchiveListing) {
+    match &listing.signer {
+        Some(signer) => println!(
+            "{}: OK ({} files, signed by {})",
+            archive_path,
+            listing.entries.len(),
+            signer
+        ),
+        None => println!("{}: OK ({} files, not signed)", archive_path, listing.entries.len()),
+    }
+}
+
+/// Creates one progress bar covering the combined size of all batch inputs.
+fn batch_progress(jobs: &[(PathBuf, PathBuf)]) -> Option<TerminalProgress> {
+    let total = jobs
+        .iter()
+        .filter_map(|(input, _)| input.metadata().ok())
+        .map(|m| m.len())
+        .sum();
+    TerminalProgress::new(Some(total))
+}
+
+/// Gives one batch job its own handle on the shared progress bar.
+fn child_progress(bar: Option<&TerminalProgress>) -> Option<Arc<dyn Progress>> {
+    bar.map(|bar| Arc::new(bar.child()) as Arc<dyn Progress>)
+}
+
+/// Clears the progress bar (if any) so later messages start on a clean line.
+fn finish(bar: Option<&TerminalProgress>) {
+    if let Some(bar) = bar {
+        bar.finish();
+    }
+}
+
+/// Pairs every single-file-mode input with its output path: `--output` for a single input,
+/// otherwise the default name derived from the input (`codec` is `None` when decompressing).
+fn xz_jobs(args: &Cli, codec: Option<&dyn Codec>) -> Vec<(PathBuf, PathBuf)> {
+    args.inputs
+        .iter()
+        .map(|input| {
+            let input_path = input.to_str().unwrap_or_default();
+            let output = match &args.output {
+                Some(path) => path.clone(),
+                // Raw streams are not detected, so only their own suffix is stripped.
+                None if codec.is_none() && args.raw.is_some() => {
+                    PathBuf::from(file_io::strip_codec_suffix(input_path, args.codec.as_ref()))
+                }
+                None => PathBuf::from(file_io::default_output_path(input_path, codec)),
+            };
+            (input.clone(), output)
+        })
+        .collect()
+}
+
+/// Prints one line per failed file and turns any failure into an overall error.
+fn report_batch(jobs: &[(PathBuf, PathBuf)], results: Vec<io::Result<()>>) -> io::Result<()> {
+    let mut failed = 0;
+    for ((input, _), result) in jobs.iter().zip(results) {
+        if let Err(e) = result {
+            eprintln!("rs-zip: {}: {}", input.display(), e);
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        return Err(io::Error::other(format!(
+            "{} of {} files failed",
+            failed,
+            jobs.len()
+        )));
     }
-}
\ No newline at end of file
+    Ok(())
+}
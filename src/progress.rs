@@ -0,0 +1,285 @@
+use std::cell::Cell;
+use std::fmt;
+use std::io::{self, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// A snapshot of how far a compression or extraction has got.
+#[derive(Debug, Clone, Copy)]
+pub struct ProgressUpdate<'a> {
+    /// Name of the entry currently being processed.
+    pub entry: &'a str,
+    /// Zero-based index of the current entry.
+    pub entry_index: u64,
+    /// Total number of entries in this operation.
+    pub entry_count: u64,
+    /// Bytes consumed so far (uncompressed when compressing, compressed when extracting).
+    pub bytes_in: u64,
+    /// Bytes produced so far (compressed when compressing, uncompressed when extracting).
+    pub bytes_out: u64,
+    /// Total input size, when it is known up front.
+    pub total_in: Option<u64>,
+}
+
+/// Receives progress updates from the compression and extraction APIs.
+///
+/// Any `Fn(&ProgressUpdate)` closure that is `Send + Sync` can be used as a progress callback.
+pub trait Progress: Send + Sync {
+    /// Called whenever an entry starts and as data flows through it.
+    fn update(&self, update: &ProgressUpdate);
+
+    /// Called when something is left out rather than failing the operation, such as an entry
+    /// that can't be extracted. The default prints the message to stderr.
+    fn warn(&self, message: &str) {
+        eprintln!("rs-zip: warning: {}", message);
+    }
+}
+
+impl<F> Progress for F
+where
+    F: Fn(&ProgressUpdate) + Send + Sync,
+{
+    fn update(&self, update: &ProgressUpdate) {
+        self(update)
+    }
+}
+
+impl fmt::Debug for dyn Progress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Progress")
+    }
+}
+
+/// Counts the bytes written through it, so compressed output can be reported.
+/// The count is shared, so it stays readable while the writer is borrowed by an encoder.
+pub(crate) struct CountingWriter<W> {
+    inner: W,
+    count: Rc<Cell<u64>>,
+}
+
+impl<W: Write> CountingWriter<W> {
+    pub(crate) fn new(inner: W) -> Self {
+        CountingWriter {
+            inner,
+            count: Rc::new(Cell::new(0)),
+        }
+    }
+
+    pub(crate) fn count(&self) -> u64 {
+        self.count.get()
+    }
+
+    /// Returns a handle that observes the count.
+    pub(crate) fn counter(&self) -> Rc<Cell<u64>> {
+        Rc::clone(&self.count)
+    }
+}
+
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Counts the bytes read through it, so compressed input can be reported.
+/// The count is shared, so it stays readable while the reader is borrowed by a decoder.
+pub(crate) struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> CountingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        CountingReader {
+            inner,
+            count: Rc::new(Cell::new(0)),
+        }
+    }
+
+    /// Returns a handle that observes the count.
+    pub(crate) fn counter(&self) -> Rc<Cell<u64>> {
+        Rc::clone(&self.count)
+    }
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for CountingReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+/// Copies `reader` into `writer` in chunks, calling `on_chunk` with both ends and the
+/// number of bytes copied so far after every chunk.
+pub(crate) fn copy_with_progress<R, W, F>(reader: &mut R, writer: &mut W, mut on_chunk: F) -> io::Result<u64>
+where
+    R: Read + ?Sized,
+    W: Write + ?Sized,
+    F: FnMut(&R, &W, u64),
+{
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut copied = 0u64;
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        writer.write_all(&buffer[..n])?;
+        copied += n as u64;
+        on_chunk(reader, writer, copied);
+    }
+}
+
+/// Keeps the per-operation counters and forwards them to an optional `Progress`.
+pub(crate) struct Tracker<'a> {
+    progress: Option<&'a dyn Progress>,
+    entry: String,
+    entry_index: u64,
+    entry_count: u64,
+    total_in: Option<u64>,
+}
+
+impl<'a> Tracker<'a> {
+    pub(crate) fn new(
+        progress: Option<&'a dyn Progress>,
+        entry_count: u64,
+        total_in: Option<u64>,
+    ) -> Self {
+        Tracker {
+            progress,
+            entry: String::new(),
+            entry_index: 0,
+            entry_count,
+            total_in,
+        }
+    }
+
+    /// Updates the entry count once it is known (e.g. after an archive's index is read).
+    pub(crate) fn set_entry_count(&mut self, entry_count: u64) {
+        self.entry_count = entry_count;
+    }
+
+    /// Marks the start of entry `index` and reports it.
+    pub(crate) fn start_entry(&mut self, index: u64, name: &str, bytes_in: u64, bytes_out: u64) {
+        self.entry_index = index;
+        self.entry.clear();
+        self.entry.push_str(name);
+        self.report(bytes_in, bytes_out);
+    }
+
+    /// Passes a warning about the current entry to the progress callback, or prints it to
+    /// stderr when there is none.
+    pub(crate) fn warn(&self, message: &str) {
+        let message = format!("{}: {}", self.entry, message);
+        match self.progress {
+            Some(progress) => progress.warn(&message),
+            None => eprintln!("rs-zip: warning: {}", message),
+        }
+    }
+
+    /// Reports the current byte counters for the current entry.
+    pub(crate) fn report(&self, bytes_in: u64, bytes_out: u64) {
+        if let Some(progress) = self.progress {
+            progress.update(&ProgressUpdate {
+                entry: &self.entry,
+                entry_index: self.entry_index,
+                entry_count: self.entry_count,
+                bytes_in,
+                bytes_out,
+                total_in: self.total_in,
+            });
+        }
+    }
+}
+
+/// A terminal progress bar on stderr showing bytes, throughput, ETA and the current entry.
+///
+/// Several handles created with [`TerminalProgress::child`] can feed one bar from parallel
+/// operations; each handle tracks its own operation's counters.
+pub struct TerminalProgress {
+    bar: ProgressBar,
+    seen: AtomicU64,
+}
+
+impl TerminalProgress {
+    /// Creates a progress bar, or returns `None` when stderr is not a terminal.
+    ///
+    /// # Arguments
+    /// * `total` - Total number of input bytes, if known; otherwise taken from the first update.
+    pub fn new(total: Option<u64>) -> Option<Self> {
+        if !io::stderr().is_terminal() {
+            return None;
+        }
+        let bar = ProgressBar::new(total.unwrap_or(0));
+        if total.is_none() {
+            bar.unset_length();
+        }
+        bar.set_style(
+            ProgressStyle::with_template(
+                "[{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} ({binary_bytes_per_sec}, ETA {eta}) {msg}",
+            )
+            .expect("progress template is valid")
+            .progress_chars("=> "),
+        );
+        Some(TerminalProgress {
+            bar,
+            seen: AtomicU64::new(0),
+        })
+    }
+
+    /// Returns another handle on the same bar for a separate operation.
+    pub fn child(&self) -> TerminalProgress {
+        TerminalProgress {
+            bar: self.bar.clone(),
+            seen: AtomicU64::new(0),
+        }
+    }
+
+    /// Removes the bar from the terminal.
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+impl Progress for TerminalProgress {
+    fn update(&self, update: &ProgressUpdate) {
+        if self.bar.length().is_none()
+            && let Some(total) = update.total_in
+        {
+            self.bar.set_length(total);
+        }
+        let previous = self.seen.swap(update.bytes_in, Ordering::Relaxed);
+        self.bar.inc(update.bytes_in.saturating_sub(previous));
+        if update.entry_count > 1 {
+            self.bar.set_message(format!(
+                "{}/{} {}",
+                update.entry_index + 1,
+                update.entry_count,
+                update.entry
+            ));
+        } else {
+            self.bar.set_message(update.entry.to_string());
+        }
+    }
+
+    fn warn(&self, message: &str) {
+        self.bar.suspend(|| eprintln!("rs-zip: warning: {}", message));
+    }
+}
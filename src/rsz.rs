@@ -0,0 +1,871 @@
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Take, Write};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use sha2::{Digest, Sha256};
+use liblzma::write::XzEncoder;
+
+use crate::codec::{self, Builtin, Codec, XZ_MAGIC};
+use crate::crypto::{self, ContentKey, DecryptingReader, EncryptingWriter, Identity, KeySlot, Passphrase};
+use crate::lzma::LimitedDecoder;
+use crate::signature::{self, SigningKey, VerifyingKey};
+
+// RSZ v2 layout (all integers little endian):
+//
+//   header   magic "RSZ\0", version u8, flags u8,
+//            [if ENCRYPTED] slot count u8, key slots
+//   blocks   compressed streams holding the concatenated entry contents
+//            (XZ unless BLOCK_CODECS; codec 0 stores data as is;
+//            chunk-encrypted when ENCRYPTED)
+//   index    XZ-compressed block and entry tables (sealed when INDEX_ENCRYPTED;
+//            followed by a tag over the header and index when INDEX_AUTHENTICATED);
+//            entries carry a SHA-256 of their contents when HASHED, and with
+//            EXTENTS a list of (block, offset, length) pieces for contents
+//            assembled from deduplicated chunks
+//   [if SIGNED] signer Ed25519 public key (32 bytes), signature (64 bytes) over
+//            the header and the stored index
+//   trailer  index offset u64, index length u64, magic "RSZ\0"
+//
+// Legacy (v1) archives are a bare XZ stream with inline metadata and no header.
+
+/// Magic bytes at the start and end of an RSZ v2 archive.
+pub const MAGIC: [u8; 4] = *b"RSZ\0";
+/// Current RSZ format version.
+pub const VERSION: u8 = 2;
+/// Block contents are encrypted.
+pub const FLAG_ENCRYPTED: u8 = 0x01;
+/// The index (names, sizes, offsets) is encrypted as well.
+pub const FLAG_INDEX_ENCRYPTED: u8 = 0x02;
+/// Index entries carry a SHA-256 hash of their contents.
+pub const FLAG_HASHED: u8 = 0x04;
+/// A signature section precedes the trailer.
+pub const FLAG_SIGNED: u8 = 0x08;
+/// The block table records each block's codec (otherwise every block is XZ).
+pub const FLAG_BLOCK_CODECS: u8 = 0x10;
+/// Index entries may list the extents their contents are assembled from.
+pub const FLAG_EXTENTS: u8 = 0x20;
+/// The plaintext index of an encrypted archive ends with a tag, sealed with the content
+/// key, over the header and the index, so it cannot be altered without the key.
+pub const FLAG_INDEX_AUTHENTICATED: u8 = 0x40;
+const KNOWN_FLAGS: u8 = FLAG_ENCRYPTED
+    | FLAG_INDEX_ENCRYPTED
+    | FLAG_HASHED
+    | FLAG_SIGNED
+    | FLAG_BLOCK_CODECS
+    | FLAG_EXTENTS
+    | FLAG_INDEX_AUTHENTICATED;
+
+const TRAILER_LEN: u64 = 8 + 8 + 4;
+const SIGNATURE_SECTION_LEN: u64 = (signature::PUBLIC_KEY_LEN + signature::SIGNATURE_LEN) as u64;
+/// Length of an entry's SHA-256 hash.
+pub const HASH_LEN: usize = 32;
+/// Upper bound for names and index sizes (packed and unpacked) read from an archive.
+const MAX_NAME_LEN: u64 = 64 * 1024;
+const MAX_INDEX_LEN: u64 = 1 << 30;
+
+/// The plaintext header at the start of an RSZ v2 archive.
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub version: u8,
+    pub flags: u8,
+    pub slots: Vec<KeySlot>,
+}
+
+impl Header {
+    /// Whether block contents are encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.flags & FLAG_ENCRYPTED != 0
+    }
+
+    /// Whether the index is encrypted.
+    pub fn index_encrypted(&self) -> bool {
+        self.flags & FLAG_INDEX_ENCRYPTED != 0
+    }
+
+    /// Whether the (plaintext) index is followed by a tag made with the content key.
+    pub fn index_authenticated(&self) -> bool {
+        self.flags & FLAG_INDEX_AUTHENTICATED != 0
+    }
+
+    /// Whether index entries carry content hashes.
+    pub fn is_hashed(&self) -> bool {
+        self.flags & FLAG_HASHED != 0
+    }
+
+    /// Whether the archive carries a signature.
+    pub fn is_signed(&self) -> bool {
+        self.flags & FLAG_SIGNED != 0
+    }
+
+    /// The fixed header bytes that key slots and the sealed index are bound to.
+    pub fn aad(&self) -> [u8; 6] {
+        let mut aad = [0u8; 6];
+        aad[..4].copy_from_slice(&MAGIC);
+        aad[4] = self.version;
+        aad[5] = self.flags;
+        aad
+    }
+
+    pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(&self.aad())?;
+        if self.is_encrypted() {
+            writer.write_u8(self.slots.len() as u8)?;
+            for slot in &self.slots {
+                slot.write_to(writer)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the serialised header, as covered by the archive signature.
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        self.write_to(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Reads the header, assuming the magic bytes have already been checked.
+    pub fn read_from<R: Read>(reader: &mut R) -> io::Result<Header> {
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid_data("not an RSZ archive"));
+        }
+        let version = reader.read_u8()?;
+        if version != VERSION {
+            return Err(invalid_data(&format!("unsupported RSZ version {}", version)));
+        }
+        let flags = reader.read_u8()?;
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(invalid_data(&format!("archive uses unsupported features (flags {:#04x})", flags)));
+        }
+        let mut slots = Vec::new();
+        if flags & FLAG_ENCRYPTED != 0 {
+            let count = reader.read_u8()?;
+            for _ in 0..count {
+                slots.push(KeySlot::read_from(reader)?);
+            }
+        }
+        Ok(Header { version, flags, slots })
+    }
+}
+
+/// Where one compressed block lives in the archive file.
+#[derive(Debug, Clone)]
+pub struct BlockInfo {
+    /// Absolute file offset of the block's first byte.
+    pub offset: u64,
+    /// Number of bytes the block occupies in the file.
+    pub stored_len: u64,
+    /// Number of bytes the block decompresses to.
+    pub raw_len: u64,
+    /// Id of the registered [`Codec`] that compressed the block.
+    pub codec: u8,
+    /// Random nonce prefix for encrypted blocks (all zero otherwise).
+    pub nonce_prefix: [u8; crypto::NONCE_PREFIX_LEN],
+}
+
+/// One archived file.
+#[derive(Debug, Clone)]
+pub struct EntryInfo {
+    pub name: String,
+    pub size: u64,
+    /// Index of the block holding the contents.
+    pub block: u32,
+    /// Offset of the contents within the block's decompressed data.
+    pub offset: u64,
+    /// SHA-256 of the contents, when the archive records hashes.
+    pub sha256: Option<[u8; HASH_LEN]>,
+    /// The pieces the contents are assembled from, in order, when they are not stored in
+    /// one run at `block` and `offset` (which then point at the first piece). Empty for
+    /// contiguous contents.
+    pub extents: Vec<Extent>,
+}
+
+/// A run of a block's decompressed data that is part of an entry's contents.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extent {
+    pub block: u32,
+    pub offset: u64,
+    pub len: u64,
+}
+
+/// The block and entry tables stored at the end of the archive.
+#[derive(Debug, Clone, Default)]
+pub struct Index {
+    pub blocks: Vec<BlockInfo>,
+    pub entries: Vec<EntryInfo>,
+}
+
+impl Index {
+    /// Serialises the index (uncompressed) in the layout selected by the header `flags`.
+    /// With `FLAG_HASHED`, every entry must have a hash; without `FLAG_EXTENTS`, every
+    /// entry must be contiguous.
+    pub fn encode(&self, flags: u8) -> io::Result<Vec<u8>> {
+        let hashed = flags & FLAG_HASHED != 0;
+        let mut out = Vec::new();
+        out.write_u32::<LittleEndian>(self.blocks.len() as u32)?;
+        for block in &self.blocks {
+            out.write_u64::<LittleEndian>(block.offset)?;
+            out.write_u64::<LittleEndian>(block.stored_len)?;
+            out.write_u64::<LittleEndian>(block.raw_len)?;
+            out.write_all(&block.nonce_prefix)?;
+            if flags & FLAG_BLOCK_CODECS != 0 {
+                out.write_u8(block.codec)?;
+            } else if block.codec != Builtin::Xz.id() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "block codec cannot be recorded"));
+            }
+        }
+        out.write_u64::<LittleEndian>(self.entries.len() as u64)?;
+        for entry in &self.entries {
+            out.write_u64::<LittleEndian>(entry.name.len() as u64)?;
+            out.write_all(entry.name.as_bytes())?;
+            out.write_u64::<LittleEndian>(entry.size)?;
+            out.write_u32::<LittleEndian>(entry.block)?;
+            out.write_u64::<LittleEndian>(entry.offset)?;
+            if hashed {
+                let hash = entry
+                    .sha256
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "index entry is missing its hash"))?;
+                out.write_all(&hash)?;
+            }
+            if flags & FLAG_EXTENTS != 0 {
+                out.write_u32::<LittleEndian>(entry.extents.len() as u32)?;
+                for extent in &entry.extents {
+                    out.write_u32::<LittleEndian>(extent.block)?;
+                    out.write_u64::<LittleEndian>(extent.offset)?;
+                    out.write_u64::<LittleEndian>(extent.len)?;
+                }
+            } else if !entry.extents.is_empty() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "entry extents cannot be recorded"));
+            }
+        }
+        Ok(out)
+    }
+
+    /// Parses an index produced by [`Index::encode`] with the same header `flags`.
+    pub fn decode(mut data: &[u8], flags: u8) -> io::Result<Index> {
+        let hashed = flags & FLAG_HASHED != 0;
+        let block_count = data.read_u32::<LittleEndian>()?;
+        let mut blocks = Vec::new();
+        for _ in 0..block_count {
+            let offset = data.read_u64::<LittleEndian>()?;
+            let stored_len = data.read_u64::<LittleEndian>()?;
+            let raw_len = data.read_u64::<LittleEndian>()?;
+            let mut nonce_prefix = [0u8; crypto::NONCE_PREFIX_LEN];
+            data.read_exact(&mut nonce_prefix)?;
+            let codec = if flags & FLAG_BLOCK_CODECS != 0 {
+                data.read_u8()?
+            } else {
+                Builtin::Xz.id()
+            };
+            blocks.push(BlockInfo { offset, stored_len, raw_len, codec, nonce_prefix });
+        }
+        let entry_count = data.read_u64::<LittleEndian>()?;
+        let mut entries = Vec::new();
+        for _ in 0..entry_count {
+            let name_len = data.read_u64::<LittleEndian>()?;
+            if name_len > MAX_NAME_LEN {
+                return Err(invalid_data("entry name is implausibly long"));
+            }
+            let mut name = vec![0u8; name_len as usize];
+            data.read_exact(&mut name)?;
+            let name = String::from_utf8(name).map_err(|_| invalid_data("entry name is not UTF-8"))?;
+            let size = data.read_u64::<LittleEndian>()?;
+            let block = data.read_u32::<LittleEndian>()?;
+            let offset = data.read_u64::<LittleEndian>()?;
+            let sha256 = if hashed {
+                let mut hash = [0u8; HASH_LEN];
+                data.read_exact(&mut hash)?;
+                Some(hash)
+            } else {
+                None
+            };
+            let mut extents = Vec::new();
+            if flags & FLAG_EXTENTS != 0 {
+                for _ in 0..data.read_u32::<LittleEndian>()? {
+                    extents.push(Extent {
+                        block: data.read_u32::<LittleEndian>()?,
+                        offset: data.read_u64::<LittleEndian>()?,
+                        len: data.read_u64::<LittleEndian>()?,
+                    });
+                }
+            }
+            if block as usize >= blocks.len() || extents.iter().any(|extent| extent.block as usize >= blocks.len()) {
+                return Err(invalid_data(&format!("entry {} points at a missing block", name)));
+            }
+            if !extents.is_empty() && extents.iter().try_fold(0u64, |sum, extent| sum.checked_add(extent.len)) != Some(size) {
+                return Err(invalid_data(&format!("the extents of entry {} do not add up to its size", name)));
+            }
+            entries.push(EntryInfo { name, size, block, offset, sha256, extents });
+        }
+        Ok(Index { blocks, entries })
+    }
+}
+
+/// How to unlock an encrypted archive, which signers to accept and how much memory
+/// decoding may use.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Unlock<'a> {
+    /// Password for passphrase key slots.
+    pub password: Option<&'a Passphrase>,
+    /// Private keys for X25519 recipient slots.
+    pub identities: &'a [Identity],
+    /// The content key itself, when the caller already knows it (e.g. right after writing).
+    pub content_key: Option<&'a [u8; crypto::KEY_LEN]>,
+    /// Keys the archive must be signed by (any one of them). When empty, unsigned archives
+    /// are accepted, but a signature that is present must still be valid.
+    pub signers: &'a [VerifyingKey],
+    /// Most memory the index and block decoders may use (see `ExtractOptions::memlimit`).
+    pub memlimit: Option<u64>,
+}
+
+/// Returns true if the reader starts with the RSZ v2 magic, false for a legacy (bare XZ)
+/// archive. The reader is rewound to the start either way.
+pub fn is_v2<R: Read + Seek>(reader: &mut R) -> io::Result<bool> {
+    let mut magic = [0u8; 6];
+    reader.seek(SeekFrom::Start(0))?;
+    let read = read_up_to(reader, &mut magic)?;
+    reader.seek(SeekFrom::Start(0))?;
+    if read >= 4 && magic[..4] == MAGIC {
+        Ok(true)
+    } else if read == 6 && magic == XZ_MAGIC {
+        Ok(false)
+    } else {
+        Err(invalid_data("not an RSZ archive"))
+    }
+}
+
+/// Output side of a block: plain, or chunk-encrypted.
+pub(crate) enum BlockWriter<W: Write> {
+    Plain(W),
+    Encrypted(EncryptingWriter<W>),
+}
+
+impl<W: Write> BlockWriter<W> {
+    pub(crate) fn new(inner: W, key: Option<&ContentKey>, nonce_prefix: [u8; crypto::NONCE_PREFIX_LEN]) -> Self {
+        match key {
+            Some(key) => BlockWriter::Encrypted(EncryptingWriter::new(inner, key, nonce_prefix)),
+            None => BlockWriter::Plain(inner),
+        }
+    }
+
+    pub(crate) fn finish(self) -> io::Result<W> {
+        match self {
+            BlockWriter::Plain(w) => Ok(w),
+            BlockWriter::Encrypted(w) => w.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for BlockWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            BlockWriter::Plain(w) => w.write(buf),
+            BlockWriter::Encrypted(w) => w.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            BlockWriter::Plain(w) => w.flush(),
+            BlockWriter::Encrypted(w) => w.flush(),
+        }
+    }
+}
+
+/// Input side of a block: plain, or chunk-encrypted.
+pub(crate) enum BlockReader<R: Read> {
+    Plain(Take<R>),
+    Encrypted(DecryptingReader<Take<R>>),
+}
+
+impl<R: Read> Read for BlockReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            BlockReader::Plain(r) => r.read(buf),
+            BlockReader::Encrypted(r) => r.read(buf),
+        }
+    }
+}
+
+/// Compresses the serialised index and, with a key, seals it or (when the index stays
+/// readable) appends its authentication tag.
+pub(crate) fn pack_index(index: &Index, header: &Header, key: Option<&ContentKey>, level: u32) -> io::Result<Vec<u8>> {
+    let mut encoder = XzEncoder::new(Vec::new(), level);
+    encoder.write_all(&index.encode(header.flags)?)?;
+    let mut packed = encoder.finish()?;
+    match key {
+        Some(key) if header.index_encrypted() => crypto::seal(key, &header.aad(), &packed),
+        Some(key) if header.index_authenticated() => {
+            let tag = crypto::seal(key, &index_tag_aad(header, &packed)?, &[])?;
+            packed.extend_from_slice(&tag);
+            Ok(packed)
+        }
+        _ => Ok(packed),
+    }
+}
+
+/// What the tag of an authenticated index covers: the whole header, key slots included,
+/// then the packed index.
+fn index_tag_aad(header: &Header, packed: &[u8]) -> io::Result<Vec<u8>> {
+    let mut aad = header.to_bytes()?;
+    aad.extend_from_slice(packed);
+    Ok(aad)
+}
+
+/// Signs the header and stored index and writes the signature section.
+pub(crate) fn write_signature<W: Write>(
+    writer: &mut W,
+    key: &SigningKey,
+    header: &Header,
+    packed_index: &[u8],
+) -> io::Result<()> {
+    let signature = key.sign_archive(&header.to_bytes()?, packed_index);
+    writer.write_all(&key.verifying_key().to_bytes())?;
+    writer.write_all(&signature)
+}
+
+/// Writes the trailer that points at the index.
+pub(crate) fn write_trailer<W: Write>(writer: &mut W, index_offset: u64, index_len: u64) -> io::Result<()> {
+    writer.write_u64::<LittleEndian>(index_offset)?;
+    writer.write_u64::<LittleEndian>(index_len)?;
+    writer.write_all(&MAGIC)
+}
+
+/// An RSZ v2 archive whose header and index have been read and whose key (if any) is unlocked.
+pub(crate) struct OpenArchive<R> {
+    reader: R,
+    pub index: Index,
+    /// The key that signed the archive, if it is signed (the signature has been checked).
+    pub signer: Option<VerifyingKey>,
+    key: Option<ContentKey>,
+    /// Memory limit for the block decoders.
+    memlimit: u64,
+}
+
+impl<R: Read + Seek> OpenArchive<R> {
+    /// Reads the header and index, checking the signature (if any) and unlocking the
+    /// content key when the archive is encrypted.
+    pub(crate) fn open(mut reader: R, unlock: &Unlock) -> io::Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+        let header = Header::read_from(&mut reader)?;
+
+        let file_len = reader.seek(SeekFrom::End(0))?;
+        if file_len < TRAILER_LEN {
+            return Err(invalid_data("RSZ archive is truncated"));
+        }
+        reader.seek(SeekFrom::Start(file_len - TRAILER_LEN))?;
+        let index_offset = reader.read_u64::<LittleEndian>()?;
+        let index_len = reader.read_u64::<LittleEndian>()?;
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        let index_end = if header.is_signed() {
+            (file_len - TRAILER_LEN).checked_sub(SIGNATURE_SECTION_LEN)
+        } else {
+            Some(file_len - TRAILER_LEN)
+        };
+        if magic != MAGIC
+            || index_len > MAX_INDEX_LEN
+            || index_end.is_none_or(|end| index_offset.saturating_add(index_len) > end)
+        {
+            return Err(invalid_data("RSZ archive trailer is damaged or the archive is truncated"));
+        }
+
+        reader.seek(SeekFrom::Start(index_offset))?;
+        let mut packed = vec![0u8; index_len as usize];
+        reader.read_exact(&mut packed)?;
+
+        let signer = match index_end {
+            Some(end) if header.is_signed() => {
+                reader.seek(SeekFrom::Start(end))?;
+                let mut public = [0u8; signature::PUBLIC_KEY_LEN];
+                let mut sig = [0u8; signature::SIGNATURE_LEN];
+                reader.read_exact(&mut public)?;
+                reader.read_exact(&mut sig)?;
+                let signer = VerifyingKey::from_bytes(&public)?;
+                signer.verify_archive(&header.to_bytes()?, &packed, &sig)?;
+                Some(signer)
+            }
+            _ => None,
+        };
+        check_signer(signer.as_ref(), unlock.signers)?;
+
+        let key = if header.is_encrypted() {
+            Some(unlock_key(&header, unlock)?)
+        } else {
+            None
+        };
+        if let Some(key) = &key {
+            if header.index_encrypted() {
+                packed = crypto::open(key, &header.aad(), &packed)?;
+            } else if header.index_authenticated() {
+                let Some(index_len) = packed.len().checked_sub(crypto::SEAL_OVERHEAD) else {
+                    return Err(invalid_data("RSZ index is missing its authentication tag"));
+                };
+                let tag = packed.split_off(index_len);
+                crypto::open(key, &index_tag_aad(&header, &packed)?, &tag)
+                    .map_err(|_| invalid_data("RSZ index failed authentication (corrupted or tampered)"))?;
+            }
+        }
+        let memlimit = unlock.memlimit.unwrap_or(u64::MAX);
+        // A small packed index could still decode to any size, so the limit applies twice.
+        let mut raw = Vec::new();
+        LimitedDecoder::xz(&packed[..], memlimit)?
+            .take(MAX_INDEX_LEN + 1)
+            .read_to_end(&mut raw)?;
+        if raw.len() as u64 > MAX_INDEX_LEN {
+            return Err(invalid_data("RSZ index is larger than 1 GiB"));
+        }
+        let index = Index::decode(&raw, header.flags)?;
+
+        Ok(OpenArchive { reader, index, signer, key, memlimit })
+    }
+
+    /// Returns a decoder positioned at the start of block `block`'s decompressed data.
+    pub(crate) fn block_reader(&mut self, block: usize) -> io::Result<Box<dyn Read + '_>> {
+        decode_block(&mut self.reader, &self.index.blocks[block], self.key.as_ref(), self.memlimit)
+    }
+
+    /// Like [`block_reader`](OpenArchive::block_reader), but reads the block through
+    /// `source`, another handle to the archive file.
+    pub(crate) fn block_reader_from<'s, S: Read + Seek + 's>(
+        &self,
+        source: S,
+        block: usize,
+    ) -> io::Result<Box<dyn Read + 's>> {
+        decode_block(source, &self.index.blocks[block], self.key.as_ref(), self.memlimit)
+    }
+}
+
+fn decode_block<'s, S: Read + Seek + 's>(
+    mut source: S,
+    info: &BlockInfo,
+    key: Option<&ContentKey>,
+    memlimit: u64,
+) -> io::Result<Box<dyn Read + 's>> {
+    // Resolved here rather than when the index is read, so listing an archive doesn't
+    // need its codecs registered.
+    let codec = codec::by_id(info.codec)?;
+    source.seek(SeekFrom::Start(info.offset))?;
+    let limited = source.take(info.stored_len);
+    let inner = match key {
+        Some(key) => BlockReader::Encrypted(DecryptingReader::new(limited, key, info.nonce_prefix, info.stored_len)),
+        None => BlockReader::Plain(limited),
+    };
+    codec.decoder_with_memlimit(Box::new(inner), memlimit)
+}
+
+/// Reads an entry's contents from its extents, reusing one block decoder while the extents
+/// move forward through a block and starting it over when they go back.
+pub(crate) struct ExtentReader<'a, R> {
+    archive: &'a OpenArchive<R>,
+    source: &'a File,
+    extents: &'a [Extent],
+    /// The decoder and its position in the block's decompressed data.
+    current: Option<(u32, u64, Box<dyn Read + 'a>)>,
+    /// Bytes left of `extents[0]`, once the decoder is positioned at them.
+    left: Option<u64>,
+}
+
+impl<'a, R: Read + Seek> ExtentReader<'a, R> {
+    /// Reads `extents` of `archive`, decoding blocks through `source` (another handle to
+    /// the archive file).
+    pub(crate) fn new(archive: &'a OpenArchive<R>, source: &'a File, extents: &'a [Extent]) -> Self {
+        ExtentReader { archive, source, extents, current: None, left: None }
+    }
+}
+
+impl<R: Read + Seek> Read for ExtentReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let Some(extent) = self.extents.first() else {
+                return Ok(0);
+            };
+            let left = match self.left {
+                Some(left) => left,
+                None => {
+                    let reusable = matches!(&self.current, Some((block, position, _))
+                        if *block == extent.block && *position <= extent.offset);
+                    if !reusable {
+                        let decoder = self.archive.block_reader_from(self.source, extent.block as usize)?;
+                        self.current = Some((extent.block, 0, decoder));
+                    }
+                    let (_, position, decoder) = self.current.as_mut().expect("a decoder was just opened");
+                    let skipped = io::copy(&mut decoder.take(extent.offset - *position), &mut io::sink())?;
+                    *position += skipped;
+                    if *position != extent.offset {
+                        return Err(invalid_data("RSZ block is shorter than its entries need"));
+                    }
+                    extent.len
+                }
+            };
+            if left == 0 {
+                self.extents = &self.extents[1..];
+                self.left = None;
+                continue;
+            }
+            let (_, position, decoder) = self.current.as_mut().expect("the decoder is positioned");
+            let want = buf.len().min(usize::try_from(left).unwrap_or(usize::MAX));
+            let n = decoder.read(&mut buf[..want])?;
+            if n == 0 {
+                return Err(invalid_data("RSZ block is shorter than its entries need"));
+            }
+            *position += n as u64;
+            self.left = Some(left - n as u64);
+            return Ok(n);
+        }
+    }
+}
+
+/// Fails unless the archive was signed by one of `trusted` (when any are given).
+pub(crate) fn check_signer(signer: Option<&VerifyingKey>, trusted: &[VerifyingKey]) -> io::Result<()> {
+    if trusted.is_empty() {
+        return Ok(());
+    }
+    match signer {
+        None => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "archive is not signed, but a verifying key was given",
+        )),
+        Some(signer) if !trusted.contains(signer) => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("archive is signed by {}, which is not a trusted key", signer),
+        )),
+        Some(_) => Ok(()),
+    }
+}
+
+/// Hashes everything `reader` yields.
+///
+/// # Returns
+/// * `io::Result<(u64, [u8; HASH_LEN])>` - The number of bytes read and their SHA-256.
+pub(crate) fn hash_contents(reader: &mut dyn Read) -> io::Result<(u64, [u8; HASH_LEN])> {
+    let mut hashing = HashingReader::new(reader);
+    let len = io::copy(&mut hashing, &mut io::sink())?;
+    Ok((len, hashing.digest()))
+}
+
+/// Hashes everything read through it, so entry contents can be checked against the index.
+pub(crate) struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: Read> HashingReader<R> {
+    pub(crate) fn new(inner: R) -> Self {
+        HashingReader { inner, hasher: Sha256::new() }
+    }
+
+    pub(crate) fn get_ref(&self) -> &R {
+        &self.inner
+    }
+
+    /// Returns the hash of everything read so far.
+    pub(crate) fn digest(&self) -> [u8; HASH_LEN] {
+        self.hasher.clone().finalize().into()
+    }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Recovers the content key from the header's key slots.
+fn unlock_key(header: &Header, unlock: &Unlock) -> io::Result<ContentKey> {
+    if let Some(key) = unlock.content_key {
+        return Ok(zeroize::Zeroizing::new(*key));
+    }
+    if unlock.password.is_none() && unlock.identities.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "archive is encrypted; a password (--password-file) or identity (--identity) is required",
+        ));
+    }
+    for slot in &header.slots {
+        for identity in unlock.identities {
+            if let Some(key) = slot.unlock_with_identity(identity, &header.aad()) {
+                return Ok(key);
+            }
+        }
+        if let Some(password) = unlock.password
+            && let Some(key) = slot.unlock_with_passphrase(password, &header.aad())?
+        {
+            return Ok(key);
+        }
+    }
+    let message = match (unlock.password.is_some(), unlock.identities.is_empty()) {
+        (true, true) => "incorrect password for encrypted archive",
+        (false, false) => "none of the given identities can decrypt this archive",
+        _ => "neither the password nor the given identities can decrypt this archive",
+    };
+    Err(io::Error::new(io::ErrorKind::PermissionDenied, message))
+}
+
+/// Reads until `buf` is full or the reader is exhausted, returning the number of bytes read.
+pub(crate) fn read_up_to<R: Read + ?Sized>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::compression::{self, CompressOptions};
+    use crate::decompression::{self, ExtractOptions};
+
+    fn write_archive(dir: &Path, options: &CompressOptions) -> PathBuf {
+        let a = dir.join("a.txt");
+        let b = dir.join("b.txt");
+        fs::write(&a, "first file\n").unwrap();
+        fs::write(&b, "second, longer file\n").unwrap();
+        let output = dir.join("test.rsz");
+        compression::create_padded_archive(&[a, b], output.to_str().unwrap(), options).unwrap();
+        output
+    }
+
+    fn recipient_options(identity: &Identity) -> CompressOptions {
+        CompressOptions {
+            recipients: vec![identity.to_public()],
+            ..CompressOptions::default()
+        }
+    }
+
+    fn identity_options(identity: Identity) -> ExtractOptions {
+        ExtractOptions {
+            identities: vec![identity],
+            ..ExtractOptions::default()
+        }
+    }
+
+    /// Decodes the (authenticated, unsigned) index of the archive at `path`, lets `edit`
+    /// change it, and stores it back with its original tag.
+    fn rewrite_index(path: &Path, edit: impl FnOnce(&mut Index)) {
+        let mut bytes = fs::read(path).unwrap();
+        let header = Header::read_from(&mut &bytes[..]).unwrap();
+        let mut trailer = &bytes[bytes.len() - TRAILER_LEN as usize..];
+        let offset = trailer.read_u64::<LittleEndian>().unwrap() as usize;
+        let len = trailer.read_u64::<LittleEndian>().unwrap() as usize;
+        let (packed, tag) = bytes[offset..offset + len].split_at(len - crypto::SEAL_OVERHEAD);
+
+        let mut raw = Vec::new();
+        liblzma::read::XzDecoder::new(packed).read_to_end(&mut raw).unwrap();
+        let mut index = Index::decode(&raw, header.flags).unwrap();
+        edit(&mut index);
+        let mut encoder = XzEncoder::new(Vec::new(), 6);
+        encoder.write_all(&index.encode(header.flags).unwrap()).unwrap();
+        let mut packed = encoder.finish().unwrap();
+        packed.extend_from_slice(tag);
+
+        bytes.truncate(offset);
+        bytes.extend_from_slice(&packed);
+        write_trailer(&mut bytes, offset as u64, packed.len() as u64).unwrap();
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn encrypted_archive_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = Identity::generate();
+        let archive = write_archive(dir.path(), &recipient_options(&identity));
+
+        let listing = decompression::test_archive(&archive, &identity_options(identity)).unwrap();
+        let names: Vec<_> = listing.entries.iter().map(|entry| entry.name.as_str()).collect();
+        assert_eq!(names.len(), 2);
+        assert!(names[0].ends_with("/a.txt") && names[1].ends_with("/b.txt"), "{:?}", names);
+    }
+
+    #[test]
+    fn wrong_identity_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = write_archive(dir.path(), &recipient_options(&Identity::generate()));
+
+        assert!(decompression::test_archive(&archive, &identity_options(Identity::generate())).is_err());
+        assert!(decompression::test_archive(&archive, &ExtractOptions::default()).is_err());
+    }
+
+    #[test]
+    fn flipped_header_flag_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = Identity::generate();
+        let archive = write_archive(dir.path(), &recipient_options(&identity));
+        let original = fs::read(&archive).unwrap();
+        assert_eq!(original[5] & FLAG_INDEX_AUTHENTICATED, FLAG_INDEX_AUTHENTICATED);
+
+        // Dropping the index tag, claiming the index is sealed, or dropping the hashes.
+        for flip in [FLAG_INDEX_AUTHENTICATED, FLAG_INDEX_ENCRYPTED, FLAG_HASHED] {
+            let mut bytes = original.clone();
+            bytes[5] ^= flip;
+            fs::write(&archive, &bytes).unwrap();
+            let options = identity_options(identity.clone());
+            assert!(decompression::test_archive(&archive, &options).is_err(), "flag {:#04x}", flip);
+        }
+    }
+
+    #[test]
+    fn swapped_index_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = Identity::generate();
+        let archive = write_archive(dir.path(), &recipient_options(&identity));
+
+        // Swap the names, so the first file's contents would be extracted as the second.
+        rewrite_index(&archive, |index| {
+            let first = index.entries[0].name.clone();
+            index.entries[0].name = std::mem::replace(&mut index.entries[1].name, first);
+        });
+        let err = decompression::test_archive(&archive, &identity_options(identity)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("authentication"), "{}", err);
+    }
+
+    #[test]
+    fn tampered_sealed_index_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let identity = Identity::generate();
+        let options = CompressOptions {
+            encrypt_index: true,
+            ..recipient_options(&identity)
+        };
+        let archive = write_archive(dir.path(), &options);
+
+        let mut bytes = fs::read(&archive).unwrap();
+        let offset = bytes.len() - TRAILER_LEN as usize - 1;
+        bytes[offset] ^= 1;
+        fs::write(&archive, bytes).unwrap();
+        let err = decompression::test_archive(&archive, &identity_options(identity)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn any_recipient_can_open_the_archive() {
+        let dir = tempfile::tempdir().unwrap();
+        let (alice, bob) = (Identity::generate(), Identity::generate());
+        let options = CompressOptions {
+            recipients: vec![alice.to_public(), bob.to_public()],
+            ..CompressOptions::default()
+        };
+        let archive = write_archive(dir.path(), &options);
+
+        for identity in [alice, bob] {
+            let listing = decompression::test_archive(&archive, &identity_options(identity)).unwrap();
+            assert_eq!(listing.entries.len(), 2);
+        }
+    }
+}
@@ -0,0 +1,443 @@
+use std::fmt;
+use std::io::{self, Cursor, Read, Seek, SeekFrom};
+use std::str::FromStr;
+
+use crate::codec::XZ_MAGIC;
+use crate::lzma::{self, LimitedDecoder};
+
+// Every XZ stream ends with an index listing the size of each of its blocks, followed by a
+// 12-byte footer giving the index size:
+//   stream header: magic (6) | stream flags (2) | CRC32 of the flags (4)
+//   blocks:        block header, compressed data, check; padded to a multiple of 4
+//   index:         0x00 | record count | (unpadded size, uncompressed size)... | padding | CRC32
+//   footer:        CRC32 of the next 6 bytes | index size / 4 - 1 (u32) | stream flags | "YZ"
+// Reading the indexes back to front gives the position of every block, so a read can start
+// at the block holding the wanted data.
+
+/// Size of the stream header, and of the stream footer.
+const STREAM_HEADER_LEN: u64 = 12;
+/// Magic at the end of every stream footer.
+const FOOTER_MAGIC: [u8; 2] = *b"YZ";
+/// The original size rs-zip's single-file format stores in front of its XZ stream.
+const SIZE_HEADER_LEN: u64 = 8;
+
+/// One XZ block: where it is in the file and which part of the decompressed data it holds.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Block {
+    /// File offset of the block header.
+    pub(crate) offset: u64,
+    /// Block header, compressed data and check, without the padding after them.
+    pub(crate) unpadded_size: u64,
+    pub(crate) uncompressed_size: u64,
+    /// Flags of the stream the block is in (they name its integrity check).
+    stream_flags: [u8; 2],
+    /// Offset of the block's data in the decompressed output.
+    start: u64,
+    /// Bytes of the block's data that belong to the output: all of them, except where
+    /// rs-zip's size header says the end is padding.
+    len: u64,
+}
+
+/// One XZ stream of a file, as its index describes it.
+#[derive(Debug, Clone)]
+pub(crate) struct StreamIndex {
+    /// The original size from rs-zip's size header in front of the stream, if there is one.
+    pub(crate) size_header: Option<u64>,
+    pub(crate) stream_flags: [u8; 2],
+    pub(crate) blocks: Vec<Block>,
+}
+
+impl StreamIndex {
+    /// Size of the data the stream decodes to, without the padding rs-zip adds to small files.
+    pub(crate) fn data_len(&self) -> u64 {
+        let total = self.blocks.iter().map(|block| block.uncompressed_size).sum();
+        self.size_header.map_or(total, |size| size.min(total))
+    }
+}
+
+/// The block being decoded, and how far.
+struct OpenBlock {
+    index: usize,
+    decoder: LimitedDecoder<Cursor<Vec<u8>>>,
+    decoded: u64,
+}
+
+/// Reads the decompressed contents of a `.xz` file at any offset, decoding only the blocks
+/// that hold the bytes read. Files written with a block size (`--block-size`, or
+/// `xz -T0`) have many small blocks and seek cheaply; a single-block file is decoded from
+/// its start. Concatenated streams and rs-zip's own single-file format are read as one.
+pub struct XzSeekableReader<R: Read + Seek> {
+    reader: R,
+    blocks: Vec<Block>,
+    len: u64,
+    pos: u64,
+    memlimit: u64,
+    current: Option<OpenBlock>,
+}
+
+impl<R: Read + Seek> XzSeekableReader<R> {
+    /// Reads the block index of an `.xz` file.
+    ///
+    /// # Arguments
+    /// * `reader` - The compressed file.
+    ///
+    /// # Returns
+    /// * `io::Result<Self>` - The reader, positioned at the start of the data; `InvalidData`
+    ///   if the file isn't XZ or its index is damaged.
+    pub fn new(reader: R) -> io::Result<Self> {
+        XzSeekableReader::with_memlimit(reader, u64::MAX)
+    }
+
+    /// Like [`XzSeekableReader::new`], but the decoder may use at most `memlimit` bytes of
+    /// memory per block.
+    pub fn with_memlimit(mut reader: R, memlimit: u64) -> io::Result<Self> {
+        let mut blocks = Vec::new();
+        let mut len = 0;
+        for stream in read_streams(&mut reader)? {
+            let mut remaining = stream.data_len();
+            for mut block in stream.blocks {
+                block.start = len;
+                block.len = block.uncompressed_size.min(remaining);
+                remaining -= block.len;
+                len += block.len;
+                blocks.push(block);
+            }
+        }
+        Ok(XzSeekableReader { reader, blocks, len, pos: 0, memlimit, current: None })
+    }
+
+    /// Size of the decompressed data.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the decompressed data is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of XZ blocks in the file, the units it can be decoded in.
+    pub fn block_count(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Starts decoding block `index` on its own: its bytes are put in a one-block stream of
+    /// their own, so the decoder still checks them against the index sizes and the check.
+    fn open_block(&mut self, index: usize) -> io::Result<OpenBlock> {
+        let block = self.blocks[index];
+        let mut stream = Vec::new();
+        stream.extend_from_slice(&XZ_MAGIC);
+        stream.extend_from_slice(&block.stream_flags);
+        stream.extend_from_slice(&crc32fast::hash(&block.stream_flags).to_le_bytes());
+
+        let header_len = stream.len();
+        stream.resize(header_len + padded(block.unpadded_size) as usize, 0);
+        self.reader.seek(SeekFrom::Start(block.offset))?;
+        self.reader.read_exact(&mut stream[header_len..])?;
+
+        let index_start = stream.len();
+        stream.push(0);
+        for value in [1, block.unpadded_size, block.uncompressed_size] {
+            write_varint(&mut stream, value);
+        }
+        while !stream.len().is_multiple_of(4) {
+            stream.push(0);
+        }
+        let crc = crc32fast::hash(&stream[index_start..]);
+        stream.extend_from_slice(&crc.to_le_bytes());
+
+        let mut footer = Vec::with_capacity(STREAM_HEADER_LEN as usize);
+        footer.extend_from_slice(&(((stream.len() - index_start) / 4 - 1) as u32).to_le_bytes());
+        footer.extend_from_slice(&block.stream_flags);
+        stream.extend_from_slice(&crc32fast::hash(&footer).to_le_bytes());
+        stream.extend_from_slice(&footer);
+        stream.extend_from_slice(&FOOTER_MAGIC);
+
+        let decoder = LimitedDecoder::xz_single(Cursor::new(stream), self.memlimit)?;
+        Ok(OpenBlock { index, decoder, decoded: 0 })
+    }
+}
+
+impl<R: Read + Seek> Read for XzSeekableReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.pos >= self.len {
+            return Ok(0);
+        }
+        let index = self.blocks.partition_point(|block| block.start + block.len <= self.pos);
+        let block = self.blocks[index];
+        let offset = self.pos - block.start;
+        let reusable = matches!(&self.current, Some(open) if open.index == index && open.decoded <= offset);
+        if !reusable {
+            self.current = Some(self.open_block(index)?);
+        }
+        let open = self.current.as_mut().unwrap();
+
+        let skip = offset - open.decoded;
+        if io::copy(&mut (&mut open.decoder).take(skip), &mut io::sink())? < skip {
+            return Err(corrupt("an XZ block holds less data than the index says"));
+        }
+        let wanted = (block.len - offset).min(buf.len() as u64) as usize;
+        let read = open.decoder.read(&mut buf[..wanted])?;
+        if read == 0 {
+            return Err(corrupt("an XZ block holds less data than the index says"));
+        }
+        open.decoded = offset + read as u64;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read + Seek> Seek for XzSeekableReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+        self.pos = target.ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "cannot seek before the start of the data")
+        })?;
+        Ok(self.pos)
+    }
+}
+
+/// A part of the decompressed data, given as `OFFSET:LEN` on the command line (both with
+/// an optional `KiB`, `MiB` or `GiB` suffix).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ByteRange {
+    pub offset: u64,
+    pub len: u64,
+}
+
+impl FromStr for ByteRange {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let (offset, len) = s.split_once(':').ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, format!("expected OFFSET:LEN, got {}", s))
+        })?;
+        Ok(ByteRange {
+            offset: lzma::parse_size(offset)?,
+            len: lzma::parse_size(len)?,
+        })
+    }
+}
+
+impl fmt::Display for ByteRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.offset, self.len)
+    }
+}
+
+/// Reads the index of every XZ stream in a file, from the last one back, skipping stream
+/// padding and noting rs-zip's size headers.
+///
+/// # Returns
+/// * `io::Result<Vec<StreamIndex>>` - The streams in file order; `InvalidData` if the file
+///   isn't XZ or an index is damaged.
+pub(crate) fn read_streams<R: Read + Seek>(reader: &mut R) -> io::Result<Vec<StreamIndex>> {
+    let mut streams: Vec<StreamIndex> = Vec::new();
+    let mut end = reader.seek(SeekFrom::End(0))?;
+    while end > 0 {
+        let tail = read_tail(reader, end)?;
+        // rs-zip's size header sits in front of the stream starting at `end`: at the start of
+        // the file, or after another stream when rs-zip files were concatenated. Eight null
+        // bytes after a stream are stream padding, not the header of an empty file.
+        let header = &tail[tail.len().saturating_sub(SIZE_HEADER_LEN as usize)..];
+        if let Some(stream) = streams.last_mut()
+            && end >= SIZE_HEADER_LEN
+            && !tail.ends_with(&FOOTER_MAGIC)
+            && (end == SIZE_HEADER_LEN
+                || (header != [0; SIZE_HEADER_LEN as usize]
+                    && tail[..tail.len() - SIZE_HEADER_LEN as usize].ends_with(&FOOTER_MAGIC)))
+        {
+            let size = u64::from_le_bytes(header.try_into().unwrap());
+            stream.size_header = Some(size);
+            end -= SIZE_HEADER_LEN;
+            continue;
+        }
+        // Stream padding: null bytes in groups of four.
+        if tail.ends_with(&[0; 4]) {
+            end -= 4;
+            continue;
+        }
+        let (start, stream) = read_stream(reader, end)?;
+        streams.push(stream);
+        end = start;
+    }
+    if streams.is_empty() {
+        return Err(corrupt("the file holds no XZ stream"));
+    }
+    streams.reverse();
+    Ok(streams)
+}
+
+/// Reads the stream that ends at `end` from its footer, index and header.
+///
+/// # Returns
+/// * `io::Result<(u64, StreamIndex)>` - The stream's start and index (the blocks' `start`
+///   and `len` are left for the reader to fill in).
+fn read_stream<R: Read + Seek>(reader: &mut R, end: u64) -> io::Result<(u64, StreamIndex)> {
+    if end < 2 * STREAM_HEADER_LEN {
+        return Err(corrupt("not an XZ file, or data follows its last stream"));
+    }
+    let mut footer = [0u8; STREAM_HEADER_LEN as usize];
+    reader.seek(SeekFrom::Start(end - STREAM_HEADER_LEN))?;
+    reader.read_exact(&mut footer)?;
+    if !footer.ends_with(&FOOTER_MAGIC) {
+        return Err(corrupt("not an XZ file, or data follows its last stream"));
+    }
+    if crc32fast::hash(&footer[4..10]).to_le_bytes() != footer[..4] {
+        return Err(corrupt("the XZ stream footer is damaged"));
+    }
+    let index_len = (u64::from(u32::from_le_bytes(footer[4..8].try_into().unwrap())) + 1) * 4;
+    let stream_flags = [footer[8], footer[9]];
+
+    let index_start = (end - STREAM_HEADER_LEN)
+        .checked_sub(index_len)
+        .ok_or_else(|| corrupt("the XZ index is larger than the file"))?;
+    let mut index = vec![0u8; index_len as usize];
+    reader.seek(SeekFrom::Start(index_start))?;
+    reader.read_exact(&mut index)?;
+    let (body, crc) = index.split_at(index.len() - 4);
+    if body.first() != Some(&0) || crc32fast::hash(body).to_le_bytes() != crc {
+        return Err(corrupt("the XZ index is damaged"));
+    }
+
+    let mut fields = &body[1..];
+    let count = read_varint(&mut fields)?;
+    let mut records = Vec::new();
+    for _ in 0..count {
+        let unpadded_size = read_varint(&mut fields)?;
+        let uncompressed_size = read_varint(&mut fields)?;
+        records.push((unpadded_size, uncompressed_size));
+    }
+    if fields.len() > 3 || fields.iter().any(|&b| b != 0) {
+        return Err(corrupt("the XZ index is damaged"));
+    }
+
+    let blocks_len = records
+        .iter()
+        .try_fold(0u64, |total, &(unpadded, _)| total.checked_add(padded(unpadded)))
+        .ok_or_else(|| corrupt("the XZ index is damaged"))?;
+    let start = index_start
+        .checked_sub(blocks_len)
+        .and_then(|blocks_start| blocks_start.checked_sub(STREAM_HEADER_LEN))
+        .ok_or_else(|| corrupt("the XZ index lists more data than the file holds"))?;
+    let mut header = [0u8; STREAM_HEADER_LEN as usize];
+    reader.seek(SeekFrom::Start(start))?;
+    reader.read_exact(&mut header)?;
+    if header[..6] != XZ_MAGIC || header[6..8] != stream_flags {
+        return Err(corrupt("the XZ index does not match the stream it ends"));
+    }
+
+    let mut offset = start + STREAM_HEADER_LEN;
+    let blocks = records
+        .into_iter()
+        .map(|(unpadded_size, uncompressed_size)| {
+            let block = Block { offset, unpadded_size, uncompressed_size, stream_flags, start: 0, len: 0 };
+            offset += padded(unpadded_size);
+            block
+        })
+        .collect();
+    Ok((start, StreamIndex { size_header: None, stream_flags, blocks }))
+}
+
+/// The up to 20 bytes before `end`: enough to see a footer's magic behind rs-zip's size
+/// header, or stream padding.
+fn read_tail<R: Read + Seek>(reader: &mut R, end: u64) -> io::Result<Vec<u8>> {
+    let len = end.min(SIZE_HEADER_LEN + STREAM_HEADER_LEN);
+    let mut tail = vec![0u8; len as usize];
+    reader.seek(SeekFrom::Start(end - len))?;
+    reader.read_exact(&mut tail)?;
+    Ok(tail)
+}
+
+/// Size of a block once padded to a multiple of four bytes.
+fn padded(unpadded_size: u64) -> u64 {
+    unpadded_size.div_ceil(4) * 4
+}
+
+/// Reads one of XZ's variable-length integers: 7 bits per byte, low bits first.
+pub(crate) fn read_varint(input: &mut &[u8]) -> io::Result<u64> {
+    let mut value = 0u64;
+    for (i, &byte) in input.iter().enumerate().take(9) {
+        value |= u64::from(byte & 0x7F) << (7 * i);
+        if byte & 0x80 == 0 {
+            *input = &input[i + 1..];
+            return Ok(value);
+        }
+    }
+    Err(corrupt("the XZ index is damaged"))
+}
+
+/// Appends `value` as one of XZ's variable-length integers.
+fn write_varint(output: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        output.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    output.push(value as u8);
+}
+
+fn corrupt(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    fn xz(data: &[u8]) -> Vec<u8> {
+        // Preset 6 with a CRC-64, like `xz` by default.
+        let stream = liblzma::stream::Stream::new_easy_encoder(6, liblzma::stream::Check::Crc64).unwrap();
+        let mut encoder = liblzma::write::XzEncoder::new_stream(Vec::new(), stream);
+        encoder.write_all(data).unwrap();
+        encoder.finish().unwrap()
+    }
+
+    fn read_range(file: Vec<u8>, offset: u64, len: u64) -> Vec<u8> {
+        let mut reader = XzSeekableReader::new(Cursor::new(file)).unwrap();
+        reader.seek(SeekFrom::Start(offset)).unwrap();
+        let mut data = Vec::new();
+        reader.take(len).read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn null_bytes_between_streams_are_padding() {
+        // `xz a; 8 null bytes; xz b`: the null bytes would also pass for an empty file's size header.
+        let mut file = xz(b"aaaaaaaaaaaaaaaaaaaa\n");
+        file.extend_from_slice(&[0; 8]);
+        file.extend_from_slice(&xz(b"bbbbbbbbbbbbbbbbbbbb\n"));
+
+        assert_eq!(XzSeekableReader::new(Cursor::new(file.clone())).unwrap().len(), 42);
+        assert_eq!(read_range(file.clone(), 20, 10), b"\nbbbbbbbbb");
+        let mut decoded = Vec::new();
+        crate::decompression::decompress_stream(&file[..], &mut decoded).unwrap();
+        assert_eq!(decoded.len(), 42);
+    }
+
+    #[test]
+    fn size_headers_trim_padding_of_concatenated_files() {
+        // Two rs-zip single-file outputs, `cat`ed together: size header, then padded data.
+        let mut file = Vec::new();
+        for (size, data) in [(3u64, b"abc"), (2, b"de\0")] {
+            file.extend_from_slice(&size.to_le_bytes());
+            file.extend_from_slice(&xz(&[&data[..], &[0; 100]].concat()));
+        }
+
+        assert_eq!(read_range(file.clone(), 0, 10), b"abcde");
+        let mut decoded = Vec::new();
+        crate::decompression::decompress_stream(&file[..], &mut decoded).unwrap();
+        assert_eq!(decoded, b"abcde");
+    }
+
+    #[test]
+    fn data_after_the_last_stream_is_rejected() {
+        let mut file = xz(b"data");
+        file.extend_from_slice(b"trailing");
+        assert!(XzSeekableReader::new(Cursor::new(file)).is_err());
+    }
+}
@@ -0,0 +1,376 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use sevenz_rust::{Archive, Password, SevenZArchiveEntry, SevenZMethod, SevenZReader};
+
+use crate::crypto::Passphrase;
+use crate::decompression::{ArchiveListing, ExtractOptions};
+use crate::entry::{ArchiveEntry, EntryKind};
+use crate::file_io::{self, OutputFile};
+use crate::lzma::{self, LzmaProps};
+use crate::progress::{self, CountingReader, Tracker};
+use crate::rsz::EntryInfo;
+
+/// Windows attribute bit p7zip and 7-Zip for Linux set when the high 16 bits hold a unix mode.
+const UNIX_EXTENSION_ATTRIBUTE: u32 = 0x8000;
+/// Unix file type bits of a mode, and the type of a symlink.
+const S_IFMT: u32 = 0o170_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// Whether `path` names a 7z archive (`.7z`).
+pub fn is_7z_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("7z"))
+}
+
+/// Checks whether a 7z archive needs a password, either for its file list (encrypted
+/// headers) or for the contents of any of its files.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.7z` archive.
+///
+/// # Returns
+/// * `Result<bool, std::io::Error>` - Whether a password is needed to extract it.
+pub fn is_encrypted_7z_archive<P: AsRef<Path>>(archive_path: P) -> io::Result<bool> {
+    match open(archive_path.as_ref(), None) {
+        Ok(reader) => Ok(reader.archive().folders.iter().any(|folder| {
+            folder
+                .coders
+                .iter()
+                .any(|coder| coder.decompression_method_id() == SevenZMethod::ID_AES256SHA256)
+        })),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => Ok(true),
+        Err(e) => Err(e),
+    }
+}
+
+/// Extracts a 7z archive into the current directory. LZMA and LZMA2 archives are
+/// supported, solid or not, as are the other methods `sevenz-rust` decodes (copy, BCJ,
+/// delta and zstd) and AES-256 encryption of files and headers.
+///
+/// Files get their modification time back, and their unix permissions when the archive
+/// was created on a unix system; directories are created, and symlinks (with a warning)
+/// and "anti" items (deletion markers in update archives) are skipped.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.7z` archive.
+/// * `options` - Overwrite policy, progress reporting and, for encrypted archives, the
+///   password. `KeepNewer` compares existing files against each entry's stored time.
+///
+/// # Returns
+/// * `Result<(), std::io::Error>` - Ok if all files are extracted (or skipped by the policy);
+///   `PermissionDenied` if a password is needed but missing, `InvalidData` for corrupt
+///   data, a wrong password or names that would leave the current directory.
+pub fn extract_7z_archive<P: AsRef<Path>>(archive_path: P, options: &ExtractOptions) -> io::Result<()> {
+    let archive_path = archive_path.as_ref();
+    let archive_len = fs::metadata(archive_path)?.len();
+    let source = CountingReader::new(BufReader::new(File::open(archive_path)?));
+    let archive_bytes = source.counter();
+    let mut reader = SevenZReader::new(source, archive_len, password(options.password.as_ref())?)
+        .map_err(|e| to_io_error(e, archive_path))?;
+    check_memlimit(reader.archive(), options.memlimit)?;
+    let entry_count = reader.archive().files.len() as u64;
+    let mut tracker = Tracker::new(options.progress.as_deref(), entry_count, Some(archive_len));
+    let mut bytes_out = 0u64;
+    let mut index = 0u64;
+    // The file being written; removed if it fails to extract or its CRC is wrong.
+    let mut pending: Option<PathBuf> = None;
+    let mut failure: Option<io::Error> = None;
+
+    let result = reader.for_each_entries(|entry, data| {
+        // Returning false only ends the current folder, so later ones are skipped here.
+        if failure.is_some() {
+            return Ok(false);
+        }
+        pending = None;
+        tracker.start_entry(index, entry.name(), archive_bytes.get(), bytes_out);
+        index += 1;
+        let mut extract = || -> io::Result<()> {
+            if entry.is_anti_item() {
+                return Ok(());
+            }
+            if entry.is_directory() {
+                if let Some(path) = file_io::safe_extract_dir(entry.name())? {
+                    file_io::create_extract_dir(&path)?;
+                }
+                return Ok(());
+            }
+            // Symlinks are stored as files holding the target, marked only by their mode.
+            if entry_mode(entry).is_some_and(|mode| mode & S_IFMT == S_IFLNK) {
+                tracker.warn("symlinks are not extracted; skipped");
+                // The target is still read, as later files in a solid block follow it.
+                io::copy(data, &mut io::sink()).map_err(|e| entry_error(e, entry, options.password.is_some()))?;
+                return Ok(());
+            }
+
+            let path = file_io::safe_extract_path(entry.name())?;
+            if let Some(parent) = path.parent() {
+                file_io::create_extract_dir(parent)?;
+            }
+            let modified = entry_modified(entry);
+            let encrypted = options.password.is_some();
+            match file_io::create_extracted_file(&path, options.overwrite, modified)? {
+                Some(OutputFile { writer: mut output_file, created }) => {
+                    pending = created.then_some(path);
+                    let entry_start = bytes_out;
+                    let copied = progress::copy_with_progress(data, &mut output_file, |_, _, copied| {
+                        tracker.report(archive_bytes.get(), entry_start + copied)
+                    })
+                    .map_err(|e| entry_error(e, entry, encrypted))?;
+                    check_size(copied, entry, encrypted)?;
+                    output_file.flush()?;
+                    file_io::restore_metadata(output_file.get_ref(), entry_mode(entry), modified)?;
+                }
+                // Files in a solid block are decoded in sequence, so skipped ones are still read.
+                None => {
+                    let copied =
+                        io::copy(data, &mut io::sink()).map_err(|e| entry_error(e, entry, encrypted))?;
+                    check_size(copied, entry, encrypted)?;
+                }
+            }
+            bytes_out += entry.size();
+            Ok(())
+        };
+        match extract() {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                failure = Some(e);
+                Ok(false)
+            }
+        }
+    });
+    let result = match failure {
+        Some(e) => Err(e),
+        None => result.map_err(|e| to_io_error(e, archive_path)),
+    };
+    if result.is_err()
+        && let Some(path) = pending
+    {
+        file_io::remove_partial_output(&path, true);
+    }
+    result?;
+
+    tracker.report(archive_len, bytes_out);
+    Ok(())
+}
+
+/// Lists the files in a 7z archive from its headers, without decompressing.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.7z` archive.
+/// * `password` - Needed when the archive's headers are encrypted.
+///
+/// # Returns
+/// * `Result<ArchiveListing, std::io::Error>` - The entries (7z archives are never signed).
+pub fn list_7z_archive<P: AsRef<Path>>(archive_path: P, password: Option<&Passphrase>) -> io::Result<ArchiveListing> {
+    let reader = open(archive_path.as_ref(), password)?;
+    let entries = reader
+        .archive()
+        .files
+        .iter()
+        .filter(|entry| !entry.is_directory() && !entry.is_anti_item())
+        .map(entry_info)
+        .collect();
+    Ok(ArchiveListing { entries, signer: None })
+}
+
+/// Decompresses every file in a 7z archive without writing anything, checking each
+/// file's CRC-32.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.7z` archive.
+/// * `options` - The password (needed when the archive is encrypted) and the decoder memory limit.
+///
+/// # Returns
+/// * `Result<ArchiveListing, std::io::Error>` - The entries if every one reads back intact.
+pub fn test_7z_archive<P: AsRef<Path>>(archive_path: P, options: &ExtractOptions) -> io::Result<ArchiveListing> {
+    let archive_path = archive_path.as_ref();
+    let password = options.password.as_ref();
+    let mut reader = open(archive_path, password)?;
+    check_memlimit(reader.archive(), options.memlimit)?;
+    let mut entries = Vec::new();
+    let mut failure: Option<io::Error> = None;
+    let result = reader.for_each_entries(|entry, data| {
+        if failure.is_some() {
+            return Ok(false);
+        }
+        if entry.is_directory() || entry.is_anti_item() {
+            return Ok(true);
+        }
+        let read = io::copy(data, &mut io::sink()).map_err(|e| entry_error(e, entry, password.is_some()));
+        match read.and_then(|read| check_size(read, entry, password.is_some())) {
+            Ok(()) => {
+                entries.push(entry_info(entry));
+                Ok(true)
+            }
+            Err(e) => {
+                failure = Some(e);
+                Ok(false)
+            }
+        }
+    });
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    result.map_err(|e| to_io_error(e, archive_path))?;
+    Ok(ArchiveListing { entries, signer: None })
+}
+
+/// Reads every entry of a 7z archive in order, for conversion to another format. Anti
+/// items are left out.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.7z` archive.
+/// * `options` - The password (needed when the archive is encrypted) and the decoder memory limit.
+/// * `visit` - Called with each entry and its contents, whose size is checked.
+pub(crate) fn read_7z_entries(
+    archive_path: &Path,
+    options: &ExtractOptions,
+    visit: &mut dyn FnMut(&ArchiveEntry, &mut dyn Read) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut reader = open(archive_path, options.password.as_ref())?;
+    check_memlimit(reader.archive(), options.memlimit)?;
+    let encrypted = options.password.is_some();
+    let mut failure: Option<io::Error> = None;
+    let result = reader.for_each_entries(|entry, data| {
+        // Returning false only ends the current folder, so later ones are skipped here.
+        if failure.is_some() || entry.is_anti_item() {
+            return Ok(failure.is_none());
+        }
+        let mode = entry_mode(entry);
+        let kind = if entry.is_directory() {
+            EntryKind::Directory
+        } else if mode.is_some_and(|mode| mode & S_IFMT == S_IFLNK) {
+            // Symlinks are stored as files holding the target, marked only by their mode.
+            EntryKind::Other("symlink")
+        } else {
+            EntryKind::File
+        };
+        let info = ArchiveEntry {
+            name: entry.name().trim_end_matches('/').to_string(),
+            kind,
+            size: if kind == EntryKind::File { entry.size() } else { 0 },
+            mode: mode.map(|mode| mode & 0o7777),
+            modified: entry_modified(entry),
+            owner: None,
+        };
+        let mut counted = CountingReader::new(data);
+        let read = counted.counter();
+        // Files in a solid block are decoded in sequence, so whatever is left is still read.
+        let converted = visit(&info, &mut counted)
+            .and_then(|()| io::copy(&mut counted, &mut io::sink()))
+            .map_err(|e| entry_error(e, entry, encrypted))
+            .and_then(|_| check_size(read.get(), entry, encrypted));
+        match converted {
+            Ok(()) => Ok(true),
+            Err(e) => {
+                failure = Some(e);
+                Ok(false)
+            }
+        }
+    });
+    if let Some(e) = failure {
+        return Err(e);
+    }
+    result.map_err(|e| to_io_error(e, archive_path))
+}
+
+/// Fails if an LZMA or LZMA2 coder's dictionary is larger than `memlimit`. sevenz-rust
+/// takes no limit of its own, and the dictionary is what its decoders allocate.
+fn check_memlimit(archive: &Archive, memlimit: Option<u64>) -> io::Result<()> {
+    let Some(memlimit) = memlimit else {
+        return Ok(());
+    };
+    for coder in archive.folders.iter().flat_map(|folder| &folder.coders) {
+        let dict_size = match coder.decompression_method_id() {
+            [0x03, 0x01, 0x01] => coder.properties.get(1..5).map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            [0x21] => coder.properties.first().and_then(|&b| LzmaProps::from_lzma2_byte(b).ok()).map(|p| p.dict_size),
+            _ => None,
+        };
+        if let Some(dict_size) = dict_size
+            && u64::from(dict_size) > memlimit
+        {
+            return Err(lzma::memlimit_exceeded(dict_size.into(), memlimit));
+        }
+    }
+    Ok(())
+}
+
+fn open(archive_path: &Path, password: Option<&Passphrase>) -> io::Result<SevenZReader<BufReader<File>>> {
+    let file = File::open(archive_path)?;
+    let len = file.metadata()?.len();
+    SevenZReader::new(BufReader::new(file), len, self::password(password)?).map_err(|e| to_io_error(e, archive_path))
+}
+
+/// 7z keys are derived from the UTF-16 form of the password, so it must be valid UTF-8.
+fn password(password: Option<&Passphrase>) -> io::Result<Password> {
+    let Some(password) = password else {
+        return Ok(Password::empty());
+    };
+    let text = std::str::from_utf8(password.as_bytes()).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "7z passwords must be valid UTF-8")
+    })?;
+    Ok(Password::from(text))
+}
+
+fn entry_info(entry: &SevenZArchiveEntry) -> EntryInfo {
+    EntryInfo {
+        name: entry.name().to_string(),
+        size: entry.size(),
+        block: 0,
+        offset: 0,
+        sha256: None,
+        extents: Vec::new(),
+    }
+}
+
+fn entry_modified(entry: &SevenZArchiveEntry) -> Option<SystemTime> {
+    entry
+        .has_last_modified_date
+        .then(|| SystemTime::from(entry.last_modified_date()))
+}
+
+fn entry_mode(entry: &SevenZArchiveEntry) -> Option<u32> {
+    let attributes = entry.windows_attributes();
+    (entry.has_windows_attributes && attributes & UNIX_EXTENSION_ATTRIBUTE != 0).then_some(attributes >> 16)
+}
+
+/// The decoder can end early on corrupt data without an error, so the size is checked too.
+fn check_size(read: u64, entry: &SevenZArchiveEntry, encrypted: bool) -> io::Result<()> {
+    if read == entry.size() {
+        return Ok(());
+    }
+    let e = io::Error::new(io::ErrorKind::UnexpectedEof, "data ends early");
+    Err(entry_error(e, entry, encrypted))
+}
+
+/// Names the entry in a read error; with a password, a wrong one is the likeliest cause.
+fn entry_error(e: io::Error, entry: &SevenZArchiveEntry, encrypted: bool) -> io::Error {
+    let hint = if encrypted { " (wrong password?)" } else { "" };
+    io::Error::new(e.kind(), format!("{}: {}{}", entry.name(), e, hint))
+}
+
+fn to_io_error(e: sevenz_rust::Error, archive_path: &Path) -> io::Error {
+    match e {
+        sevenz_rust::Error::Io(e, _) | sevenz_rust::Error::FileOpen(e, _) => e,
+        sevenz_rust::Error::PasswordRequired => io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("{} is encrypted; a password is needed", archive_path.display()),
+        ),
+        sevenz_rust::Error::MaybeBadPassword(e) => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {} (wrong password?)", archive_path.display(), e),
+        ),
+        sevenz_rust::Error::UnsupportedCompressionMethod(method) => io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} uses the unsupported 7z method {}", archive_path.display(), method),
+        ),
+        e => io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {}", archive_path.display(), e),
+        ),
+    }
+}
@@ -0,0 +1,274 @@
+use std::fmt;
+use std::io;
+use std::str::FromStr;
+
+use bech32::{Bech32, Hrp};
+use ed25519_dalek::Signer;
+use zeroize::Zeroizing;
+
+use crate::crypto;
+
+/// Length of an Ed25519 public key in bytes.
+pub const PUBLIC_KEY_LEN: usize = 32;
+/// Length of an Ed25519 signature in bytes.
+pub const SIGNATURE_LEN: usize = 64;
+
+/// Bech32 prefix of verifying (public) keys.
+const VERIFYING_HRP: &str = "rszsign";
+/// Bech32 prefix of signing (private) keys, written in upper case.
+const SIGNING_HRP: &str = "rsz-signing-key-";
+/// Domain separator so archive signatures cannot be confused with other Ed25519 messages.
+const CONTEXT: &[u8] = b"rs-zip/v2/signature\0";
+
+/// An Ed25519 private key used to sign RSZ archives, written as `RSZ-SIGNING-KEY-1...`.
+/// The key is wiped from memory when dropped.
+#[derive(Clone)]
+pub struct SigningKey(ed25519_dalek::SigningKey);
+
+impl SigningKey {
+    /// Generates a new random signing key.
+    pub fn generate() -> Self {
+        let seed = Zeroizing::new(crypto::random_bytes::<32>());
+        SigningKey(ed25519_dalek::SigningKey::from_bytes(&seed))
+    }
+
+    /// Returns the public key that verifies this key's signatures.
+    pub fn verifying_key(&self) -> VerifyingKey {
+        VerifyingKey(self.0.verifying_key())
+    }
+
+    /// Returns the key in its text form, `RSZ-SIGNING-KEY-1...`.
+    pub fn to_secret_string(&self) -> Zeroizing<String> {
+        let hrp = Hrp::parse_unchecked(SIGNING_HRP);
+        let bytes = Zeroizing::new(self.0.to_bytes());
+        Zeroizing::new(
+            bech32::encode_upper::<Bech32>(hrp, bytes.as_ref()).expect("32-byte keys always encode"),
+        )
+    }
+
+    /// Signs an archive's header and stored index.
+    pub(crate) fn sign_archive(&self, header: &[u8], index: &[u8]) -> [u8; SIGNATURE_LEN] {
+        self.0.sign(&signed_message(header, index)).to_bytes()
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SigningKey({})", self.verifying_key())
+    }
+}
+
+impl FromStr for SigningKey {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let bytes = crypto::decode_key(s.trim(), SIGNING_HRP)
+            .ok_or_else(|| invalid_input("invalid signing key (expected RSZ-SIGNING-KEY-1...)".to_string()))?;
+        Ok(SigningKey(ed25519_dalek::SigningKey::from_bytes(&bytes)))
+    }
+}
+
+/// An Ed25519 public key that archive signatures are checked against, written as `rszsign1...`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct VerifyingKey(ed25519_dalek::VerifyingKey);
+
+impl VerifyingKey {
+    /// Returns the raw 32-byte public key.
+    pub fn to_bytes(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.0.to_bytes()
+    }
+
+    /// Parses a raw 32-byte public key, as stored in an archive.
+    pub fn from_bytes(bytes: &[u8; PUBLIC_KEY_LEN]) -> io::Result<Self> {
+        ed25519_dalek::VerifyingKey::from_bytes(bytes)
+            .map(VerifyingKey)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "archive signer key is malformed"))
+    }
+
+    /// Checks a signature made by [`SigningKey::sign_archive`].
+    ///
+    /// # Returns
+    /// * `io::Result<()>` - Ok if the signature is valid, an `InvalidData` error otherwise.
+    pub(crate) fn verify_archive(&self, header: &[u8], index: &[u8], signature: &[u8; SIGNATURE_LEN]) -> io::Result<()> {
+        let signature = ed25519_dalek::Signature::from_bytes(signature);
+        self.0
+            .verify_strict(&signed_message(header, index), &signature)
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "archive signature is invalid (the archive was modified after signing)",
+                )
+            })
+    }
+}
+
+impl fmt::Display for VerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hrp = Hrp::parse_unchecked(VERIFYING_HRP);
+        let encoded = bech32::encode::<Bech32>(hrp, self.0.as_bytes()).map_err(|_| fmt::Error)?;
+        f.write_str(&encoded)
+    }
+}
+
+impl fmt::Debug for VerifyingKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "VerifyingKey({})", self)
+    }
+}
+
+impl FromStr for VerifyingKey {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        let invalid = || invalid_input(format!("invalid verifying key: {}", s.trim()));
+        let bytes = crypto::decode_key(s.trim(), VERIFYING_HRP).ok_or_else(invalid)?;
+        ed25519_dalek::VerifyingKey::from_bytes(&bytes)
+            .map(VerifyingKey)
+            .map_err(|_| invalid())
+    }
+}
+
+/// Parses a signing key file: a single `RSZ-SIGNING-KEY-1...` line, with blank lines and
+/// `#` comments ignored.
+pub fn parse_signing_key(text: &str) -> io::Result<SigningKey> {
+    let mut lines = crypto::key_lines(text);
+    let key = lines
+        .next()
+        .ok_or_else(|| invalid_input("no signing key found".to_string()))?
+        .parse()?;
+    if lines.next().is_some() {
+        return Err(invalid_input("expected a single signing key".to_string()));
+    }
+    Ok(key)
+}
+
+/// Parses a file of trusted verifying keys: one `rszsign1...` per line, with blank lines
+/// and `#` comments ignored.
+pub fn parse_verifying_keys(text: &str) -> io::Result<Vec<VerifyingKey>> {
+    let keys = crypto::key_lines(text)
+        .map(VerifyingKey::from_str)
+        .collect::<io::Result<Vec<_>>>()?;
+    if keys.is_empty() {
+        return Err(invalid_input("no verifying keys found".to_string()));
+    }
+    Ok(keys)
+}
+
+/// The exact bytes an archive signature covers: a context string, then the header and the
+/// stored (compressed, possibly encrypted) index, each prefixed with its length.
+fn signed_message(header: &[u8], index: &[u8]) -> Vec<u8> {
+    let mut message = Vec::with_capacity(CONTEXT.len() + 16 + header.len() + index.len());
+    message.extend_from_slice(CONTEXT);
+    message.extend_from_slice(&(header.len() as u64).to_le_bytes());
+    message.extend_from_slice(header);
+    message.extend_from_slice(&(index.len() as u64).to_le_bytes());
+    message.extend_from_slice(index);
+    message
+}
+
+fn invalid_input(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use crate::compression::{self, CompressOptions};
+    use crate::decompression::{self, ExtractOptions};
+
+    fn write_archive(dir: &Path, signing_key: Option<SigningKey>) -> PathBuf {
+        let file = dir.join("a.txt");
+        fs::write(&file, "signed contents\n").unwrap();
+        let output = dir.join("test.rsz");
+        let options = CompressOptions {
+            signing_key,
+            ..CompressOptions::default()
+        };
+        compression::create_padded_archive(&[file], output.to_str().unwrap(), &options).unwrap();
+        output
+    }
+
+    fn trusting(signers: Vec<VerifyingKey>) -> ExtractOptions {
+        ExtractOptions {
+            signers,
+            ..ExtractOptions::default()
+        }
+    }
+
+    #[test]
+    fn signature_covers_header_and_index() {
+        let key = SigningKey::generate();
+        let signature = key.sign_archive(b"header", b"index");
+        let verifying = key.verifying_key();
+
+        assert!(verifying.verify_archive(b"header", b"index", &signature).is_ok());
+        assert!(verifying.verify_archive(b"header", b"indeX", &signature).is_err());
+        assert!(verifying.verify_archive(b"headeR", b"index", &signature).is_err());
+        // The lengths are signed too, so bytes cannot move from the header to the index.
+        assert!(verifying.verify_archive(b"heade", b"rindex", &signature).is_err());
+        let other = SigningKey::generate().verifying_key();
+        assert!(other.verify_archive(b"header", b"index", &signature).is_err());
+    }
+
+    #[test]
+    fn keys_survive_their_text_form() {
+        let key = SigningKey::generate();
+        let verifying = key.verifying_key();
+        assert!(verifying.to_string().starts_with("rszsign1"));
+
+        let parsed = parse_signing_key(&format!("# release key\n{}\n", key.to_secret_string().as_str())).unwrap();
+        assert_eq!(parsed.verifying_key(), verifying);
+        let keys = parse_verifying_keys(&format!("{}\n\n{}\n", verifying, SigningKey::generate().verifying_key())).unwrap();
+        assert_eq!(keys.len(), 2);
+        assert_eq!(keys[0], verifying);
+
+        assert!(verifying.to_string().parse::<SigningKey>().is_err());
+        assert!(parse_signing_key(&format!("{0}\n{0}\n", key.to_secret_string().as_str())).is_err());
+    }
+
+    #[test]
+    fn trusted_signer_is_accepted() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SigningKey::generate();
+        let archive = write_archive(dir.path(), Some(key.clone()));
+
+        let listing = decompression::test_archive(&archive, &trusting(vec![key.verifying_key()])).unwrap();
+        assert_eq!(listing.signer, Some(key.verifying_key()));
+        // Without trusted keys the signature is still checked and reported.
+        let listing = decompression::test_archive(&archive, &ExtractOptions::default()).unwrap();
+        assert_eq!(listing.signer, Some(key.verifying_key()));
+    }
+
+    #[test]
+    fn untrusted_or_missing_signer_is_rejected() {
+        let (dir, other_dir) = (tempfile::tempdir().unwrap(), tempfile::tempdir().unwrap());
+        let trusted = vec![SigningKey::generate().verifying_key()];
+
+        let signed = write_archive(dir.path(), Some(SigningKey::generate()));
+        let err = decompression::test_archive(&signed, &trusting(trusted.clone())).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+
+        let unsigned = write_archive(other_dir.path(), None);
+        let err = decompression::test_archive(&unsigned, &trusting(trusted)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+    }
+
+    #[test]
+    fn modified_archive_fails_verification() {
+        let dir = tempfile::tempdir().unwrap();
+        let key = SigningKey::generate();
+        let archive = write_archive(dir.path(), Some(key.clone()));
+
+        // The last index byte sits just before the signer key, signature and trailer.
+        let mut bytes = fs::read(&archive).unwrap();
+        let at = bytes.len() - (PUBLIC_KEY_LEN + SIGNATURE_LEN + 20) - 1;
+        bytes[at] ^= 1;
+        fs::write(&archive, bytes).unwrap();
+        let err = decompression::test_archive(&archive, &ExtractOptions::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("signature"), "{}", err);
+    }
+}
@@ -0,0 +1,111 @@
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use clap::ValueEnum;
+
+use crate::lzma;
+
+/// How RSZ archives split their files into solid blocks, selected with `--solid`. Files are
+/// never split between blocks; a block is decoded from its start to reach any file in it,
+/// so smaller blocks bound the work of reading one file at some cost in ratio.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SolidBlocks {
+    /// One block for all files (`on`), apart from the blocks that stored files and codec
+    /// variants need.
+    #[default]
+    Single,
+    /// A new block once the current one holds at least this many bytes (`SIZE`, e.g. `64MiB`).
+    Size(u64),
+    /// A block per directory (`dir`).
+    Directory,
+    /// A block per file (`file`), like a non-solid archive.
+    File,
+}
+
+impl SolidBlocks {
+    /// Whether a non-empty file named `name` should start a new block rather than join the
+    /// current one.
+    ///
+    /// # Arguments
+    /// * `block_len` - Bytes already in the current block.
+    /// * `block_dir` - Directory of the files in the current block.
+    /// * `name` - The next file's archive name.
+    pub(crate) fn starts_block(self, block_len: u64, block_dir: &str, name: &str) -> bool {
+        match self {
+            SolidBlocks::Single => false,
+            SolidBlocks::Size(size) => block_len >= size,
+            SolidBlocks::Directory => block_dir != parent(name),
+            SolidBlocks::File => block_len > 0,
+        }
+    }
+}
+
+impl FromStr for SolidBlocks {
+    type Err = io::Error;
+
+    fn from_str(s: &str) -> io::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "on" => Ok(SolidBlocks::Single),
+            "dir" => Ok(SolidBlocks::Directory),
+            "file" | "off" => Ok(SolidBlocks::File),
+            _ => match lzma::parse_size(s) {
+                Ok(0) => Err(io::Error::new(io::ErrorKind::InvalidInput, "solid block size must not be 0")),
+                Ok(size) => Ok(SolidBlocks::Size(size)),
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("expected on, dir, file or a block size such as 64MiB, got {}", s),
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for SolidBlocks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SolidBlocks::Single => write!(f, "on"),
+            SolidBlocks::Size(size) => write!(f, "{}", size),
+            SolidBlocks::Directory => write!(f, "dir"),
+            SolidBlocks::File => write!(f, "file"),
+        }
+    }
+}
+
+/// The order files are written to an RSZ archive in, selected with `--order`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileOrder {
+    /// Group files by extension, then by name, so similar files share a block and
+    /// compress against each other (as 7-Zip does)
+    #[default]
+    Type,
+    /// Keep the order the inputs were given and walked in
+    Input,
+}
+
+/// Sorts archive names into the order `order` asks for. With `solid` set to per-directory
+/// blocks, each directory's files stay together so it gets a single block.
+pub fn sort_names<T>(items: &mut [T], name: impl Fn(&T) -> &str, order: FileOrder, solid: SolidBlocks) {
+    if order == FileOrder::Input {
+        return;
+    }
+    items.sort_by_cached_key(|item| {
+        let name = name(item);
+        let dir = if solid == SolidBlocks::Directory { parent(name) } else { "" };
+        let (extension, stem) = type_key(name);
+        (dir.to_string(), extension, stem, name.to_string())
+    });
+}
+
+/// The directory part of an archive name (empty for top-level files).
+pub(crate) fn parent(name: &str) -> &str {
+    Path::new(name).parent().and_then(|p| p.to_str()).unwrap_or_default()
+}
+
+/// Extension, then file stem, both ignoring case: `lib/a.min.js` sorts as `("js", "a.min")`.
+fn type_key(name: &str) -> (String, String) {
+    let path = Path::new(name);
+    let lower = |s: Option<&std::ffi::OsStr>| s.and_then(|s| s.to_str()).unwrap_or_default().to_lowercase();
+    (lower(path.extension()), lower(path.file_stem()))
+}
@@ -0,0 +1,134 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+
+/// How much of the start of a file the probe looks at.
+pub const PROBE_LEN: usize = 64 * 1024;
+/// Files shorter than this are never probed: there is too little data to judge, and
+/// compressing them costs next to nothing.
+const MIN_PROBE_LEN: usize = 4096;
+/// Below this many bits of entropy per byte, data is assumed to compress.
+const MIN_ENTROPY: f64 = 7.5;
+/// A trial compression must save at least this share of the sample (in percent) for the
+/// file to be compressed.
+const MIN_SAVING_PERCENT: usize = 3;
+
+/// Extensions of formats that are compressed already (media, archives, packages).
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "jpg", "jpeg", "png", "gif", "webp", "heic", "heif", "avif", "jxl", "mp4", "m4v", "m4a", "mov", "mkv",
+    "webm", "avi", "mp3", "aac", "ogg", "oga", "opus", "flac", "wma", "wmv", "zip", "jar", "apk", "docx",
+    "xlsx", "pptx", "odt", "ods", "epub", "gz", "tgz", "bz2", "tbz2", "xz", "txz", "lzma", "zst", "lz4",
+    "7z", "rar", "rsz", "woff", "woff2",
+];
+
+/// Magic bytes of formats that are compressed already, with their offset in the file.
+const COMPRESSED_MAGIC: &[(usize, &[u8])] = &[
+    (0, &[0xFF, 0xD8, 0xFF]),                   // JPEG
+    (0, b"\x89PNG\r\n\x1a\n"),                  // PNG
+    (0, b"GIF8"),                               // GIF
+    (8, b"WEBP"),                               // WebP (in a RIFF container)
+    (4, b"ftyp"),                               // MP4, MOV, HEIF, AVIF
+    (0, &[0x1A, 0x45, 0xDF, 0xA3]),             // Matroska, WebM
+    (0, b"ID3"),                                // MP3 with ID3 tags
+    (0, b"OggS"),                               // Ogg
+    (0, b"fLaC"),                               // FLAC
+    (0, b"PK\x03\x04"),                         // ZIP and its offspring (JAR, APK, DOCX, ...)
+    (0, &[0x1F, 0x8B]),                         // gzip
+    (0, b"BZh"),                                // bzip2
+    (0, &[0xFD, b'7', b'z', b'X', b'Z', 0x00]), // XZ
+    (0, &[0x28, 0xB5, 0x2F, 0xFD]),             // zstd
+    (0, &[0x04, 0x22, 0x4D, 0x18]),             // LZ4
+    (0, &[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C]), // 7z
+    (0, b"Rar!\x1a\x07"),                       // RAR
+    (0, b"RSZ\0"),                              // RSZ
+    (0, b"wOF2"),                               // WOFF2
+];
+
+/// Which archive entries are stored as they are rather than compressed, selected with
+/// `--store`.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StorePolicy {
+    /// Compress every file
+    Never,
+    /// Store files whose extension or magic bytes mark them as compressed already
+    Known,
+    /// Like `known`, and also store files whose first 64 KiB look random and don't shrink
+    /// in a quick trial compression
+    #[default]
+    Auto,
+}
+
+/// Decides per entry whether to store it: JPEGs, videos and nested archives barely shrink,
+/// and compressing them with LZMA takes far longer than copying them.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StoreRules {
+    pub policy: StorePolicy,
+    /// More extensions to store (without the dot, any case), on top of the built-in list.
+    pub extensions: Vec<String>,
+}
+
+impl StoreRules {
+    /// Rules that compress everything.
+    pub fn never() -> Self {
+        StoreRules {
+            policy: StorePolicy::Never,
+            extensions: Vec::new(),
+        }
+    }
+
+    /// Whether to store the entry `name`.
+    ///
+    /// # Arguments
+    /// * `name` - The entry's name, for its extension.
+    /// * `head` - The start of its contents (up to [`PROBE_LEN`] bytes).
+    ///
+    /// # Returns
+    /// * `bool` - true if compressing the entry is not worth it.
+    pub fn should_store(&self, name: &str, head: &[u8]) -> bool {
+        match self.policy {
+            StorePolicy::Never => false,
+            StorePolicy::Known => self.is_known(name, head),
+            StorePolicy::Auto => self.is_known(name, head) || incompressible(head),
+        }
+    }
+
+    fn is_known(&self, name: &str, head: &[u8]) -> bool {
+        let extension = Path::new(name).extension().and_then(|e| e.to_str()).unwrap_or_default();
+        let listed = |known: &str| known.eq_ignore_ascii_case(extension);
+        (!extension.is_empty()
+            && (COMPRESSED_EXTENSIONS.iter().any(|e| listed(e)) || self.extensions.iter().any(|e| listed(e))))
+            || COMPRESSED_MAGIC
+                .iter()
+                .any(|(offset, magic)| head.get(*offset..).is_some_and(|rest| rest.starts_with(magic)))
+    }
+}
+
+/// Whether `sample` looks incompressible: nearly random bytes that a fast zstd pass can't
+/// shrink by [`MIN_SAVING_PERCENT`].
+fn incompressible(sample: &[u8]) -> bool {
+    if sample.len() < MIN_PROBE_LEN || entropy(sample) < MIN_ENTROPY {
+        return false;
+    }
+    // The entropy of single bytes misses repeats of longer strings; a trial run doesn't.
+    match zstd::bulk::compress(sample, 1) {
+        Ok(compressed) => compressed.len() * 100 >= sample.len() * (100 - MIN_SAVING_PERCENT),
+        Err(_) => false,
+    }
+}
+
+/// Shannon entropy of the bytes in `data`, in bits per byte (0-8).
+fn entropy(data: &[u8]) -> f64 {
+    let mut counts = [0usize; 256];
+    for &byte in data {
+        counts[usize::from(byte)] += 1;
+    }
+    let len = data.len() as f64;
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
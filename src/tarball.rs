@@ -0,0 +1,441 @@
+use std::cell::Cell;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use tar::{Archive, Builder, EntryType, Header};
+
+use crate::codec::{self, Codec, Encoder, PassThrough, SharedWriter};
+use crate::compression::CompressOptions;
+use crate::decompression::{self, ArchiveListing, ExtractOptions};
+use crate::entry::{ArchiveEntry, EntryKind};
+use crate::file_io::{self, OutputFile};
+use crate::progress::{self, CountingReader, CountingWriter, Tracker};
+use crate::rsz::EntryInfo;
+
+// tar (ustar) layout: every entry is a 512-byte header followed by its data, padded to
+// a multiple of 512 bytes. Names too long for the header go in a preceding pax header.
+const BLOCK_LEN: u64 = 512;
+/// Short suffixes that name a compressed tar archive.
+const SHORT_SUFFIXES: [&str; 5] = [".tgz", ".txz", ".tbz2", ".tzst", ".tlz4"];
+
+/// Whether `path` names a tar archive: `.tar`, `.tar.<ext>` for any registered codec's
+/// extension, or one of `.tgz`, `.txz`, `.tbz2`, `.tzst` and `.tlz4`.
+pub fn is_tar_path(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let name = name.to_ascii_lowercase();
+    if name.ends_with(".tar") || SHORT_SUFFIXES.iter().any(|suffix| name.ends_with(suffix)) {
+        return true;
+    }
+    codec::registered()
+        .iter()
+        .any(|codec| name.ends_with(&format!(".tar.{}", codec.extension())))
+}
+
+/// The codec a tar archive's name implies: `.tar.<ext>` or the short `.t<ext>` form of a
+/// registered codec's extension. `None` for a plain `.tar` or an unknown suffix.
+pub fn tar_codec(path: &Path) -> Option<Arc<dyn Codec>> {
+    let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+    codec::registered().into_iter().find(|codec| {
+        name.ends_with(&format!(".tar.{}", codec.extension())) || name.ends_with(&format!(".t{}", codec.extension()))
+    })
+}
+
+/// Creates a tar archive (ustar headers, pax headers for long names) from a list of files,
+/// storing each file's mode, owner and modification time. The archive is compressed with
+/// `options.codec` unless `output_path` ends in `.tar`.
+///
+/// # Arguments
+/// * `files` - The files to archive, e.g. from `file_io::collect_files`. They are stored
+///   under their paths with any leading `/` removed.
+/// * `output_path` - Path to the output archive file.
+/// * `options` - Codec, compression level, overwrite policy, progress and source removal.
+///   Encryption and signing need RSZ and are rejected.
+///
+/// # Returns
+/// * `Result<(), io::Error>` - Ok on success (including when the policy skips the output).
+pub fn create_tar_archive(
+    files: &[PathBuf],
+    output_path: &str,
+    options: &CompressOptions,
+) -> io::Result<()> {
+    TarWriter::check_options(options)?;
+    let entries = files
+        .iter()
+        .map(|path| Ok((path.clone(), file_io::archive_entry_name(path)?)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // The newest input decides whether an existing archive counts as up to date.
+    let source_modified = files
+        .iter()
+        .filter_map(|p| p.metadata().and_then(|m| m.modified()).ok())
+        .max();
+    let Some(OutputFile { writer: output_file, created }) =
+        file_io::create_output_file_with_policy(Path::new(output_path), options.overwrite, source_modified)?
+    else {
+        return Ok(());
+    };
+
+    let total_in = files
+        .iter()
+        .filter_map(|p| p.metadata().ok())
+        .map(|m| m.len())
+        .sum();
+    let result = TarWriter::new(output_file, Path::new(output_path), options, entries.len() as u64, Some(total_in))
+        .and_then(|mut writer| {
+            for (path, name) in &entries {
+                let mut file = File::open(path)?;
+                let entry = ArchiveEntry::from_metadata(name.clone(), &file.metadata()?);
+                writer.add_file(&entry, &mut file)?;
+            }
+            writer.finish()
+        });
+    if let Err(e) = result {
+        // Don't leave a truncated archive behind that looks like a finished one.
+        file_io::remove_partial_output(Path::new(output_path), created);
+        return Err(e);
+    }
+
+    if options.remove_sources {
+        File::open(output_path)?.sync_all()?;
+        verify_tar(output_path, &entries)?;
+        for file_path in files {
+            fs::remove_file(file_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a tar archive one entry at a time, compressed with the options' codec unless
+/// the output is a plain `.tar`.
+pub(crate) struct TarWriter<'a> {
+    builder: Builder<Box<dyn Encoder>>,
+    output: SharedWriter<CountingWriter<BufWriter<File>>>,
+    written: Rc<Cell<u64>>,
+    tracker: Tracker<'a>,
+    entries: u64,
+    bytes_in: u64,
+}
+
+impl<'a> TarWriter<'a> {
+    /// Rejects the options tar cannot honour (encryption and signing).
+    pub(crate) fn check_options(options: &CompressOptions) -> io::Result<()> {
+        if options.password.is_some() || !options.recipients.is_empty() || options.signing_key.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "tar archives cannot be encrypted or signed; use RSZ",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Starts an archive in `output`, which was created for `output_path`.
+    pub(crate) fn new(
+        output: BufWriter<File>,
+        output_path: &Path,
+        options: &'a CompressOptions,
+        entry_count: u64,
+        total_in: Option<u64>,
+    ) -> io::Result<Self> {
+        let output = CountingWriter::new(output);
+        let written = output.counter();
+        let output = SharedWriter::new(output);
+        let plain = output_path
+            .to_string_lossy()
+            .to_ascii_lowercase()
+            .ends_with(".tar");
+        let encoder: Box<dyn Encoder> = if plain {
+            Box::new(PassThrough(output.handle()))
+        } else {
+            options.codec.encoder(Box::new(output.handle()), options.level)?
+        };
+        Ok(TarWriter {
+            builder: Builder::new(encoder),
+            output,
+            written,
+            tracker: Tracker::new(options.progress.as_deref(), entry_count, total_in),
+            entries: 0,
+            bytes_in: 0,
+        })
+    }
+
+    /// Appends a regular file, copying exactly `entry.size` bytes from `data`.
+    pub(crate) fn add_file(&mut self, entry: &ArchiveEntry, data: &mut dyn Read) -> io::Result<()> {
+        self.tracker.start_entry(self.entries, &entry.name, self.bytes_in, self.written.get());
+        self.append_header(entry, EntryType::Regular, &entry.name)?;
+
+        // Copy exactly the size recorded in the header, even if the source changes meanwhile.
+        let size = entry.size;
+        let out = self.builder.get_mut();
+        let entry_start = self.bytes_in;
+        let (tracker, written) = (&self.tracker, &self.written);
+        let copied = progress::copy_with_progress(&mut data.take(size), out, |_, _, copied| {
+            tracker.report(entry_start + copied, written.get())
+        })?;
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrank while it was being archived", entry.name),
+            ));
+        }
+        let padding = (BLOCK_LEN - size % BLOCK_LEN) % BLOCK_LEN;
+        out.write_all(&[0u8; BLOCK_LEN as usize][..padding as usize])?;
+        self.bytes_in += size;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Appends a directory entry.
+    pub(crate) fn add_directory(&mut self, entry: &ArchiveEntry) -> io::Result<()> {
+        self.tracker.start_entry(self.entries, &entry.name, self.bytes_in, self.written.get());
+        self.append_header(entry, EntryType::Directory, &format!("{}/", entry.name.trim_end_matches('/')))?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// Writes the end-of-archive blocks and finishes the codec stream.
+    pub(crate) fn finish(self) -> io::Result<()> {
+        self.builder.into_inner()?.finish()?;
+        self.output.into_inner()?.flush()?;
+        self.tracker.report(self.bytes_in, self.written.get());
+        Ok(())
+    }
+
+    fn append_header(&mut self, entry: &ArchiveEntry, kind: EntryType, name: &str) -> io::Result<()> {
+        let mut header = Header::new_ustar();
+        header.set_entry_type(kind);
+        header.set_size(if kind.is_dir() { 0 } else { entry.size });
+        let default_mode = if kind.is_dir() { 0o755 } else { 0o644 };
+        header.set_mode(entry.mode.unwrap_or(default_mode));
+        let mtime = entry
+            .modified
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+        header.set_mtime(mtime);
+        if let Some((uid, gid)) = entry.owner {
+            header.set_uid(uid);
+            header.set_gid(gid);
+        }
+        if header.set_path(name).is_err() {
+            // Too long for ustar's name and prefix fields: the pax header carries the full
+            // name and the ustar header keeps as much of it as fits.
+            self.builder.append_pax_extensions([("path", name.as_bytes())])?;
+            let field = &mut header.as_old_mut().name;
+            let mut len = name.len().min(field.len());
+            while !name.is_char_boundary(len) {
+                len -= 1;
+            }
+            field.fill(0);
+            field[..len].copy_from_slice(&name.as_bytes()[..len]);
+        }
+        header.set_cksum();
+        self.builder.get_mut().write_all(header.as_bytes())
+    }
+}
+
+/// Extracts a tar archive (plain or compressed with any registered codec) into the current
+/// directory. Regular files get their stored permissions (without set-id bits) and
+/// modification time back; directories are created; links and special files are skipped.
+///
+/// # Arguments
+/// * `archive_path` - Path to the tar archive.
+/// * `options` - Overwrite policy and progress reporting. `KeepNewer` compares existing
+///   files against each entry's stored modification time.
+///
+/// # Returns
+/// * `Result<(), std::io::Error>` - Ok if all files are extracted (or skipped by the policy);
+///   `InvalidData` for entries whose names would leave the current directory.
+pub fn extract_tar_archive<P: AsRef<Path>>(archive_path: P, options: &ExtractOptions) -> io::Result<()> {
+    let file = File::open(archive_path)?;
+    let archive_len = file.metadata()?.len();
+    let mut tracker = Tracker::new(options.progress.as_deref(), 0, Some(archive_len));
+    let mut bytes_out = 0u64;
+    // The file being written; removed if it fails to extract.
+    let mut pending: Option<PathBuf> = None;
+
+    let result = for_each_tar_entry(file, options.memlimit, |index, entry, archive_bytes| {
+        pending = None;
+        let name = entry.path()?.to_string_lossy().into_owned();
+        tracker.set_entry_count(index + 1);
+        tracker.start_entry(index, &name, archive_bytes.get(), bytes_out);
+
+        let kind = entry.header().entry_type();
+        if kind.is_dir() {
+            if let Some(path) = file_io::safe_extract_dir(&name)? {
+                file_io::create_extract_dir(&path)?;
+            }
+            return Ok(());
+        }
+        if !matches!(kind, EntryType::Regular | EntryType::Continuous) {
+            return Ok(());
+        }
+
+        let path = file_io::safe_extract_path(&name)?;
+        if let Some(parent) = path.parent() {
+            file_io::create_extract_dir(parent)?;
+        }
+        let mode = entry.header().mode().ok();
+        let modified = entry.header().mtime().ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+        if let Some(OutputFile { writer: mut output_file, created }) =
+            file_io::create_extracted_file(&path, options.overwrite, modified)?
+        {
+            pending = created.then_some(path);
+            let entry_start = bytes_out;
+            progress::copy_with_progress(entry, &mut output_file, |_, _, copied| {
+                tracker.report(archive_bytes.get(), entry_start + copied)
+            })?;
+            output_file.flush()?;
+            file_io::restore_metadata(output_file.get_ref(), mode, modified)?;
+        }
+        bytes_out += entry.size();
+        Ok(())
+    });
+    if result.is_err()
+        && let Some(path) = pending
+    {
+        file_io::remove_partial_output(&path, true);
+    }
+    result?;
+
+    tracker.report(archive_len, bytes_out);
+    Ok(())
+}
+
+/// Lists the regular files in a tar archive. Compressed archives are decompressed to find
+/// the entries, but nothing is written.
+///
+/// # Arguments
+/// * `archive_path` - Path to the tar archive.
+/// * `options` - The decoder memory limit.
+///
+/// # Returns
+/// * `Result<ArchiveListing, std::io::Error>` - The entries (tar archives are never signed).
+pub fn list_tar_archive<P: AsRef<Path>>(archive_path: P, options: &ExtractOptions) -> io::Result<ArchiveListing> {
+    let mut entries = Vec::new();
+    for_each_tar_entry(File::open(archive_path)?, options.memlimit, |_, entry, _| {
+        if matches!(entry.header().entry_type(), EntryType::Regular | EntryType::Continuous) {
+            entries.push(EntryInfo {
+                name: entry.path()?.to_string_lossy().into_owned(),
+                size: entry.size(),
+                block: 0,
+                offset: 0,
+                sha256: None,
+                extents: Vec::new(),
+            });
+        }
+        Ok(())
+    })?;
+    Ok(ArchiveListing { entries, signer: None })
+}
+
+/// Reads a whole tar archive without extracting it, so truncation and corruption caught
+/// by the codec's own checks are reported.
+///
+/// # Arguments
+/// * `archive_path` - Path to the tar archive.
+/// * `options` - The decoder memory limit.
+///
+/// # Returns
+/// * `Result<ArchiveListing, std::io::Error>` - The entries if the archive reads cleanly.
+pub fn test_tar_archive<P: AsRef<Path>>(archive_path: P, options: &ExtractOptions) -> io::Result<ArchiveListing> {
+    let mut entries = Vec::new();
+    for_each_tar_entry(File::open(archive_path)?, options.memlimit, |_, entry, _| {
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let size = entry.size();
+        let read = io::copy(entry, &mut io::sink())?;
+        if read != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("archive is truncated inside {}", name),
+            ));
+        }
+        if matches!(entry.header().entry_type(), EntryType::Regular | EntryType::Continuous) {
+            entries.push(EntryInfo { name, size, block: 0, offset: 0, sha256: None, extents: Vec::new() });
+        }
+        Ok(())
+    })?;
+    Ok(ArchiveListing { entries, signer: None })
+}
+
+/// Reads every entry of a tar archive in order, for conversion to another format. Links
+/// and special files are passed on as `EntryKind::Other` with no data.
+///
+/// # Arguments
+/// * `archive_path` - Path to the tar archive (plain or compressed with any registered codec).
+/// * `options` - The decoder memory limit.
+/// * `visit` - Called with each entry and its contents.
+pub(crate) fn read_tar_entries(
+    archive_path: &Path,
+    options: &ExtractOptions,
+    visit: &mut dyn FnMut(&ArchiveEntry, &mut dyn Read) -> io::Result<()>,
+) -> io::Result<()> {
+    for_each_tar_entry(File::open(archive_path)?, options.memlimit, |_, entry, _| {
+        let header = entry.header();
+        let kind = match header.entry_type() {
+            EntryType::Regular | EntryType::Continuous => EntryKind::File,
+            EntryType::Directory => EntryKind::Directory,
+            EntryType::Symlink => EntryKind::Other("symlink"),
+            EntryType::Link => EntryKind::Other("hard link"),
+            _ => EntryKind::Other("special file"),
+        };
+        let info = ArchiveEntry {
+            name: entry.path()?.to_string_lossy().trim_end_matches('/').to_string(),
+            kind,
+            size: if kind == EntryKind::File { entry.size() } else { 0 },
+            mode: header.mode().ok().map(|mode| mode & 0o7777),
+            modified: header.mtime().ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs)),
+            owner: header.uid().ok().zip(header.gid().ok()),
+        };
+        visit(&info, entry)
+    })
+}
+
+/// Checks that a just-written tar archive holds exactly `entries`, byte for byte.
+fn verify_tar(archive_path: &str, entries: &[(PathBuf, String)]) -> io::Result<()> {
+    let mismatch = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+    let mut seen = 0usize;
+    for_each_tar_entry(File::open(archive_path)?, None, |_, entry, _| {
+        let name = entry.path()?.to_string_lossy().into_owned();
+        let Some((source, expected)) = entries.get(seen) else {
+            return Err(mismatch(format!("archive holds more than the expected {} files", entries.len())));
+        };
+        if name != *expected {
+            return Err(mismatch(format!("archive entry {} does not match {}", name, source.display())));
+        }
+        seen += 1;
+        let size = entry.size();
+        decompression::compare_with_source(entry, size, source)
+    })?;
+    if seen != entries.len() {
+        return Err(mismatch(format!("archive holds {} files, expected {}", seen, entries.len())));
+    }
+    Ok(())
+}
+
+/// Walks the entries of a tar archive, decompressing it first if it starts with the magic
+/// of a registered codec (whose decoder may use at most `memlimit` bytes). The visitor gets
+/// the entry's position, the entry itself and the number of archive bytes read so far.
+fn for_each_tar_entry<F>(file: File, memlimit: Option<u64>, mut visit: F) -> io::Result<()>
+where
+    F: FnMut(u64, &mut tar::Entry<'_, Box<dyn Read + '_>>, &Cell<u64>) -> io::Result<()>,
+{
+    let reader = CountingReader::new(file);
+    let archive_bytes = reader.counter();
+    let mut reader = BufReader::new(reader);
+    let head = reader.fill_buf()?;
+    let head = &head[..head.len().min(codec::MAGIC_LEN)];
+    let input: Box<dyn Read> = match codec::detect(head) {
+        Some(codec) => codec.decoder_with_memlimit(Box::new(reader), memlimit.unwrap_or(u64::MAX))?,
+        None => Box::new(reader),
+    };
+    let mut archive = Archive::new(input);
+    for (index, entry) in archive.entries()?.enumerate() {
+        visit(index as u64, &mut entry?, &archive_bytes)?;
+    }
+    Ok(())
+}
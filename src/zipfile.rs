@@ -0,0 +1,910 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use clap::ValueEnum;
+use flate2::CrcReader;
+use liblzma::stream::{LzmaOptions, Stream};
+use liblzma::write::XzEncoder;
+use zip::{CompressionMethod, ZipArchive};
+use zip::extra_fields::ExtraField;
+
+use crate::codec::{Encoder, PassThrough};
+use crate::compression::CompressOptions;
+use crate::decompression::{self, ArchiveListing, ExtractOptions};
+use crate::entry::{ArchiveEntry, EntryKind};
+use crate::file_io::{self, OutputFile};
+use crate::lzma::LimitedDecoder;
+use crate::progress::{self, CountingReader, CountingWriter, Tracker};
+use crate::rsz::{self, EntryInfo};
+use crate::store::{self, StoreRules};
+
+// ZIP layout (all integers little endian): for every entry a local header, its name and
+// extra fields, then the compressed data; then the central directory (one header per
+// entry) and the end-of-central-directory record, preceded by ZIP64 versions of that
+// record when counts or offsets overflow 16/32 bits.
+const LOCAL_HEADER_SIG: u32 = 0x0403_4b50;
+const CENTRAL_HEADER_SIG: u32 = 0x0201_4b50;
+const EOCD_SIG: u32 = 0x0605_4b50;
+const ZIP64_EOCD_SIG: u32 = 0x0606_4b50;
+const ZIP64_LOCATOR_SIG: u32 = 0x0706_4b50;
+const LOCAL_HEADER_LEN: u64 = 30;
+/// Offset of the CRC-32 field in a local header.
+const LOCAL_CRC_OFFSET: u64 = 14;
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+/// Info-ZIP extended timestamp: the modification time as unix seconds.
+const TIMESTAMP_EXTRA_ID: u16 = 0x5455;
+/// General-purpose flag: LZMA data ends with an end-of-stream marker.
+const FLAG_LZMA_EOS: u16 = 0x0002;
+/// General-purpose flag: names are UTF-8.
+const FLAG_UTF8: u16 = 0x0800;
+const ZIP64_VERSION: u16 = 45;
+/// MS-DOS attribute marking a directory, kept in the low byte of the external attributes.
+const DOS_DIRECTORY_ATTRIBUTE: u32 = 0x10;
+/// Unix file type bits, stored above the permissions in the external attributes.
+const S_IFREG: u32 = 0o100_000;
+const S_IFDIR: u32 = 0o040_000;
+/// Entries at least this large get ZIP64 sizes up front, leaving room for the small
+/// expansion incompressible data can see.
+const ZIP64_ENTRY_THRESHOLD: u64 = 0xF000_0000;
+/// Version of the LZMA SDK recorded in front of LZMA entry data.
+const LZMA_SDK_VERSION: [u8; 2] = [9, 20];
+const LZMA_PROPS_LEN: usize = 5;
+/// `.lzma` header produced by liblzma: properties then the (unknown) uncompressed size.
+const LZMA_ALONE_HEADER_LEN: usize = LZMA_PROPS_LEN + 8;
+
+/// Compression methods rs-zip can write to ZIP archives.
+#[derive(ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ZipMethod {
+    /// No compression
+    Stored,
+    /// DEFLATE (readable everywhere)
+    #[default]
+    Deflate,
+    /// LZMA (7-Zip, WinZip and most archivers; not Windows Explorer)
+    Lzma,
+    /// Zstandard (7-Zip with zstd support, WinZip, libarchive)
+    Zstd,
+}
+
+impl ZipMethod {
+    fn id(self) -> u16 {
+        match self {
+            ZipMethod::Stored => 0,
+            ZipMethod::Deflate => 8,
+            ZipMethod::Lzma => 14,
+            ZipMethod::Zstd => 93,
+        }
+    }
+
+    /// The "version needed to extract" the method requires.
+    fn version_needed(self) -> u16 {
+        match self {
+            ZipMethod::Stored | ZipMethod::Deflate => 20,
+            ZipMethod::Lzma | ZipMethod::Zstd => 63,
+        }
+    }
+
+    fn flags(self) -> u16 {
+        match self {
+            ZipMethod::Lzma => FLAG_UTF8 | FLAG_LZMA_EOS,
+            _ => FLAG_UTF8,
+        }
+    }
+
+    fn encoder<'a>(self, writer: Box<dyn Write + 'a>, level: u32) -> io::Result<Box<dyn Encoder + 'a>> {
+        if level > 9 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Compression level must be between 0 and 9",
+            ));
+        }
+        Ok(match self {
+            ZipMethod::Stored => Box::new(PassThrough(writer)),
+            ZipMethod::Deflate => Box::new(flate2::write::DeflateEncoder::new(writer, flate2::Compression::new(level))),
+            ZipMethod::Lzma => {
+                let options = LzmaOptions::new_preset(level).map_err(io::Error::other)?;
+                let stream = Stream::new_lzma_encoder(&options).map_err(io::Error::other)?;
+                Box::new(XzEncoder::new_stream(LzmaHeaderWriter::new(writer), stream))
+            }
+            ZipMethod::Zstd => Box::new(zstd::stream::write::Encoder::new(writer, level as i32)?),
+        })
+    }
+}
+
+/// Whether `path` names a ZIP archive (`.zip`).
+pub fn is_zip_path(path: &Path) -> bool {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("zip"))
+}
+
+impl<W: Write> Encoder for flate2::write::DeflateEncoder<W> {
+    fn finish(self: Box<Self>) -> io::Result<()> {
+        flate2::write::DeflateEncoder::finish(*self).map(drop)
+    }
+}
+
+/// Turns liblzma's `.lzma` output into ZIP's LZMA entry data: the 13-byte `.lzma` header
+/// (properties and uncompressed size) becomes the SDK version, the properties length and
+/// the properties. The LZMA stream that follows is the same in both.
+struct LzmaHeaderWriter<W> {
+    inner: W,
+    header: Vec<u8>,
+}
+
+impl<W: Write> LzmaHeaderWriter<W> {
+    fn new(inner: W) -> Self {
+        LzmaHeaderWriter { inner, header: Vec::with_capacity(LZMA_ALONE_HEADER_LEN) }
+    }
+}
+
+impl<W: Write> Write for LzmaHeaderWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.header.len() < LZMA_ALONE_HEADER_LEN {
+            let take = buf.len().min(LZMA_ALONE_HEADER_LEN - self.header.len());
+            self.header.extend_from_slice(&buf[..take]);
+            if self.header.len() == LZMA_ALONE_HEADER_LEN {
+                self.inner.write_all(&LZMA_SDK_VERSION)?;
+                self.inner.write_u16::<LittleEndian>(LZMA_PROPS_LEN as u16)?;
+                self.inner.write_all(&self.header[..LZMA_PROPS_LEN])?;
+            }
+            return Ok(take);
+        }
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// What the central directory records about an entry written earlier.
+struct CentralEntry {
+    name: String,
+    method: ZipMethod,
+    version_needed: u16,
+    dos_time: u16,
+    dos_date: u16,
+    crc: u32,
+    compressed_size: u64,
+    size: u64,
+    offset: u64,
+    /// Unix file type and permission bits.
+    mode: Option<u32>,
+    directory: bool,
+    mtime: Option<u32>,
+}
+
+/// Creates a ZIP archive from a list of files with the method in `options.zip_method`.
+/// Entries record each file's unix permissions and modification time (as DOS time and
+/// an extended timestamp); ZIP64 records are used wherever sizes, offsets or the entry
+/// count need them.
+///
+/// # Arguments
+/// * `files` - The files to archive, e.g. from `file_io::collect_files`. They are stored
+///   under their paths with any leading `/` removed.
+/// * `output_path` - Path to the output archive file.
+/// * `options` - Method, compression level, overwrite policy, progress and source removal.
+///   Encryption and signing need RSZ and are rejected.
+///
+/// # Returns
+/// * `Result<(), io::Error>` - Ok on success (including when the policy skips the output).
+pub fn create_zip_archive(
+    files: &[PathBuf],
+    output_path: &str,
+    options: &CompressOptions,
+) -> io::Result<()> {
+    ZipWriter::check_options(options)?;
+    let entries = files
+        .iter()
+        .map(|path| Ok((path.clone(), file_io::archive_entry_name(path)?)))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    // The newest input decides whether an existing archive counts as up to date.
+    let source_modified = files
+        .iter()
+        .filter_map(|p| p.metadata().and_then(|m| m.modified()).ok())
+        .max();
+    let Some(OutputFile { writer: output, created }) =
+        file_io::create_output_file_with_policy(Path::new(output_path), options.overwrite, source_modified)?
+    else {
+        return Ok(());
+    };
+
+    let total_in = files
+        .iter()
+        .filter_map(|p| p.metadata().ok())
+        .map(|m| m.len())
+        .sum();
+    let mut writer = ZipWriter::new(output, options, entries.len() as u64, Some(total_in));
+    let result = entries
+        .iter()
+        .try_for_each(|(path, name)| {
+            let mut file = File::open(path)?;
+            let entry = ArchiveEntry::from_metadata(name.clone(), &file.metadata()?);
+            writer.add_file(&entry, &mut file)
+        })
+        .and_then(|()| writer.finish());
+    if let Err(e) = result {
+        // Don't leave a truncated archive behind that looks like a finished one.
+        file_io::remove_partial_output(Path::new(output_path), created);
+        return Err(e);
+    }
+
+    if options.remove_sources {
+        File::open(output_path)?.sync_all()?;
+        verify_zip(output_path, &entries)?;
+        for file_path in files {
+            fs::remove_file(file_path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes a ZIP archive one entry at a time with the options' method and level, storing
+/// the entries its [`StoreRules`] pick.
+pub(crate) struct ZipWriter<'a> {
+    output: BufWriter<File>,
+    method: ZipMethod,
+    store: &'a StoreRules,
+    level: u32,
+    central: Vec<CentralEntry>,
+    tracker: Tracker<'a>,
+    bytes_in: u64,
+}
+
+impl<'a> ZipWriter<'a> {
+    /// Rejects the options ZIP cannot honour (encryption and signing).
+    pub(crate) fn check_options(options: &CompressOptions) -> io::Result<()> {
+        if options.password.is_some() || !options.recipients.is_empty() || options.signing_key.is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ZIP archives cannot be encrypted or signed; use RSZ",
+            ));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn new(
+        output: BufWriter<File>,
+        options: &'a CompressOptions,
+        entry_count: u64,
+        total_in: Option<u64>,
+    ) -> Self {
+        ZipWriter {
+            output,
+            method: options.zip_method,
+            store: &options.store,
+            level: options.level,
+            central: Vec::new(),
+            tracker: Tracker::new(options.progress.as_deref(), entry_count, total_in),
+            bytes_in: 0,
+        }
+    }
+
+    /// Appends a file, compressing exactly `entry.size` bytes from `data`.
+    pub(crate) fn add_file(&mut self, entry: &ArchiveEntry, data: &mut dyn Read) -> io::Result<()> {
+        let output = &mut self.output;
+        let offset = output.stream_position()?;
+        self.tracker.start_entry(self.central.len() as u64, &entry.name, self.bytes_in, offset);
+        let size = entry.size;
+        let mut head = vec![0u8; store::PROBE_LEN.min(usize::try_from(size).unwrap_or(usize::MAX))];
+        let head_len = rsz::read_up_to(data, &mut head)?;
+        head.truncate(head_len);
+        let method = if self.store.should_store(&entry.name, &head) { ZipMethod::Stored } else { self.method };
+        let mtime = zip_mtime(entry);
+        let (dos_time, dos_date) = dos_date_time(mtime.unwrap_or(0));
+        let large = size >= ZIP64_ENTRY_THRESHOLD;
+        let version_needed = if large {
+            method.version_needed().max(ZIP64_VERSION)
+        } else {
+            method.version_needed()
+        };
+
+        // Local header with placeholder CRC and sizes, patched once the data is written.
+        let mut zip64 = Vec::new();
+        if large {
+            zip64.write_u64::<LittleEndian>(0)?;
+            zip64.write_u64::<LittleEndian>(0)?;
+        }
+        write_local_header(output, &entry.name, method, version_needed, (dos_time, dos_date), &zip64, mtime)?;
+        let data_start = output.stream_position()?;
+
+        // Copy exactly the recorded size, even if the source changes meanwhile.
+        let mut source = CrcReader::new(Cursor::new(head).chain(data.take(size - head_len as u64)));
+        let mut counted = CountingWriter::new(&mut *output);
+        let written = counted.counter();
+        let mut encoder = method.encoder(Box::new(&mut counted), self.level)?;
+        let entry_start = self.bytes_in;
+        let tracker = &self.tracker;
+        let copied = progress::copy_with_progress(&mut source, &mut encoder, |_, _, copied| {
+            tracker.report(entry_start + copied, data_start + written.get())
+        })?;
+        encoder.finish()?;
+        let compressed_size = written.get();
+        if copied != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("{} shrank while it was being archived", entry.name),
+            ));
+        }
+        if !large && compressed_size >= u32::MAX as u64 {
+            return Err(io::Error::other(format!(
+                "{} grew past 4 GiB when compressed; use another method",
+                entry.name
+            )));
+        }
+        let crc = source.crc().sum();
+
+        let end = output.stream_position()?;
+        output.seek(SeekFrom::Start(offset + LOCAL_CRC_OFFSET))?;
+        output.write_u32::<LittleEndian>(crc)?;
+        if large {
+            output.write_u32::<LittleEndian>(u32::MAX)?;
+            output.write_u32::<LittleEndian>(u32::MAX)?;
+            output.seek(SeekFrom::Start(offset + LOCAL_HEADER_LEN + entry.name.len() as u64 + 4))?;
+            output.write_u64::<LittleEndian>(size)?;
+            output.write_u64::<LittleEndian>(compressed_size)?;
+        } else {
+            output.write_u32::<LittleEndian>(compressed_size as u32)?;
+            output.write_u32::<LittleEndian>(size as u32)?;
+        }
+        output.seek(SeekFrom::Start(end))?;
+
+        self.central.push(CentralEntry {
+            name: entry.name.clone(),
+            method,
+            version_needed,
+            dos_time,
+            dos_date,
+            crc,
+            compressed_size,
+            size,
+            offset,
+            mode: entry.mode.map(|mode| S_IFREG | mode),
+            directory: false,
+            mtime,
+        });
+        self.bytes_in += size;
+        Ok(())
+    }
+
+    /// Appends a directory entry (a stored, empty entry whose name ends in `/`).
+    pub(crate) fn add_directory(&mut self, entry: &ArchiveEntry) -> io::Result<()> {
+        let offset = self.output.stream_position()?;
+        self.tracker.start_entry(self.central.len() as u64, &entry.name, self.bytes_in, offset);
+        let name = format!("{}/", entry.name.trim_end_matches('/'));
+        let mtime = zip_mtime(entry);
+        let dos = dos_date_time(mtime.unwrap_or(0));
+        let method = ZipMethod::Stored;
+        write_local_header(&mut self.output, &name, method, method.version_needed(), dos, &[], mtime)?;
+        self.central.push(CentralEntry {
+            name,
+            method,
+            version_needed: method.version_needed(),
+            dos_time: dos.0,
+            dos_date: dos.1,
+            crc: 0,
+            compressed_size: 0,
+            size: 0,
+            offset,
+            mode: entry.mode.map(|mode| S_IFDIR | mode),
+            directory: true,
+            mtime,
+        });
+        Ok(())
+    }
+
+    /// Writes the central directory and end records.
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        write_central_directory(&mut self.output, &self.central)?;
+        self.output.flush()?;
+        self.tracker.report(self.bytes_in, self.output.stream_position()?);
+        Ok(())
+    }
+}
+
+/// Writes a local file header. `zip64` is the body of a ZIP64 extra field, if any.
+fn write_local_header<W: Write>(
+    output: &mut W,
+    name: &str,
+    method: ZipMethod,
+    version_needed: u16,
+    (dos_time, dos_date): (u16, u16),
+    zip64: &[u8],
+    mtime: Option<u32>,
+) -> io::Result<()> {
+    let mut extra = Vec::new();
+    if !zip64.is_empty() {
+        extra.write_u16::<LittleEndian>(ZIP64_EXTRA_ID)?;
+        extra.write_u16::<LittleEndian>(field_len(zip64.len(), "ZIP64 extra field", name)?)?;
+        extra.extend_from_slice(zip64);
+    }
+    if let Some(mtime) = mtime {
+        write_timestamp_extra(&mut extra, mtime)?;
+    }
+    output.write_u32::<LittleEndian>(LOCAL_HEADER_SIG)?;
+    output.write_u16::<LittleEndian>(version_needed)?;
+    output.write_u16::<LittleEndian>(method.flags())?;
+    output.write_u16::<LittleEndian>(method.id())?;
+    output.write_u16::<LittleEndian>(dos_time)?;
+    output.write_u16::<LittleEndian>(dos_date)?;
+    output.write_all(&[0u8; 12])?; // CRC and sizes
+    output.write_u16::<LittleEndian>(field_len(name.len(), "name", name)?)?;
+    output.write_u16::<LittleEndian>(field_len(extra.len(), "extra field", name)?)?;
+    output.write_all(name.as_bytes())?;
+    output.write_all(&extra)
+}
+
+/// A name's or extra field's length as the 16 bits ZIP stores it in.
+///
+/// # Returns
+/// * `io::Result<u16>` - The length; `InvalidInput` if it is over 65535 bytes, which would
+///   otherwise be cut short and corrupt the archive.
+fn field_len(len: usize, what: &str, name: &str) -> io::Result<u16> {
+    u16::try_from(len).map_err(|_| {
+        // Such a name is too long to repeat in full.
+        let start: String = name.chars().take(64).collect();
+        let ellipsis = if start.len() < name.len() { "..." } else { "" };
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{}{}: the {} is {} bytes, more than ZIP's limit of 65535", start, ellipsis, what, len),
+        )
+    })
+}
+
+/// An entry's modification time as the unix seconds the extended timestamp holds.
+fn zip_mtime(entry: &ArchiveEntry) -> Option<u32> {
+    entry
+        .modified
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_secs().min(u32::MAX as u64) as u32)
+}
+
+/// Writes the central directory and the (ZIP64 and classic) end records.
+fn write_central_directory<W: Write + Seek>(output: &mut W, entries: &[CentralEntry]) -> io::Result<()> {
+    let cd_offset = output.stream_position()?;
+    let mut needs_zip64 = entries.len() >= u16::MAX as usize;
+    for entry in entries {
+        let mut extra = Vec::new();
+        let mut zip64 = Vec::new();
+        if entry.size >= u32::MAX as u64 {
+            zip64.write_u64::<LittleEndian>(entry.size)?;
+        }
+        if entry.compressed_size >= u32::MAX as u64 {
+            zip64.write_u64::<LittleEndian>(entry.compressed_size)?;
+        }
+        if entry.offset >= u32::MAX as u64 {
+            zip64.write_u64::<LittleEndian>(entry.offset)?;
+        }
+        let version_needed = if zip64.is_empty() {
+            entry.version_needed
+        } else {
+            extra.write_u16::<LittleEndian>(ZIP64_EXTRA_ID)?;
+            extra.write_u16::<LittleEndian>(field_len(zip64.len(), "ZIP64 extra field", &entry.name)?)?;
+            extra.extend_from_slice(&zip64);
+            entry.version_needed.max(ZIP64_VERSION)
+        };
+        if let Some(mtime) = entry.mtime {
+            write_timestamp_extra(&mut extra, mtime)?;
+        }
+        let dos_attributes = if entry.directory { DOS_DIRECTORY_ATTRIBUTE } else { 0 };
+        let (made_by, external_attributes) = match entry.mode {
+            Some(mode) => ((3 << 8) | 63, (mode << 16) | dos_attributes),
+            None => (63, dos_attributes),
+        };
+
+        output.write_u32::<LittleEndian>(CENTRAL_HEADER_SIG)?;
+        output.write_u16::<LittleEndian>(made_by)?;
+        output.write_u16::<LittleEndian>(version_needed)?;
+        output.write_u16::<LittleEndian>(entry.method.flags())?;
+        output.write_u16::<LittleEndian>(entry.method.id())?;
+        output.write_u16::<LittleEndian>(entry.dos_time)?;
+        output.write_u16::<LittleEndian>(entry.dos_date)?;
+        output.write_u32::<LittleEndian>(entry.crc)?;
+        output.write_u32::<LittleEndian>(entry.compressed_size.min(u32::MAX as u64) as u32)?;
+        output.write_u32::<LittleEndian>(entry.size.min(u32::MAX as u64) as u32)?;
+        output.write_u16::<LittleEndian>(field_len(entry.name.len(), "name", &entry.name)?)?;
+        output.write_u16::<LittleEndian>(field_len(extra.len(), "extra field", &entry.name)?)?;
+        output.write_u16::<LittleEndian>(0)?; // comment length
+        output.write_u16::<LittleEndian>(0)?; // disk number
+        output.write_u16::<LittleEndian>(0)?; // internal attributes
+        output.write_u32::<LittleEndian>(external_attributes)?;
+        output.write_u32::<LittleEndian>(entry.offset.min(u32::MAX as u64) as u32)?;
+        output.write_all(entry.name.as_bytes())?;
+        output.write_all(&extra)?;
+    }
+    let cd_end = output.stream_position()?;
+    let cd_size = cd_end - cd_offset;
+    needs_zip64 |= cd_offset >= u32::MAX as u64 || cd_size >= u32::MAX as u64;
+
+    if needs_zip64 {
+        output.write_u32::<LittleEndian>(ZIP64_EOCD_SIG)?;
+        output.write_u64::<LittleEndian>(44)?; // size of the rest of the record
+        output.write_u16::<LittleEndian>((3 << 8) | ZIP64_VERSION)?;
+        output.write_u16::<LittleEndian>(ZIP64_VERSION)?;
+        output.write_u32::<LittleEndian>(0)?; // this disk
+        output.write_u32::<LittleEndian>(0)?; // disk with the central directory
+        output.write_u64::<LittleEndian>(entries.len() as u64)?;
+        output.write_u64::<LittleEndian>(entries.len() as u64)?;
+        output.write_u64::<LittleEndian>(cd_size)?;
+        output.write_u64::<LittleEndian>(cd_offset)?;
+        output.write_u32::<LittleEndian>(ZIP64_LOCATOR_SIG)?;
+        output.write_u32::<LittleEndian>(0)?;
+        output.write_u64::<LittleEndian>(cd_end)?;
+        output.write_u32::<LittleEndian>(1)?; // total disks
+    }
+    let count = entries.len().min(u16::MAX as usize) as u16;
+    output.write_u32::<LittleEndian>(EOCD_SIG)?;
+    output.write_u16::<LittleEndian>(0)?;
+    output.write_u16::<LittleEndian>(0)?;
+    output.write_u16::<LittleEndian>(count)?;
+    output.write_u16::<LittleEndian>(count)?;
+    output.write_u32::<LittleEndian>(cd_size.min(u32::MAX as u64) as u32)?;
+    output.write_u32::<LittleEndian>(cd_offset.min(u32::MAX as u64) as u32)?;
+    output.write_u16::<LittleEndian>(0)?; // comment length
+    Ok(())
+}
+
+fn write_timestamp_extra(extra: &mut Vec<u8>, mtime: u32) -> io::Result<()> {
+    extra.write_u16::<LittleEndian>(TIMESTAMP_EXTRA_ID)?;
+    extra.write_u16::<LittleEndian>(5)?;
+    extra.write_u8(1)?; // only the modification time follows
+    extra.write_u32::<LittleEndian>(mtime)
+}
+
+/// Converts unix seconds to MS-DOS (time, date), in UTC and clamped to 1980-2107.
+fn dos_date_time(secs: u32) -> (u16, u16) {
+    let days = (secs / 86_400) as i64;
+    let rem = secs % 86_400;
+    let (year, month, day) = civil_from_days(days);
+    if year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    if year > 2107 {
+        return ((23 << 11) | (59 << 5) | 29, (127 << 9) | (12 << 5) | 31);
+    }
+    let time = ((rem / 3600) << 11) | (((rem / 60) % 60) << 5) | ((rem % 60) / 2);
+    let date = (((year - 1980) as u32) << 9) | (month << 5) | day;
+    (time as u16, date as u16)
+}
+
+/// Converts days since 1970-01-01 to a (year, month, day) date.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// Converts a (year, month, day) date to days since 1970-01-01.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// What rs-zip needs to know about an entry before reading it.
+struct EntryMeta {
+    name: String,
+    size: u64,
+    crc: u32,
+    lzma: bool,
+    dir: bool,
+    symlink: bool,
+    mode: Option<u32>,
+    modified: Option<SystemTime>,
+}
+
+impl EntryMeta {
+    fn read<R: Read + Seek>(archive: &mut ZipArchive<R>, index: usize) -> io::Result<Self> {
+        let entry = archive.by_index_raw(index)?;
+        Ok(EntryMeta {
+            name: entry.name().to_string(),
+            size: entry.size(),
+            crc: entry.crc32(),
+            lzma: entry.compression() == CompressionMethod::Lzma,
+            dir: entry.is_dir(),
+            symlink: entry.is_symlink(),
+            mode: entry.unix_mode(),
+            modified: entry_modified(&entry),
+        })
+    }
+
+    fn info(&self) -> EntryInfo {
+        EntryInfo {
+            name: self.name.clone(),
+            size: self.size,
+            block: 0,
+            offset: 0,
+            sha256: None,
+            extents: Vec::new(),
+        }
+    }
+}
+
+/// Opens an entry's decompressed data, which fails with `InvalidData` at the end if the
+/// CRC-32 does not match.
+///
+/// LZMA entries are decoded by liblzma, within `memlimit` bytes of memory: their data is
+/// the LZMA stream of a `.lzma` file with a shorter header, so the `.lzma` header is rebuilt
+/// in front of it. Every other method is left to the `zip` crate.
+fn open_entry<'a, R: Read + Seek>(
+    archive: &'a mut ZipArchive<R>,
+    index: usize,
+    meta: &EntryMeta,
+    memlimit: Option<u64>,
+) -> io::Result<Box<dyn Read + 'a>> {
+    if !meta.lzma {
+        return Ok(Box::new(archive.by_index(index)?));
+    }
+    let mut raw = archive.by_index_raw(index)?;
+    if raw.encrypted() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("{} is encrypted, which ZIP extraction does not support", meta.name),
+        ));
+    }
+    let mut head = [0u8; 4];
+    raw.read_exact(&mut head)?;
+    if u16::from_le_bytes([head[2], head[3]]) as usize != LZMA_PROPS_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{} has an invalid LZMA header", meta.name),
+        ));
+    }
+    let mut header = vec![0u8; LZMA_PROPS_LEN];
+    raw.read_exact(&mut header)?;
+    // With the size known the decoder stops there, whether or not an end marker follows.
+    header.extend_from_slice(&meta.size.to_le_bytes());
+    let decoder = LimitedDecoder::lzma_alone(
+        BufReader::new(io::Cursor::new(header).chain(raw)),
+        memlimit.unwrap_or(u64::MAX),
+    )?;
+    Ok(Box::new(CrcCheckedReader {
+        inner: CrcReader::new(decoder.take(meta.size)),
+        size: meta.size,
+        crc: meta.crc,
+        name: meta.name.clone(),
+    }))
+}
+
+/// Checks the size and CRC-32 of an entry once it has been read to the end.
+struct CrcCheckedReader<R> {
+    inner: CrcReader<R>,
+    size: u64,
+    crc: u32,
+    name: String,
+}
+
+impl<R: Read> Read for CrcCheckedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            if self.inner.crc().amount() as u64 != self.size {
+                return Err(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("archive is truncated inside {}", self.name),
+                ));
+            }
+            if self.inner.crc().sum() != self.crc {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{} is corrupt: its CRC-32 does not match", self.name),
+                ));
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Extracts a ZIP archive into the current directory. Stored, deflate, LZMA and zstd
+/// entries are supported; files get their unix permissions (without set-id bits) and
+/// modification time back, directories are created and symlinks are skipped with a warning.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.zip` archive.
+/// * `options` - Overwrite policy and progress reporting. `KeepNewer` compares existing
+///   files against each entry's stored modification time.
+///
+/// # Returns
+/// * `Result<(), std::io::Error>` - Ok if all files are extracted (or skipped by the policy);
+///   `InvalidData` for entries whose names would leave the current directory or whose
+///   CRC does not match.
+pub fn extract_zip_archive<P: AsRef<Path>>(archive_path: P, options: &ExtractOptions) -> io::Result<()> {
+    let file = File::open(archive_path)?;
+    let archive_len = file.metadata()?.len();
+    let reader = CountingReader::new(BufReader::new(file));
+    let archive_bytes = reader.counter();
+    let mut archive = ZipArchive::new(reader)?;
+    let mut tracker = Tracker::new(options.progress.as_deref(), archive.len() as u64, Some(archive_len));
+    let mut bytes_out = 0u64;
+    // The file being written; removed if it fails to extract or its CRC is wrong.
+    let mut pending: Option<PathBuf> = None;
+
+    let mut extract = || -> io::Result<()> {
+        for index in 0..archive.len() {
+            pending = None;
+            let meta = EntryMeta::read(&mut archive, index)?;
+            tracker.start_entry(index as u64, &meta.name, archive_bytes.get(), bytes_out);
+            if meta.dir {
+                if let Some(path) = file_io::safe_extract_dir(&meta.name)? {
+                    file_io::create_extract_dir(&path)?;
+                }
+                continue;
+            }
+            if meta.symlink {
+                tracker.warn("symlinks are not extracted; skipped");
+                continue;
+            }
+
+            let path = file_io::safe_extract_path(&meta.name)?;
+            if let Some(parent) = path.parent() {
+                file_io::create_extract_dir(parent)?;
+            }
+            if let Some(OutputFile { writer: mut output_file, created }) =
+                file_io::create_extracted_file(&path, options.overwrite, meta.modified)?
+            {
+                pending = created.then_some(path);
+                let mut entry = open_entry(&mut archive, index, &meta, options.memlimit)?;
+                let entry_start = bytes_out;
+                progress::copy_with_progress(&mut entry, &mut output_file, |_, _, copied| {
+                    tracker.report(archive_bytes.get(), entry_start + copied)
+                })?;
+                output_file.flush()?;
+                file_io::restore_metadata(output_file.get_ref(), meta.mode, meta.modified)?;
+            }
+            bytes_out += meta.size;
+        }
+        Ok(())
+    };
+    let result = extract();
+    if result.is_err()
+        && let Some(path) = pending
+    {
+        file_io::remove_partial_output(&path, true);
+    }
+    result?;
+
+    tracker.report(archive_len, bytes_out);
+    Ok(())
+}
+
+/// Reads every entry of a ZIP archive in order, for conversion to another format. Symlinks
+/// are passed on as `EntryKind::Other` with no data.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.zip` archive.
+/// * `options` - The decoder memory limit.
+/// * `visit` - Called with each entry and its contents, whose CRC-32 is checked.
+pub(crate) fn read_zip_entries(
+    archive_path: &Path,
+    options: &ExtractOptions,
+    visit: &mut dyn FnMut(&ArchiveEntry, &mut dyn Read) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(archive_path)?))?;
+    for index in 0..archive.len() {
+        let meta = EntryMeta::read(&mut archive, index)?;
+        let kind = if meta.dir {
+            EntryKind::Directory
+        } else if meta.symlink {
+            EntryKind::Other("symlink")
+        } else {
+            EntryKind::File
+        };
+        let entry = ArchiveEntry {
+            name: meta.name.trim_end_matches('/').to_string(),
+            kind,
+            size: if kind == EntryKind::File { meta.size } else { 0 },
+            mode: meta.mode.map(|mode| mode & 0o7777),
+            modified: meta.modified,
+            owner: None,
+        };
+        if kind == EntryKind::File {
+            let mut data = open_entry(&mut archive, index, &meta, options.memlimit)?;
+            visit(&entry, &mut data)?;
+            // Reading to the end is what checks the CRC-32.
+            io::copy(&mut data, &mut io::sink())?;
+        } else {
+            visit(&entry, &mut io::empty())?;
+        }
+    }
+    Ok(())
+}
+
+/// An entry's modification time: the extended timestamp if present, otherwise its DOS
+/// date and time (taken as UTC).
+fn entry_modified(entry: &zip::read::ZipFile<'_>) -> Option<SystemTime> {
+    let timestamp = entry.extra_data_fields().find_map(|field| match field {
+        ExtraField::ExtendedTimestamp(ts) => ts.mod_time(),
+        _ => None,
+    });
+    if let Some(secs) = timestamp {
+        return Some(UNIX_EPOCH + Duration::from_secs(secs.into()));
+    }
+    let dt = entry.last_modified()?;
+    let days = days_from_civil(dt.year().into(), dt.month().into(), dt.day().into());
+    let secs = days * 86_400 + i64::from(dt.hour()) * 3600 + i64::from(dt.minute()) * 60 + i64::from(dt.second());
+    u64::try_from(secs).ok().map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Lists the files in a ZIP archive from its central directory, without decompressing.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.zip` archive.
+///
+/// # Returns
+/// * `Result<ArchiveListing, std::io::Error>` - The entries (ZIP archives are never signed).
+pub fn list_zip_archive<P: AsRef<Path>>(archive_path: P) -> io::Result<ArchiveListing> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(archive_path)?))?;
+    let mut entries = Vec::new();
+    for index in 0..archive.len() {
+        let meta = EntryMeta::read(&mut archive, index)?;
+        if !meta.dir && !meta.symlink {
+            entries.push(meta.info());
+        }
+    }
+    Ok(ArchiveListing { entries, signer: None })
+}
+
+/// Decompresses every file in a ZIP archive without writing anything, checking each
+/// entry's CRC-32.
+///
+/// # Arguments
+/// * `archive_path` - Path to the `.zip` archive.
+/// * `options` - The decoder memory limit.
+///
+/// # Returns
+/// * `Result<ArchiveListing, std::io::Error>` - The entries if every one reads back intact.
+pub fn test_zip_archive<P: AsRef<Path>>(archive_path: P, options: &ExtractOptions) -> io::Result<ArchiveListing> {
+    let mut archive = ZipArchive::new(BufReader::new(File::open(archive_path)?))?;
+    let mut entries = Vec::new();
+    for index in 0..archive.len() {
+        let meta = EntryMeta::read(&mut archive, index)?;
+        if meta.dir || meta.symlink {
+            continue;
+        }
+        let read = io::copy(&mut open_entry(&mut archive, index, &meta, options.memlimit)?, &mut io::sink())?;
+        if read != meta.size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("archive is truncated inside {}", meta.name),
+            ));
+        }
+        entries.push(meta.info());
+    }
+    Ok(ArchiveListing { entries, signer: None })
+}
+
+/// Checks that a just-written ZIP archive holds exactly `entries`, byte for byte.
+fn verify_zip(archive_path: &str, entries: &[(PathBuf, String)]) -> io::Result<()> {
+    let mismatch = |what: String| io::Error::new(io::ErrorKind::InvalidData, what);
+    let mut archive = ZipArchive::new(BufReader::new(File::open(archive_path)?))?;
+    if archive.len() != entries.len() {
+        return Err(mismatch(format!("archive holds {} files, expected {}", archive.len(), entries.len())));
+    }
+    for (index, (source, expected)) in entries.iter().enumerate() {
+        let meta = EntryMeta::read(&mut archive, index)?;
+        if meta.name != *expected {
+            return Err(mismatch(format!("archive entry {} does not match {}", meta.name, source.display())));
+        }
+        let mut entry = open_entry(&mut archive, index, &meta, None)?;
+        decompression::compare_with_source(&mut entry, meta.size, source)?;
+        // Reading to the end makes the reader check the entry's CRC.
+        io::copy(&mut entry, &mut io::sink())?;
+    }
+    Ok(())
+}